@@ -9,7 +9,7 @@ use idgener::embedded;
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt = Options::parse().expect("parse config");
-    logger::init(opt.debug);
+    logger::setup(&opt.logging, opt.debug)?;
     log::info!("use config: {:#?}", opt);
 
     let (tx, _) = tokio::sync::broadcast::channel::<u64>(1);
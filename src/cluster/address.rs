@@ -0,0 +1,192 @@
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+/// 选择对外公布地址的网卡，可以是网卡名称（如 `eth0`）或者网段（如 `10.0.0.0/8`、`fd00::/8`）
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Interface {
+    Name(String),
+    Network(IpNet),
+}
+
+impl FromStr for Interface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(String::from("empty interface"));
+        }
+        if s.contains('/') {
+            return s
+                .parse::<IpNet>()
+                .map(Interface::Network)
+                .map_err(|err| format!("invalid network {}: {}", s, err));
+        }
+        Ok(Interface::Name(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for Interface {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Interface> for String {
+    fn from(val: Interface) -> Self {
+        val.to_string()
+    }
+}
+
+impl Display for Interface {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interface::Name(name) => f.write_str(name),
+            Interface::Network(net) => write!(f, "{}", net),
+        }
+    }
+}
+
+impl Interface {
+    fn matches(&self, name: &str, ip: &IpAddr) -> bool {
+        match self {
+            Interface::Name(expect) => expect == name,
+            Interface::Network(net) => net.contains(ip),
+        }
+    }
+}
+
+/// 本机对外公布的IP。
+///
+/// 指定了[interface]时只从匹配的网卡中选择，否则先通过路由表查找默认出口的IP（不会真正发送数据），
+/// 找不到时（如内网、只有IPv6的主机）选择第一个非回环地址。IPv4优先，链路本地地址最后考虑。
+pub fn local_ipaddress(interface: Option<&Interface>) -> Option<IpAddr> {
+    let addrs = if_addrs::get_if_addrs()
+        .map(|interfaces| {
+            interfaces
+                .into_iter()
+                .map(|i| (i.name.clone(), i.ip()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    match interface {
+        Some(interface) => select(&addrs, Some(interface)),
+        None => route_ipaddress().or_else(|| select(&addrs, None)),
+    }
+}
+
+/// 从网卡地址中选择，[interface]为[None]时排除回环地址
+fn select(addrs: &[(String, IpAddr)], interface: Option<&Interface>) -> Option<IpAddr> {
+    addrs
+        .iter()
+        .filter(|(name, ip)| match interface {
+            Some(interface) => interface.matches(name, ip),
+            None => !ip.is_loopback() && !ip.is_unspecified(),
+        })
+        .map(|(_, ip)| *ip)
+        .min_by_key(rank)
+}
+
+fn rank(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(ip) if ip.is_link_local() => 2,
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(ip) if (ip.segments()[0] & 0xffc0) == 0xfe80 => 3,
+        IpAddr::V6(_) => 1,
+    }
+}
+
+/// 通过连接公共地址的UDP套接字查询默认出口IP
+fn route_ipaddress() -> Option<IpAddr> {
+    let targets = [
+        (
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 80),
+        ),
+        (
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+            SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)),
+                80,
+            ),
+        ),
+    ];
+    targets.iter().find_map(|(bind, target)| {
+        let socket = UdpSocket::bind(bind).ok()?;
+        socket.connect(target).ok()?;
+        let ip = socket.local_addr().ok()?.ip();
+        match ip.is_unspecified() || ip.is_loopback() {
+            true => None,
+            false => Some(ip),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::cluster::address::{select, Interface};
+
+    fn addrs() -> Vec<(String, IpAddr)> {
+        vec![
+            ("lo", "127.0.0.1"),
+            ("lo", "::1"),
+            ("eth0", "fe80::1"),
+            ("eth0", "2001:db8::10"),
+            ("eth1", "10.1.2.3"),
+            ("eth1", "fe80::2"),
+        ]
+        .into_iter()
+        .map(|(name, ip)| (name.to_string(), ip.parse().unwrap()))
+        .collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Ok(Interface::Name(String::from("eth0"))),
+            "eth0".parse::<Interface>()
+        );
+        assert!(matches!(
+            "fd00::/8".parse::<Interface>(),
+            Ok(Interface::Network(_))
+        ));
+        assert!("10.0.0.0/33".parse::<Interface>().is_err());
+    }
+
+    #[test]
+    fn select_address() {
+        let addrs = addrs();
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        assert_eq!(ip("10.1.2.3"), select(&addrs, None));
+        assert_eq!(
+            ip("2001:db8::10"),
+            select(&addrs, Some(&"eth0".parse().unwrap()))
+        );
+        assert_eq!(
+            ip("2001:db8::10"),
+            select(&addrs, Some(&"2001:db8::/32".parse().unwrap()))
+        );
+        assert_eq!(
+            ip("10.1.2.3"),
+            select(&addrs, Some(&"10.0.0.0/8".parse().unwrap()))
+        );
+        assert_eq!(None, select(&addrs, Some(&"eth9".parse().unwrap())));
+
+        // 只有IPv6的主机
+        let v6 = addrs
+            .into_iter()
+            .filter(|(_, ip)| ip.is_ipv6())
+            .collect::<Vec<_>>();
+        assert_eq!(ip("2001:db8::10"), select(&v6, None));
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+/// 和leader之间的时钟偏差。
+///
+/// follower 在 keep-alive 时记录发送时间 t0、leader 收到时间 t1、leader 响应时间 t2、
+/// 收到响应时间 t3，按照 NTP 的方式估算偏差 `((t1 - t0) + (t2 - t3)) / 2`。
+/// 偏差超过[max_skew]时节点停止发放ID，[max_skew]为0时不检查。
+#[derive(Debug)]
+pub struct Clock {
+    max_skew: i64,
+    offset: AtomicI64,
+    round_trip: AtomicI64,
+    measured: AtomicBool,
+    skewed: AtomicBool,
+}
+
+impl Clock {
+    pub fn new(max_skew: Duration) -> Self {
+        Clock {
+            max_skew: max_skew.as_millis() as i64,
+            offset: AtomicI64::new(0),
+            round_trip: AtomicI64::new(0),
+            measured: AtomicBool::new(false),
+            skewed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Clock::new(Duration::from_millis(0))
+    }
+
+    /// 记录一次 keep-alive 的时间戳，返回估算的偏差
+    pub fn sample(&self, t0: i64, t1: i64, t2: i64, t3: i64) -> i64 {
+        let offset = ((t1 - t0) + (t2 - t3)) / 2;
+        self.offset.store(offset, Ordering::SeqCst);
+        self.round_trip
+            .store((t3 - t0) - (t2 - t1), Ordering::SeqCst);
+        self.measured.store(true, Ordering::SeqCst);
+        offset
+    }
+
+    /// leader 时钟减去本机时钟的毫秒数，还没有测量时返回[None]
+    pub fn offset(&self) -> Option<i64> {
+        match self.measured.load(Ordering::SeqCst) {
+            true => Some(self.offset.load(Ordering::SeqCst)),
+            false => None,
+        }
+    }
+
+    pub fn round_trip(&self) -> Option<i64> {
+        self.offset()
+            .map(|_| self.round_trip.load(Ordering::SeqCst))
+    }
+
+    /// 清除测量结果，当前节点成为leader时使用
+    pub fn reset(&self) {
+        self.measured.store(false, Ordering::SeqCst);
+        self.skewed.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_skewed(&self) -> bool {
+        if self.max_skew == 0 {
+            return false;
+        }
+        let skewed = self.offset().map(i64::abs).unwrap_or(0) > self.max_skew;
+        if skewed != self.skewed.swap(skewed, Ordering::SeqCst) {
+            match skewed {
+                true => log::warn!(
+                    "clock offset {:?} ms exceed {} ms, stop issuing ids",
+                    self.offset(),
+                    self.max_skew
+                ),
+                false => log::info!("clock offset {:?} ms, resume issuing ids", self.offset()),
+            }
+        }
+        skewed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::cluster::Clock;
+
+    #[test]
+    fn sample() {
+        let clock = Clock::new(Duration::from_millis(100));
+        assert_eq!(None, clock.offset());
+        assert!(!clock.is_skewed());
+
+        // leader 快了 50ms，单程 10ms，leader 处理 2ms
+        assert_eq!(50, clock.sample(1000, 1060, 1062, 1022));
+        assert_eq!(Some(20), clock.round_trip());
+        assert!(!clock.is_skewed());
+
+        // leader 慢了 300ms
+        assert_eq!(-300, clock.sample(1000, 710, 712, 1022));
+        assert!(clock.is_skewed());
+
+        clock.reset();
+        assert!(!clock.is_skewed());
+
+        let disabled = Clock::disabled();
+        disabled.sample(0, 100_000, 100_000, 0);
+        assert!(!disabled.is_skewed());
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// 数据中心ID占用的位数，开启数据中心后 Snowflake 的 worker id 由数据中心ID和节点ID组成
+pub const DATACENTER_BITS: u8 = 5;
+pub const MAX_DATACENTER_ID: u8 = (1 << DATACENTER_BITS) - 1;
+/// 开启数据中心后节点ID的最大值，worker id 剩余的位数
+pub const MAX_DATACENTER_NODE_ID: u16 = (1 << (10 - DATACENTER_BITS)) - 1;
+
+/// 联邦中的一个数据中心集群
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Datacenter {
+    pub id: u8,
+    /// 数据中心名称，不同数据中心的名称必须不同
+    pub name: String,
+    /// 发送注册的节点地址
+    #[schema(value_type = String)]
+    pub address: SocketAddr,
+    /// 本机最后一次收到该数据中心注册的时间
+    #[serde(default)]
+    pub last_seen: i64,
+}
+
+/// 多数据中心联邦。
+///
+/// 每个数据中心集群使用唯一的数据中心ID，定期向配置的其他数据中心注册自己。
+/// 不同名称的数据中心使用了相同的ID时记录为冲突，和本数据中心冲突时停止发放ID。
+#[derive(Debug, Clone)]
+pub struct Federation {
+    local: Datacenter,
+    peers: Vec<SocketAddr>,
+    /// [expire]毫秒内没有注册的数据中心和冲突会被忽略
+    expire: i64,
+    datacenters: BTreeMap<u8, Datacenter>,
+    conflicts: Vec<Datacenter>,
+}
+
+/// `/api/datacenters` 返回的联邦视图
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct View {
+    pub local: Datacenter,
+    pub datacenters: Vec<Datacenter>,
+    pub conflicts: Vec<Datacenter>,
+}
+
+impl Federation {
+    pub fn new(local: Datacenter, peers: Vec<SocketAddr>, expire: i64) -> Self {
+        Federation {
+            local,
+            peers,
+            expire,
+            datacenters: BTreeMap::new(),
+            conflicts: vec![],
+        }
+    }
+
+    pub fn local(&self) -> &Datacenter {
+        &self.local
+    }
+
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    /// 记录其他数据中心的注册，ID冲突时返回错误
+    pub fn register(&mut self, datacenter: Datacenter, now: i64) -> Result<(), String> {
+        let datacenter = Datacenter {
+            last_seen: now,
+            ..datacenter
+        };
+        if datacenter.name == self.local.name {
+            // 本数据中心其他节点的注册
+            return Ok(());
+        }
+        let conflict = match self.datacenters.get(&datacenter.id) {
+            _ if datacenter.id == self.local.id => Some(self.local.name.clone()),
+            Some(exists)
+                if exists.name != datacenter.name && now - exists.last_seen <= self.expire =>
+            {
+                Some(exists.name.clone())
+            }
+            _ => None,
+        };
+        if let Some(name) = conflict {
+            let message = format!(
+                "datacenter id {} of {} conflict with {}",
+                datacenter.id, datacenter.name, name
+            );
+            self.conflicts
+                .retain(|c| !(c.id == datacenter.id && c.name == datacenter.name));
+            self.conflicts.push(datacenter);
+            return Err(message);
+        }
+        self.datacenters.insert(datacenter.id, datacenter);
+        Ok(())
+    }
+
+    /// 本数据中心的ID是否和其他数据中心冲突
+    pub fn is_conflicted(&self, now: i64) -> bool {
+        self.conflicts
+            .iter()
+            .any(|c| c.id == self.local.id && now - c.last_seen <= self.expire)
+    }
+
+    pub fn view(&self, now: i64) -> View {
+        let alive = |dc: &&Datacenter| now - dc.last_seen <= self.expire;
+        View {
+            local: self.local.clone(),
+            datacenters: self.datacenters.values().filter(alive).cloned().collect(),
+            conflicts: self.conflicts.iter().filter(alive).cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cluster::federation::{Datacenter, Federation};
+
+    fn datacenter(id: u8, name: &str) -> Datacenter {
+        Datacenter {
+            id,
+            name: name.to_string(),
+            address: "127.0.0.1:7656".parse().unwrap(),
+            last_seen: 0,
+        }
+    }
+
+    #[test]
+    fn register() {
+        let mut federation = Federation::new(datacenter(1, "beijing"), vec![], 1000);
+        assert!(federation.register(datacenter(2, "shanghai"), 10).is_ok());
+        assert!(federation.register(datacenter(2, "shanghai"), 20).is_ok());
+        assert!(federation.register(datacenter(1, "beijing"), 20).is_ok());
+        assert_eq!(1, federation.view(20).datacenters.len());
+        assert!(!federation.is_conflicted(20));
+
+        // 其他数据中心之间冲突
+        assert!(federation.register(datacenter(2, "shenzhen"), 30).is_err());
+        assert!(!federation.is_conflicted(30));
+        assert_eq!(1, federation.view(30).conflicts.len());
+
+        // 和本数据中心冲突
+        assert!(federation.register(datacenter(1, "hangzhou"), 40).is_err());
+        assert!(federation.is_conflicted(40));
+        assert!(!federation.is_conflicted(2000));
+        assert!(federation.view(2000).datacenters.is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+use chrono::Utc;
+
+/// 脑裂保护。
+///
+/// 节点在[window]时间内没有确认自己仍在多数派中（follower 成功发送 keep-alive，
+/// leader 收到多数节点的 keep-alive）时视为被隔离，被隔离的节点停止发放ID。
+/// [window]为0时不启用。
+#[derive(Debug)]
+pub struct Fencing {
+    window: i64,
+    confirmed: AtomicI64,
+    isolated: AtomicBool,
+}
+
+impl Fencing {
+    pub fn new(window: Duration) -> Self {
+        Fencing {
+            window: window.as_millis() as i64,
+            confirmed: AtomicI64::new(Utc::now().timestamp_millis()),
+            isolated: AtomicBool::new(false),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Fencing::new(Duration::from_secs(0))
+    }
+
+    pub fn window(&self) -> i64 {
+        self.window
+    }
+
+    /// 确认当前节点在多数派中
+    pub fn confirm(&self) {
+        self.confirmed
+            .store(Utc::now().timestamp_millis(), Ordering::SeqCst);
+        if self.isolated.swap(false, Ordering::SeqCst) {
+            log::info!("quorum confirmed, resume issuing ids");
+        }
+    }
+
+    /// 最后一次确认多数派的时间
+    pub fn confirmed(&self) -> i64 {
+        self.confirmed.load(Ordering::SeqCst)
+    }
+
+    pub fn is_isolated(&self) -> bool {
+        if self.window == 0 {
+            return false;
+        }
+        let isolated = Utc::now().timestamp_millis() - self.confirmed() > self.window;
+        if isolated && !self.isolated.swap(true, Ordering::SeqCst) {
+            log::warn!(
+                "can't confirm quorum in {} ms, stop issuing ids",
+                self.window
+            );
+        }
+        isolated
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use crate::cluster::Fencing;
+
+    #[test]
+    fn isolated() {
+        let fencing = Fencing::new(Duration::from_millis(1000));
+        assert!(!fencing.is_isolated());
+
+        fencing.confirmed.fetch_sub(1001, Ordering::SeqCst);
+        assert!(fencing.is_isolated());
+
+        fencing.confirm();
+        assert!(!fencing.is_isolated());
+
+        let disabled = Fencing::disabled();
+        disabled.confirmed.store(0, Ordering::SeqCst);
+        assert!(!disabled.is_isolated());
+    }
+}
@@ -0,0 +1,141 @@
+use hmac::{Hmac, Mac, NewMac};
+use rand::random;
+use sha2::Sha256;
+
+use crate::cluster::DEFAULT_CLUSTER;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 集群身份：集群名称和可选的共享密钥。
+///
+/// 同一个组播网段中只有名称和密钥都相同的节点才会组成集群。密钥本身和固定的密钥摘要都不会在网络上传输，
+/// 组播报文携带对一次性nonce计算的HMAC证明，HTTP请求使用[crate::cluster::Signature]签名。
+#[derive(Clone)]
+pub struct Identity {
+    pub name: String,
+    secret: Option<String>,
+}
+
+/// 生成一次性nonce
+pub fn nonce() -> String {
+    format!("{:016x}", random::<u64>())
+}
+
+impl Identity {
+    pub fn new(name: &str, secret: Option<&str>) -> Self {
+        Identity {
+            name: name.to_string(),
+            secret: secret.map(|s| s.to_string()),
+        }
+    }
+
+    pub fn secret(&self) -> Option<&str> {
+        self.secret.as_deref()
+    }
+
+    fn mac(&self, secret: &str, nonce: &str, fields: &[&str]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_varkey(secret.as_bytes()).expect("hmac accept any key length");
+        mac.update(self.name.as_bytes());
+        mac.update(b"\n");
+        mac.update(nonce.as_bytes());
+        for field in fields {
+            mac.update(b"\n");
+            mac.update(field.as_bytes());
+        }
+        mac
+    }
+
+    /// 使用密钥对[nonce]和[fields]计算的证明，没有设置密钥时为空字符串
+    pub fn proof(&self, nonce: &str, fields: &[&str]) -> String {
+        match &self.secret {
+            None => String::new(),
+            Some(secret) => hex::encode(self.mac(secret, nonce, fields).finalize().into_bytes()),
+        }
+    }
+
+    /// 判断远程节点是否是本集群的节点，不是时返回原因
+    pub fn verify_name(&self, name: &str) -> Result<(), String> {
+        if name != self.name {
+            return Err(format!(
+                "cluster name mismatch, expect {} got {}",
+                self.name, name
+            ));
+        }
+        Ok(())
+    }
+
+    /// 判断远程节点是否属于本集群并且持有相同的密钥，不属于时返回原因
+    pub fn verify(
+        &self,
+        name: &str,
+        nonce: &str,
+        fields: &[&str],
+        proof: &str,
+    ) -> Result<(), String> {
+        self.verify_name(name)?;
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return Ok(()),
+        };
+        let proof = hex::decode(proof).map_err(|_| format!("cluster {} secret mismatch", name))?;
+        self.mac(secret, nonce, fields)
+            .verify(&proof)
+            .map_err(|_| format!("cluster {} secret mismatch", name))
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Identity::new(DEFAULT_CLUSTER, None)
+    }
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Identity")
+            .field("name", &self.name)
+            .field("secret", &self.secret.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cluster::identity::nonce;
+    use crate::cluster::Identity;
+
+    #[test]
+    fn verify() {
+        let identity = Identity::new("staging", Some("s3cret"));
+        let nonce = nonce();
+        let proof = identity.proof(&nonce, &["search"]);
+        assert_eq!(64, proof.len());
+        assert!(identity
+            .verify("staging", &nonce, &["search"], &proof)
+            .is_ok());
+        assert!(identity.verify("staging", &nonce, &["search"], "").is_err());
+        assert!(identity
+            .verify("laptop", &nonce, &["search"], &proof)
+            .is_err());
+        // 证明和nonce、内容绑定，不能用于其他报文
+        assert!(identity
+            .verify("staging", "other", &["search"], &proof)
+            .is_err());
+        assert!(identity
+            .verify("staging", &nonce, &["announce"], &proof)
+            .is_err());
+        assert_ne!(proof, identity.proof(&super::nonce(), &["search"]));
+
+        let other = Identity::new("staging", Some("other"));
+        let proof = other.proof(&nonce, &["search"]);
+        assert!(identity
+            .verify(&other.name, &nonce, &["search"], &proof)
+            .is_err());
+
+        let open = Identity::default();
+        assert_eq!("", open.proof(&nonce, &["search"]));
+        assert!(open.verify("idgener", &nonce, &["search"], "").is_ok());
+        assert!(open.verify_name("staging").is_err());
+    }
+}
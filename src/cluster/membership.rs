@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::cluster::Node;
+
+/// 一次集群成员变更
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Join(Node),
+    Leave(u16),
+    Leader {
+        id: u16,
+        term: u64,
+    },
+    /// 保留节点ID，[address]为[None]时禁止任何节点使用该ID
+    Reserve {
+        id: u16,
+        #[schema(value_type = Option<String>)]
+        address: Option<SocketAddr>,
+    },
+    Release(u16),
+    /// 管理员移除节点，记录墓碑拒绝它重新加入
+    Evict(Tombstone),
+    /// 清除节点的墓碑，允许它重新加入
+    Pardon(u16),
+}
+
+/// 被管理员移除的节点，墓碑清除之前拒绝使用该ID或者地址的节点加入
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Tombstone {
+    pub id: u16,
+    #[schema(value_type = String)]
+    pub address: SocketAddr,
+    /// 移除节点时leader的任期
+    pub term: u64,
+}
+
+/// 带版本号的成员变更，版本号由leader单调递增分配
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Delta {
+    pub version: u64,
+    /// 记录变更时leader的任期，不同任期的leader可能分配了相同的版本号
+    #[serde(default)]
+    pub term: u64,
+    pub change: Change,
+}
+
+/// 集群成员的完整快照，所有节点上相同版本的快照内容一致
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Snapshot {
+    pub version: u64,
+    pub term: u64,
+    pub leader: Option<u16>,
+    pub nodes: Vec<Node>,
+    #[serde(default)]
+    #[schema(value_type = BTreeMap<u16, Option<String>>)]
+    pub reserved: BTreeMap<u16, Option<SocketAddr>>,
+    #[serde(default)]
+    pub evicted: BTreeMap<u16, Tombstone>,
+}
+
+/// leader 推送给follower的成员状态，follower落后太多时推送完整快照，否则推送增量
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Update {
+    Snapshot(Snapshot),
+    Deltas(Vec<Delta>),
+}
+
+impl Update {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Update::Deltas(deltas) if deltas.is_empty())
+    }
+}
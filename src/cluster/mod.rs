@@ -1,5 +1,27 @@
 pub mod multicast;
 
+mod address;
+mod clock;
+mod federation;
+mod fencing;
+mod identity;
+mod membership;
 mod nodes;
+mod protocol;
+mod signature;
+mod store;
 
+pub use address::{local_ipaddress, Interface};
+pub use clock::Clock;
+pub use federation::{
+    Datacenter, Federation, View, DATACENTER_BITS, MAX_DATACENTER_ID, MAX_DATACENTER_NODE_ID,
+};
+pub use fencing::Fencing;
+pub use identity::Identity;
+pub use membership::{Change, Delta, Snapshot, Tombstone, Update};
 pub use nodes::{Node, Nodes};
+pub use signature::{Nonces, Signature, HEADER_SIGNATURE};
+pub use store::{Persisted, Store};
+
+/// 默认集群名称
+pub const DEFAULT_CLUSTER: &str = "idgener";
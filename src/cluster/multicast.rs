@@ -1,18 +1,23 @@
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use actix_web::web;
 use anyhow::bail;
-use futures::executor::block_on;
-use futures::FutureExt;
-use lazy_static::lazy_static;
 use tokio::net::UdpSocket as TokioUdpSocket;
 use tokio::sync::broadcast;
 
+use crate::cluster::protocol::{host_name, resolve_address, Message, MAX_PACKET_LEN};
+use crate::cluster::{identity, Identity};
 use crate::server::AppState;
 
-lazy_static! {
-    static ref SEARCH: [u8; 4] = [0xAA, 0xBB, 0x01, 0x02];
+/// 通过组播发现的集群leader
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Leader {
+    pub id: u16,
+    pub term: u64,
+    pub address: SocketAddr,
+    /// leader 公布的主机名
+    pub host: Option<String>,
 }
 
 fn bind_address(address: &SocketAddr, add: u16) -> SocketAddr {
@@ -29,55 +34,131 @@ fn bind_address(address: &SocketAddr, add: u16) -> SocketAddr {
     }
 }
 
+/// 在[address]上查找[identity]集群的leader，其他集群的回复会被忽略
 pub fn finder(
     address: &SocketAddr,
+    identity: &Identity,
     timeout: Option<Duration>,
-) -> anyhow::Result<Option<(SocketAddr, u16)>> {
+) -> anyhow::Result<Option<Leader>> {
     assert!(address.ip().is_multicast(), "address is not multicast");
 
-    let bind_address = bind_address(&address, 1);
+    let bind_address = bind_address(address, 1);
     let socket = UdpSocket::bind(bind_address)?;
-    socket.set_read_timeout(timeout.or(Some(Duration::from_secs(3))))?;
+    let deadline = Instant::now() + timeout.unwrap_or(Duration::from_secs(3));
 
+    let nonce = identity::nonce();
+    let search = Message::Search {
+        cluster: identity.name.clone(),
+        nonce: nonce.clone(),
+        proof: String::new(),
+    }
+    .sign(identity)
+    .encode()?;
     log::debug!("send multicast message to {}", address);
-    match socket.send_to(&*SEARCH, address) {
+    match socket.send_to(&search, address) {
         Ok(_) => log::debug!("send multicast message ok"),
         Err(e) => bail!(format!("send multicast message {}", e)),
     }
 
-    let mut buf = [0u8; 4];
-    return match socket.recv_from(&mut buf) {
-        Ok((len, remote)) => {
-            if len != 4 {
-                return Ok(None);
-            }
-            log::debug!("multicast got data: {:?} from: {}", &buf, &remote);
-
-            let n = u32::from_be_bytes(buf);
-            let remote_port = ((n >> 16) & 0xFFFF) as u16;
-            let remove_id = (n & 0xff) as u16;
-
-            Ok(Some((SocketAddr::new(remote.ip(), remote_port), remove_id)))
-        }
-        #[cfg(unix)]
-        // timeout in linux
-        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
             log::debug!("receiver remote cluster timeout");
-            Ok(None)
+            return Ok(None);
         }
-        #[cfg(windows)]
-        Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-            log::debug!("receiver remote cluster timeout");
-            Ok(None)
+        socket.set_read_timeout(Some(remaining))?;
+
+        match socket.recv_from(&mut buf) {
+            Ok((len, remote)) => {
+                log::debug!("multicast got data: {:?} from: {}", &buf[..len], &remote);
+                match Message::decode(&buf[..len]) {
+                    // 一个无效的回复不影响接收其他节点的回复
+                    Ok(message) => match accept(identity, &nonce, message, &remote) {
+                        Ok(Some(leader)) => return Ok(Some(leader)),
+                        Ok(None) => {}
+                        Err(err) => log::warn!("reject reply from {}: {:#}", remote, err),
+                    },
+                    Err(err) => log::debug!("invalid message from {}: {}", remote, err),
+                }
+            }
+            #[cfg(unix)]
+            // timeout in linux
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                log::debug!("receiver remote cluster timeout");
+                return Ok(None);
+            }
+            #[cfg(windows)]
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                log::debug!("receiver remote cluster timeout");
+                return Ok(None);
+            }
+            Err(e) => {
+                log::warn!("received: {}", e);
+                return Ok(None);
+            }
         }
-        Err(e) => {
-            log::warn!("received: {}", e);
+    }
+}
+
+/// 校验[remote]回复的[message]，只接受本集群leader对[nonce]查找请求的回复
+fn accept(
+    identity: &Identity,
+    nonce: &str,
+    message: Message,
+    remote: &SocketAddr,
+) -> anyhow::Result<Option<Leader>> {
+    if message.nonce() != nonce {
+        log::debug!("ignore reply of other search from {}", remote);
+        return Ok(None);
+    }
+    if let Err(err) = message.verify(identity) {
+        log::warn!("reject discovery reply from {}: {}", remote, err);
+        return Ok(None);
+    }
+    match message {
+        Message::Announce {
+            leader_id,
+            term,
+            address,
+            ..
+        } => Ok(Some(Leader {
+            id: leader_id,
+            term,
+            address: resolve_address(&address, remote)?,
+            host: host_name(&address),
+        })),
+        _ => {
+            log::debug!("ignore message {:?} from {}", message, remote);
             Ok(None)
         }
+    }
+}
+
+/// leader 对[search]查找消息的回复，当前节点不是leader或者集群不一致时返回[None]
+fn announce(state: &AppState, search: &Message, remote: &SocketAddr) -> Option<Message> {
+    let identity = &state.cluster;
+    if let Err(err) = search.verify(identity) {
+        log::warn!("reject discovery search from {}: {}", remote, err);
+        return None;
+    }
+    let nodes = state.nodes.read().ok()?;
+    if !nodes.self_is_leader() {
+        return None;
+    }
+    let node = nodes.get_current()?;
+    let announce = Message::Announce {
+        cluster: identity.name.clone(),
+        leader_id: node.id,
+        term: nodes.term(),
+        address: node.endpoint(),
+        nonce: search.nonce().to_string(),
+        proof: String::new(),
     };
+    Some(announce.sign(identity))
 }
 
-/// 在[multicast_address]地址上监听，并发送服务端口给组播发送者, [signal]是关闭信息信号
+/// 在[multicast_address]地址上监听，并发送服务地址给组播发送者, [signal]是关闭信息信号
 pub async fn listener(
     multicast_address: SocketAddr,
     state: web::Data<AppState>,
@@ -93,9 +174,9 @@ pub async fn listener(
 
     let socket = TokioUdpSocket::bind(&bind_address).await?;
 
-    let mut buf = [0u8; 4];
+    let mut buf = [0u8; MAX_PACKET_LEN];
     match multicast_address.ip() {
-        IpAddr::V4(ip) => socket.join_multicast_v4(ip.clone(), Ipv4Addr::UNSPECIFIED)?,
+        IpAddr::V4(ip) => socket.join_multicast_v4(ip, Ipv4Addr::UNSPECIFIED)?,
         IpAddr::V6(ip) => socket.join_multicast_v6(&ip, 0)?,
     };
 
@@ -106,27 +187,31 @@ pub async fn listener(
                 break;
             }
             output = socket.recv_from(&mut buf) => {
-               if let Ok((len, remote)) = output {
+                if let Ok((len, remote)) = output {
                     let data = &buf[..len];
                     log::info!("multicast got data: {:?} from: {}", data, &remote);
 
-                    let nodes = &state.nodes.read().unwrap();
-                    if nodes.self_is_leader() && data.eq(&*SEARCH) {
-                        let node = nodes.get_current().unwrap();
-                        let mut send_data = [0u8;4];
-                        send_data[0..2].copy_from_slice(&node.address.port().to_be_bytes()[0..2]);
-                        send_data[2..].copy_from_slice(&node.id.to_be_bytes()[0..2]);
-                        log::info!("send data: {:?} to {}", &send_data, &remote);
-                        block_on(socket.send_to(&send_data, remote).then(|output| async move {
-                            match output {
-                                Ok(_) => {
-                                    log::info!("Reply succeeded: {}", &remote);
-                                }
-                                Err(err) => {
-                                    log::warn!("Multicast server {} sent response to: {}", remote, err);
-                                }
+                    let reply = match Message::decode(data) {
+                        Ok(search @ Message::Search { .. }) => announce(&state, &search, &remote),
+                        Ok(_) => None,
+                        Err(err) => {
+                            log::debug!("invalid message from {}: {}", remote, err);
+                            None
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        let send_data = match reply.encode() {
+                            Ok(data) => data,
+                            Err(err) => {
+                                log::warn!("encode reply to {}: {:#}", remote, err);
+                                continue;
                             }
-                        }));
+                        };
+                        log::info!("send data: {:?} to {}", &send_data, &remote);
+                        match socket.send_to(&send_data, remote).await {
+                            Ok(_) => log::info!("Reply succeeded: {}", &remote),
+                            Err(err) => log::warn!("Multicast server {} sent response to: {}", remote, err),
+                        }
                     }
                 }
             }
@@ -135,23 +220,6 @@ pub async fn listener(
     Ok(())
 }
 
-pub fn local_ipaddress() -> Option<IpAddr> {
-    let socket = match UdpSocket::bind("0.0.0.0:0") {
-        Ok(s) => s,
-        Err(_) => return None,
-    };
-
-    match socket.connect("8.8.8.8:80") {
-        Ok(()) => (),
-        Err(_) => return None,
-    };
-
-    return match socket.local_addr() {
-        Ok(addr) => Some(addr.ip()),
-        Err(_) => None,
-    };
-}
-
 #[cfg(test)]
 mod test {
     use std::net::SocketAddr;
@@ -164,7 +232,7 @@ mod test {
     use tokio::sync::broadcast::{channel, Sender};
 
     use crate::cluster::multicast::{finder, listener};
-    use crate::cluster::Node;
+    use crate::cluster::{Identity, Node};
     use crate::config::logger;
     use crate::server::AppState;
 
@@ -194,12 +262,12 @@ mod test {
         thread::sleep(Duration::from_secs(1));
         let bind_address = SocketAddr::from_str("234.4.10.24:7657").unwrap();
         let timeout = Duration::from_secs(3);
-        let (remote_address, id) = finder(&bind_address, Some(timeout))
+        let leader = finder(&bind_address, &Identity::default(), Some(timeout))
             .expect("finder error")
             .expect("remote address is none");
-        assert_eq!(PORT, remote_address.port());
-        assert_eq!(0, id);
-        assert_eq!(true, SIGNAL.send(1).is_ok());
+        assert_eq!(PORT, leader.address.port());
+        assert_eq!(0, leader.id);
+        assert!(SIGNAL.send(1).is_ok());
     }
 
     #[tokio::test]
@@ -1,23 +1,54 @@
 use std::borrow::BorrowMut;
+use std::collections::{BTreeMap, VecDeque};
 use std::net::SocketAddr;
 
 use chrono::Utc;
 
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+use crate::cluster::membership::{Change, Delta, Snapshot, Tombstone, Update};
+
+/// leader 保留的最近成员变更数量，follower落后更多时推送完整快照
+const CHANGE_LOG_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Node {
     pub id: u16,
+    #[schema(value_type = String, example = "10.24.0.10:1034")]
     pub address: SocketAddr,
+    /// 节点公布的主机名，[address]只是加入时解析的结果，连接时使用主机名重新解析
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// 本机最后一次收到该节点keep-alive的时间，不在节点之间同步
+    #[serde(skip_serializing, default = "now")]
     pub last_alive_timestamp: i64,
 }
 
+fn now() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
 impl Node {
     pub fn new(id: u16, address: SocketAddr) -> Self {
         Self {
             id,
             address,
-            last_alive_timestamp: Utc::now().timestamp_millis(),
+            host: None,
+            last_alive_timestamp: now(),
+        }
+    }
+
+    pub fn with_host(mut self, host: Option<String>) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// 连接该节点使用的地址，公布了主机名时为主机名和端口
+    pub fn endpoint(&self) -> String {
+        match &self.host {
+            Some(host) => format!("{}:{}", host, self.address.port()),
+            None => self.address.to_string(),
         }
     }
 }
@@ -26,7 +57,24 @@ impl Node {
 pub struct Nodes {
     current: Option<u16>,
     leader: Option<u16>,
+    /// 任期，每次切换leader加一
+    #[serde(default)]
+    term: u64,
+    /// 成员版本，leader每次变更成员加一
+    #[serde(default)]
+    version: u64,
     pub nodes: Vec<Node>,
+    /// 保留的节点ID，值为允许使用该ID的节点地址，[None]表示禁止使用
+    #[serde(default)]
+    reserved: BTreeMap<u16, Option<SocketAddr>>,
+    /// 被管理员移除的节点，管理员清除之前拒绝它们重新加入
+    #[serde(default)]
+    evicted: BTreeMap<u16, Tombstone>,
+    #[serde(skip)]
+    changes: VecDeque<Delta>,
+    /// 可以分配的最大节点ID，开启数据中心后节点ID只有较少的位数
+    #[serde(skip)]
+    max_id: Option<u16>,
 }
 
 /// 主机管理器
@@ -36,7 +84,13 @@ impl Nodes {
         Nodes {
             leader: None,
             current: None,
+            term: 0,
+            version: 0,
             nodes: vec![],
+            reserved: BTreeMap::new(),
+            evicted: BTreeMap::new(),
+            changes: VecDeque::new(),
+            max_id: None,
         }
     }
 
@@ -57,19 +111,19 @@ impl Nodes {
     }
 
     pub fn get_current(&self) -> Option<&Node> {
-        if let Some(current_id) = self.current {
-            let idx = self.get_index(current_id);
-            return self.nodes.get(idx);
-        }
-        None
+        self.current.and_then(|id| self.get(id))
+    }
+
+    pub fn leader_id(&self) -> Option<u16> {
+        self.leader
     }
 
     pub fn get_leader(&self) -> Option<&Node> {
-        if let Some(current_id) = self.leader {
-            let idx = self.get_index(current_id);
-            return self.nodes.get(idx);
-        }
-        None
+        self.leader.and_then(|id| self.get(id))
+    }
+
+    pub fn get(&self, id: u16) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.id == id)
     }
 
     /// 获取[id]的主机
@@ -77,7 +131,7 @@ impl Nodes {
         let idx = self.get_index(id);
         match self.nodes.get(idx + 1) {
             Some(node) => Some(node),
-            None => self.nodes.get(0),
+            None => self.nodes.first(),
         }
     }
 
@@ -90,25 +144,109 @@ impl Nodes {
         None
     }
 
+    /// 加入节点，相同id的节点会被替换。只用于应用已经确认的变更，新节点的ID由[assign_id]检查冲突
     #[inline]
     pub fn join(&mut self, node: Node) -> &mut Self {
         let idx = self.get_index(node.id);
-        self.nodes.insert(idx, node);
+        match self.nodes.get_mut(idx) {
+            Some(exists) if exists.id == node.id => *exists = node,
+            _ => self.nodes.insert(idx, node),
+        }
         self
     }
 
+    /// 移除[id]节点，返回被移除的节点
+    pub fn leave(&mut self, id: u16) -> Option<Node> {
+        let idx = self.nodes.iter().position(|node| node.id == id)?;
+        Some(self.nodes.remove(idx))
+    }
+
+    /// 按照环形顺序返回[id]之后的其他节点，用于挑选leader的继任者
+    pub fn successors(&self, id: u16) -> Vec<Node> {
+        let (before, after): (Vec<&Node>, Vec<&Node>) = self
+            .nodes
+            .iter()
+            .filter(|node| node.id != id)
+            .partition(|node| node.id < id);
+        after.into_iter().chain(before).cloned().collect()
+    }
+
+    /// 分配最小的没有使用的ID，跳过保留的ID和被移除节点的ID
     pub fn new_node_id(&self) -> u16 {
+        let taken = |id: &u16| self.reserved.contains_key(id) || self.evicted.contains_key(id);
         let mut node_id: u16 = 0;
         for node in &self.nodes {
+            while taken(&node_id) {
+                node_id += 1;
+            }
             if node_id < node.id {
                 return node_id;
-            } else {
+            } else if node_id == node.id {
                 node_id += 1
             }
         }
+        while taken(&node_id) {
+            node_id += 1;
+        }
         node_id
     }
 
+    /// 限制可以分配的最大节点ID
+    pub fn set_max_id(&mut self, max_id: u16) -> &mut Self {
+        self.max_id = Some(max_id);
+        self
+    }
+
+    /// 为[address]分配节点ID，优先使用为该地址保留的ID，
+    /// [requested]被保留给其他节点或者已经被其他节点使用、分配的ID超过最大值时返回错误
+    pub fn assign_id(&self, address: &SocketAddr, requested: Option<u16>) -> Result<u16, String> {
+        let id = self.assign(address, requested)?;
+        match self.max_id {
+            Some(max_id) if id > max_id => Err(format!("node id {} exceed {}", id, max_id)),
+            _ => Ok(id),
+        }
+    }
+
+    fn assign(&self, address: &SocketAddr, requested: Option<u16>) -> Result<u16, String> {
+        if let Some(exists) = requested.and_then(|id| self.get(id)) {
+            if exists.address != *address {
+                return Err(format!(
+                    "node id {} is used by {}",
+                    exists.id, exists.address
+                ));
+            }
+        }
+        match requested {
+            Some(id) => match self.reserved.get(&id) {
+                None => Ok(id),
+                Some(Some(reserved)) if reserved == address => Ok(id),
+                Some(Some(reserved)) => Err(format!("node id {} is reserved for {}", id, reserved)),
+                Some(None) => Err(format!("node id {} is blacklisted", id)),
+            },
+            None => Ok(self
+                .reserved
+                .iter()
+                .find(|(_, reserved)| reserved.as_ref() == Some(address))
+                .map(|(id, _)| *id)
+                .unwrap_or_else(|| self.new_node_id())),
+        }
+    }
+
+    pub fn reserved(&self) -> &BTreeMap<u16, Option<SocketAddr>> {
+        &self.reserved
+    }
+
+    pub fn evicted(&self) -> &BTreeMap<u16, Tombstone> {
+        &self.evicted
+    }
+
+    /// 查找使用[requested]或者[address]加入的节点对应的墓碑
+    pub fn tombstone(&self, address: &SocketAddr, requested: Option<u16>) -> Option<&Tombstone> {
+        self.evicted
+            .values()
+            .find(|tombstone| Some(tombstone.id) == requested || tombstone.address == *address)
+    }
+
     pub fn is_leader(&self, node_id: u16) -> bool {
         match self.leader {
             Some(leader_id) => leader_id == node_id,
@@ -128,6 +266,152 @@ impl Nodes {
         self
     }
 
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    #[inline]
+    pub fn set_term(&mut self, term: u64) -> &mut Self {
+        self.term = term;
+        self
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: self.version,
+            term: self.term,
+            leader: self.leader,
+            nodes: self.nodes.clone(),
+            reserved: self.reserved.clone(),
+            evicted: self.evicted.clone(),
+        }
+    }
+
+    /// leader 记录一次成员变更，分配新的版本号
+    pub fn record(&mut self, change: Change) -> Delta {
+        let delta = Delta {
+            version: self.version + 1,
+            term: self.term,
+            change,
+        };
+        self.change(&delta.change);
+        self.version = delta.version;
+        self.changes.push_back(delta.clone());
+        while self.changes.len() > CHANGE_LOG_SIZE {
+            self.changes.pop_front();
+        }
+        delta
+    }
+
+    /// 返回[version]之后的成员变更，新节点或者变更日志不完整时返回完整快照
+    pub fn update_since(&self, version: u64) -> Update {
+        if version >= self.version {
+            return Update::Deltas(vec![]);
+        }
+        match self.changes.front() {
+            Some(first) if version > 0 && first.version <= version + 1 => Update::Deltas(
+                self.changes
+                    .iter()
+                    .filter(|delta| delta.version > version)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => Update::Snapshot(self.snapshot()),
+        }
+    }
+
+    /// follower 应用leader推送的成员状态，重复应用是安全的。
+    /// 返回是否有变化，增量不连续或者来自其他任期时返回当前版本号，需要leader推送完整快照。
+    pub fn apply(&mut self, update: &Update) -> Result<bool, u64> {
+        match update {
+            Update::Snapshot(snapshot) => {
+                if (snapshot.term, snapshot.version) <= (self.term, self.version) {
+                    return Ok(false);
+                }
+                let alive = self.nodes.clone();
+                self.nodes = snapshot.nodes.clone();
+                for node in self.nodes.iter_mut() {
+                    if let Some(exists) = alive.iter().find(|n| n.id == node.id) {
+                        node.last_alive_timestamp = exists.last_alive_timestamp;
+                    }
+                }
+                self.reserved = snapshot.reserved.clone();
+                self.evicted = snapshot.evicted.clone();
+                self.leader = snapshot.leader;
+                self.term = snapshot.term;
+                self.version = snapshot.version;
+                self.changes.clear();
+                Ok(true)
+            }
+            Update::Deltas(deltas) => {
+                let mut changed = false;
+                for delta in deltas.iter() {
+                    // 其他任期的leader记录的变更历史可能和本机不同，相同版本号不代表相同的变更
+                    if delta.term != self.term {
+                        return Err(self.version);
+                    }
+                    if delta.version <= self.version {
+                        continue;
+                    }
+                    if delta.version != self.version + 1 {
+                        return Err(self.version);
+                    }
+                    self.change(&delta.change);
+                    self.version = delta.version;
+                    changed = true;
+                }
+                Ok(changed)
+            }
+        }
+    }
+
+    fn change(&mut self, change: &Change) {
+        match change {
+            Change::Join(node) => {
+                let mut node = node.clone();
+                if let Some(exists) = self.get(node.id) {
+                    node.last_alive_timestamp = exists.last_alive_timestamp;
+                }
+                self.join(node);
+            }
+            Change::Leave(id) => {
+                self.leave(*id);
+            }
+            Change::Leader { id, term } => {
+                self.set_leader(Some(*id)).set_term(*term);
+            }
+            Change::Reserve { id, address } => {
+                self.reserved.insert(*id, *address);
+            }
+            Change::Release(id) => {
+                self.reserved.remove(id);
+            }
+            Change::Evict(tombstone) => {
+                self.leave(tombstone.id);
+                self.evicted.insert(tombstone.id, tombstone.clone());
+            }
+            Change::Pardon(id) => {
+                self.evicted.remove(id);
+            }
+        }
+    }
+
+    /// leader 判断是否仍然拥有多数派：[window]毫秒内发送过keep-alive的节点（包括自己）超过半数
+    pub fn has_quorum(&self, now: i64, window: i64) -> bool {
+        let alive = self
+            .nodes
+            .iter()
+            .filter(|node| {
+                Some(node.id) == self.current || now - node.last_alive_timestamp <= window
+            })
+            .count();
+        alive > self.nodes.len() / 2
+    }
+
     pub fn self_is_leader(&self) -> bool {
         match self.current {
             Some(current) => self.is_leader(current),
@@ -138,6 +422,7 @@ impl Nodes {
 
 #[cfg(test)]
 mod tests {
+    use crate::cluster::membership::{Change, Tombstone, Update};
     use crate::cluster::{Node, Nodes};
     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
@@ -146,7 +431,13 @@ mod tests {
         let mut nodes = Nodes {
             leader: Some(0_u16),
             current: Some(0_u16),
+            term: 0,
+            version: 0,
             nodes: vec![Node::new(0, addr)],
+            reserved: Default::default(),
+            evicted: Default::default(),
+            changes: Default::default(),
+            max_id: None,
         };
         nodes.join(Node::new(
             nodes.new_node_id(),
@@ -156,7 +447,7 @@ mod tests {
             nodes.new_node_id(),
             "192.168.11.45:8081".parse().unwrap(),
         ));
-        return nodes;
+        nodes
     }
 
     #[test]
@@ -183,16 +474,16 @@ mod tests {
     fn is_leader() {
         let mut nodes = nodes();
         assert_eq!(3, nodes.nodes.len());
-        assert_eq!(true, nodes.self_is_leader());
-        assert_eq!(false, nodes.is_leader(1));
-        assert_eq!(false, nodes.is_leader(2));
+        assert!(nodes.self_is_leader());
+        assert!(!nodes.is_leader(1));
+        assert!(!nodes.is_leader(2));
 
         nodes.set_leader(Some(1));
         let node = nodes.get_leader().unwrap();
         assert_eq!(1, node.id);
 
         nodes.set_leader(Some(2));
-        assert_eq!(false, nodes.self_is_leader());
+        assert!(!nodes.self_is_leader());
         let node = nodes.get_leader().unwrap();
         assert_eq!(2, node.id);
     }
@@ -200,7 +491,7 @@ mod tests {
     #[test]
     fn set_leader() {
         let mut nodes = nodes();
-        assert_eq!(true, nodes.self_is_leader());
+        assert!(nodes.self_is_leader());
 
         let current = nodes.get_current().unwrap();
         assert_eq!(0, current.id);
@@ -234,6 +525,30 @@ mod tests {
         assert_eq!(0, leader.id);
     }
 
+    #[test]
+    fn leave() {
+        let mut nodes = nodes();
+        assert!(nodes.leave(7).is_none());
+        assert_eq!(1, nodes.leave(1).unwrap().id);
+        assert_eq!(2, nodes.nodes.len());
+        assert_eq!(1, nodes.new_node_id());
+    }
+
+    #[test]
+    fn successors() {
+        let nodes = nodes();
+        let ids = |id| {
+            nodes
+                .successors(id)
+                .iter()
+                .map(|n| n.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![1, 2], ids(0));
+        assert_eq!(vec![2, 0], ids(1));
+        assert_eq!(vec![0, 1], ids(2));
+    }
+
     #[test]
     fn test_next() {
         let nodes = nodes();
@@ -246,4 +561,207 @@ mod tests {
         assert!(n1.is_some());
         assert_eq!(0, n1.unwrap().id);
     }
+
+    #[test]
+    fn has_quorum() {
+        let mut nodes = nodes();
+        let now = nodes.get(0).unwrap().last_alive_timestamp + 5000;
+        assert!(!nodes.has_quorum(now, 3000));
+        nodes
+            .get_node_by_address(&"127.0.0.1:8685".parse().unwrap())
+            .unwrap()
+            .last_alive_timestamp = now;
+        assert!(nodes.has_quorum(now, 3000));
+
+        let mut single = Nodes::default();
+        single
+            .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+            .set_current(0);
+        assert!(single.has_quorum(now + 100_000, 3000));
+    }
+
+    #[test]
+    fn join_replace() {
+        let mut nodes = nodes();
+        nodes.join(Node::new(1, "127.0.0.9:8685".parse().unwrap()));
+        nodes.join(Node::new(1, "127.0.0.9:8685".parse().unwrap()));
+        assert_eq!(3, nodes.nodes.len());
+        assert_eq!(
+            "127.0.0.9:8685".parse::<SocketAddr>().unwrap(),
+            nodes.get(1).unwrap().address
+        );
+    }
+
+    #[test]
+    fn endpoint() {
+        let node = Node::new(1, "10.0.0.1:7656".parse().unwrap());
+        assert_eq!("10.0.0.1:7656", node.endpoint());
+        let node = node.with_host(Some(String::from("node-1")));
+        assert_eq!("node-1:7656", node.endpoint());
+        let json = serde_json::to_string(&node).unwrap();
+        assert_eq!(
+            Some(String::from("node-1")),
+            serde_json::from_str::<Node>(&json).unwrap().host
+        );
+    }
+
+    fn follower(leader: &Nodes) -> Nodes {
+        let mut follower = Nodes::default();
+        follower.set_current(1);
+        assert!(follower.apply(&leader.update_since(0)).unwrap());
+        follower
+    }
+
+    fn same(leader: &Nodes, follower: &Nodes) {
+        assert_eq!(
+            serde_json::to_string(&leader.snapshot()).unwrap(),
+            serde_json::to_string(&follower.snapshot()).unwrap()
+        );
+    }
+
+    #[test]
+    fn replicate() {
+        let mut leader = nodes();
+        leader.record(Change::Join(Node::new(3, "127.0.0.3:80".parse().unwrap())));
+        let mut follower = follower(&leader);
+        same(&leader, &follower);
+
+        let version = follower.version();
+        leader.record(Change::Join(Node::new(4, "127.0.0.4:80".parse().unwrap())));
+        leader.record(Change::Leave(2));
+        let update = leader.update_since(version);
+        assert!(matches!(update, Update::Deltas(ref deltas) if deltas.len() == 2));
+
+        assert_eq!(Ok(true), follower.apply(&update));
+        same(&leader, &follower);
+
+        // 重复应用
+        assert_eq!(Ok(false), follower.apply(&update));
+        assert_eq!(Ok(false), follower.apply(&leader.update_since(0)));
+        assert!(leader.update_since(leader.version()).is_empty());
+        same(&leader, &follower);
+        assert_eq!(Some(1), follower.get_current().map(|n| n.id));
+    }
+
+    #[test]
+    fn replicate_gap() {
+        let mut leader = nodes();
+        leader.record(Change::Join(Node::new(3, "127.0.0.3:80".parse().unwrap())));
+        let mut follower = follower(&leader);
+
+        leader.record(Change::Leave(2));
+        leader.record(Change::Leader { id: 1, term: 2 });
+        // 只收到最后一个变更
+        let last = match leader.update_since(leader.version() - 1) {
+            Update::Deltas(deltas) => Update::Deltas(deltas),
+            Update::Snapshot(_) => unreachable!(),
+        };
+        assert_eq!(Err(follower.version()), follower.apply(&last));
+
+        assert_eq!(
+            Ok(true),
+            follower.apply(&leader.update_since(follower.version()))
+        );
+        same(&leader, &follower);
+        assert_eq!(1, follower.get_leader().unwrap().id);
+        assert_eq!(2, follower.term());
+    }
+
+    #[test]
+    fn replicate_other_term() {
+        let mut leader = nodes();
+        leader.record(Change::Join(Node::new(3, "127.0.0.3:80".parse().unwrap())));
+        let mut follower = follower(&leader);
+
+        // 其他任期的leader从较低的版本分配了不同的变更
+        let mut other = nodes();
+        other.set_term(1);
+        other.record(Change::Join(Node::new(4, "127.0.0.4:80".parse().unwrap())));
+        other.record(Change::Join(Node::new(5, "127.0.0.5:80".parse().unwrap())));
+        let update = other.update_since(other.version() - 1);
+        assert_eq!(Err(follower.version()), follower.apply(&update));
+
+        assert_eq!(Ok(true), follower.apply(&other.update_since(0)));
+        same(&other, &follower);
+        // 旧任期的leader推送的快照不会覆盖
+        assert_eq!(Ok(false), follower.apply(&leader.update_since(0)));
+    }
+
+    #[test]
+    fn snapshot_when_log_truncated() {
+        let mut leader = nodes();
+        for _ in 0..200 {
+            leader.record(Change::Join(Node::new(9, "127.0.0.9:80".parse().unwrap())));
+        }
+        assert!(matches!(leader.update_since(1), Update::Snapshot(_)));
+        assert!(matches!(
+            leader.update_since(leader.version() - 3),
+            Update::Deltas(ref deltas) if deltas.len() == 3
+        ));
+    }
+
+    #[test]
+    fn reserve() {
+        let mut leader = nodes();
+        let address = "127.0.0.9:80".parse::<SocketAddr>().unwrap();
+        let other = "127.0.0.8:80".parse::<SocketAddr>().unwrap();
+        leader.record(Change::Reserve {
+            id: 3,
+            address: Some(address),
+        });
+        leader.record(Change::Reserve {
+            id: 4,
+            address: None,
+        });
+        assert_eq!(5, leader.new_node_id());
+        assert_eq!(Ok(3), leader.assign_id(&address, None));
+        assert_eq!(Ok(5), leader.assign_id(&other, None));
+        assert_eq!(Ok(3), leader.assign_id(&address, Some(3)));
+        assert!(leader.assign_id(&other, Some(3)).is_err());
+        assert!(leader.assign_id(&address, Some(4)).is_err());
+        // 已经被其他节点使用的ID
+        assert!(leader.assign_id(&other, Some(1)).is_err());
+        let used = leader.get(1).unwrap().address;
+        assert_eq!(Ok(1), leader.assign_id(&used, Some(1)));
+
+        // 开启数据中心后节点ID的位数较少
+        leader.set_max_id(4);
+        assert!(leader.assign_id(&other, None).is_err());
+        assert!(leader.assign_id(&other, Some(6)).is_err());
+        assert_eq!(Ok(3), leader.assign_id(&address, None));
+
+        let mut follower = follower(&leader);
+        assert_eq!(leader.reserved(), follower.reserved());
+
+        let version = follower.version();
+        leader.record(Change::Release(4));
+        assert_eq!(Ok(true), follower.apply(&leader.update_since(version)));
+        assert_eq!(4, follower.new_node_id());
+    }
+
+    #[test]
+    fn evict() {
+        let mut leader = nodes();
+        let address = leader.get(1).unwrap().address;
+        let other = "127.0.0.9:80".parse::<SocketAddr>().unwrap();
+        leader.record(Change::Evict(Tombstone {
+            id: 1,
+            address,
+            term: leader.term(),
+        }));
+        assert!(leader.get(1).is_none());
+        assert_eq!(Some(1), leader.tombstone(&address, None).map(|t| t.id));
+        assert_eq!(Some(1), leader.tombstone(&other, Some(1)).map(|t| t.id));
+        assert!(leader.tombstone(&other, None).is_none());
+        assert_ne!(1, leader.new_node_id());
+
+        let mut follower = follower(&leader);
+        assert_eq!(leader.evicted(), follower.evicted());
+
+        let version = follower.version();
+        leader.record(Change::Pardon(1));
+        assert!(leader.tombstone(&address, Some(1)).is_none());
+        assert_eq!(Ok(true), follower.apply(&leader.update_since(version)));
+        assert!(follower.evicted().is_empty());
+    }
 }
@@ -0,0 +1,399 @@
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use anyhow::{bail, Context};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::cluster::Identity;
+
+/// 组播报文魔数
+const MAGIC: [u8; 2] = [0xAA, 0xBB];
+
+/// 当前协议版本，高版本报文中本版本不认识的字段会被忽略
+pub const VERSION: u8 = 1;
+
+/// 报文头长度：magic(2) + version(1) + kind(1) + body length(2)
+const HEADER_LEN: usize = 6;
+
+/// 单个组播报文的最大长度
+pub const MAX_PACKET_LEN: usize = 1024;
+
+const KIND_SEARCH: u8 = 1;
+const KIND_ANNOUNCE: u8 = 2;
+
+/// 组播发现协议报文。
+///
+/// 格式为 `magic | version | kind | length(u16) | body`，body 中的字符串使用 u8 长度前缀，
+/// 数字使用大端序。
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    /// 新节点查找集群leader
+    Search {
+        cluster: String,
+        /// 一次性nonce，leader 的回复需要携带相同的nonce
+        nonce: String,
+        /// 对nonce计算的密钥证明，见[crate::cluster::Identity::proof]
+        proof: String,
+    },
+    /// leader 对查找请求的回复
+    Announce {
+        cluster: String,
+        leader_id: u16,
+        term: u64,
+        /// leader 对外公布的地址，可以是 IPv4、IPv6 或者主机名
+        address: String,
+        /// 查找请求的nonce
+        nonce: String,
+        proof: String,
+    },
+}
+
+impl Message {
+    pub fn cluster(&self) -> &str {
+        match self {
+            Message::Search { cluster, .. } => cluster,
+            Message::Announce { cluster, .. } => cluster,
+        }
+    }
+
+    pub fn nonce(&self) -> &str {
+        match self {
+            Message::Search { nonce, .. } => nonce,
+            Message::Announce { nonce, .. } => nonce,
+        }
+    }
+
+    pub fn proof(&self) -> &str {
+        match self {
+            Message::Search { proof, .. } => proof,
+            Message::Announce { proof, .. } => proof,
+        }
+    }
+
+    /// 使用[identity]的密钥计算证明
+    pub fn sign(mut self, identity: &Identity) -> Message {
+        let fields = self.signed_fields();
+        let fields = fields.iter().map(String::as_str).collect::<Vec<_>>();
+        let signed = identity.proof(self.nonce(), &fields);
+        match &mut self {
+            Message::Search { proof, .. } => *proof = signed,
+            Message::Announce { proof, .. } => *proof = signed,
+        }
+        self
+    }
+
+    /// 校验报文的集群名称和密钥证明
+    pub fn verify(&self, identity: &Identity) -> Result<(), String> {
+        let fields = self.signed_fields();
+        let fields = fields.iter().map(String::as_str).collect::<Vec<_>>();
+        identity.verify(self.cluster(), self.nonce(), &fields, self.proof())
+    }
+
+    /// 证明覆盖的字段，announce 的证明同时覆盖leader和地址，避免被篡改
+    fn signed_fields(&self) -> Vec<String> {
+        match self {
+            Message::Search { .. } => vec![String::from("search")],
+            Message::Announce {
+                leader_id,
+                term,
+                address,
+                ..
+            } => vec![
+                String::from("announce"),
+                leader_id.to_string(),
+                term.to_string(),
+                address.clone(),
+            ],
+        }
+    }
+
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut body = vec![];
+        let kind = match self {
+            Message::Search {
+                cluster,
+                nonce,
+                proof,
+            } => {
+                write_str(&mut body, cluster)?;
+                write_str(&mut body, proof)?;
+                write_str(&mut body, nonce)?;
+                KIND_SEARCH
+            }
+            Message::Announce {
+                cluster,
+                leader_id,
+                term,
+                address,
+                nonce,
+                proof,
+            } => {
+                write_str(&mut body, cluster)?;
+                body.write_u16::<BigEndian>(*leader_id)?;
+                body.write_u64::<BigEndian>(*term)?;
+                write_str(&mut body, address)?;
+                write_str(&mut body, proof)?;
+                write_str(&mut body, nonce)?;
+                KIND_ANNOUNCE
+            }
+        };
+        if HEADER_LEN + body.len() > MAX_PACKET_LEN {
+            bail!("multicast message too large: {}", body.len());
+        }
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+        buf.write_all(&MAGIC)?;
+        buf.write_u8(VERSION)?;
+        buf.write_u8(kind)?;
+        buf.write_u16::<BigEndian>(body.len() as u16)?;
+        buf.write_all(&body)?;
+        Ok(buf)
+    }
+
+    pub fn decode(data: &[u8]) -> anyhow::Result<Message> {
+        let mut reader = Cursor::new(data);
+        let mut magic = [0u8; 2];
+        reader.read_exact(&mut magic).context("read magic")?;
+        if magic != MAGIC {
+            bail!("invalid magic: {:?}", magic);
+        }
+        let version = reader.read_u8().context("read version")?;
+        if version == 0 {
+            bail!("unsupported protocol version: {}", version);
+        }
+        let kind = reader.read_u8().context("read kind")?;
+        let len = reader.read_u16::<BigEndian>().context("read length")? as usize;
+        if data.len() < HEADER_LEN + len {
+            bail!(
+                "truncated message, want {} got {}",
+                len,
+                data.len() - HEADER_LEN
+            );
+        }
+
+        let mut body = Cursor::new(&data[HEADER_LEN..HEADER_LEN + len]);
+        let cluster = read_str(&mut body).context("read cluster")?;
+        match kind {
+            KIND_SEARCH => {
+                let proof = read_optional_str(&mut body).context("read proof")?;
+                Ok(Message::Search {
+                    cluster,
+                    nonce: read_optional_str(&mut body).context("read nonce")?,
+                    proof,
+                })
+            }
+            KIND_ANNOUNCE => {
+                let leader_id = body.read_u16::<BigEndian>().context("read leader id")?;
+                let term = body.read_u64::<BigEndian>().context("read term")?;
+                let address = read_str(&mut body).context("read address")?;
+                let proof = read_optional_str(&mut body).context("read proof")?;
+                Ok(Message::Announce {
+                    cluster,
+                    leader_id,
+                    term,
+                    address,
+                    nonce: read_optional_str(&mut body).context("read nonce")?,
+                    proof,
+                })
+            }
+            _ => bail!("unknown message kind: {}", kind),
+        }
+    }
+}
+
+/// 解析 leader 公布的地址，如果公布的地址是未指定地址（0.0.0.0/::）则使用报文的来源IP
+pub fn resolve_address(address: &str, remote: &SocketAddr) -> anyhow::Result<SocketAddr> {
+    let address = address
+        .to_socket_addrs()
+        .with_context(|| format!("resolve address {}", address))?
+        .next()
+        .with_context(|| format!("not found address {}", address))?;
+    if address.ip().is_unspecified() {
+        return Ok(SocketAddr::new(remote.ip(), address.port()));
+    }
+    Ok(address)
+}
+
+/// 公布的地址中的主机名，地址使用IP时返回[None]
+pub fn host_name(address: &str) -> Option<String> {
+    let host = match address.rfind(':') {
+        Some(idx) => &address[..idx],
+        None => address,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(_) => None,
+        Err(_) => Some(host.to_string()),
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, value: &str) -> anyhow::Result<()> {
+    if value.len() > u8::MAX as usize {
+        bail!("string too long: {}", value);
+    }
+    buf.write_u8(value.len() as u8)?;
+    buf.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+fn read_str(reader: &mut Cursor<&[u8]>) -> anyhow::Result<String> {
+    let len = reader.read_u8()? as usize;
+    let mut value = vec![0u8; len];
+    reader.read_exact(&mut value)?;
+    Ok(String::from_utf8(value)?)
+}
+
+/// 读取追加在报文末尾的字段，旧版本的报文中没有该字段时返回空字符串
+fn read_optional_str(reader: &mut Cursor<&[u8]>) -> anyhow::Result<String> {
+    if reader.position() as usize >= reader.get_ref().len() {
+        return Ok(String::new());
+    }
+    read_str(reader)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::cluster::protocol::{host_name, resolve_address, Message};
+    use crate::cluster::Identity;
+
+    #[test]
+    fn search() {
+        let message = Message::Search {
+            cluster: String::from("idgener"),
+            nonce: String::from("0123456789abcdef"),
+            proof: String::from("fedcba9876543210"),
+        };
+        let data = message.encode().unwrap();
+        assert_eq!(message, Message::decode(&data).unwrap());
+    }
+
+    #[test]
+    fn announce() {
+        let message = Message::Announce {
+            cluster: String::from("idgener"),
+            leader_id: 1023,
+            term: 7,
+            address: String::from("[fe80::1]:7656"),
+            nonce: String::from("0123456789abcdef"),
+            proof: String::new(),
+        };
+        let data = message.encode().unwrap();
+        assert_eq!(message, Message::decode(&data).unwrap());
+    }
+
+    #[test]
+    fn ignore_unknown_fields() {
+        let message = Message::Search {
+            cluster: String::from("idgener"),
+            nonce: String::new(),
+            proof: String::new(),
+        };
+        let mut data = message.encode().unwrap();
+        // 模拟高版本协议追加的字段
+        data[2] = 2;
+        data[5] += 3;
+        data.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(message, Message::decode(&data).unwrap());
+    }
+
+    #[test]
+    fn signed() {
+        let identity = Identity::new("idgener", Some("s3cret"));
+        let search = Message::Search {
+            cluster: String::from("idgener"),
+            nonce: String::from("0123456789abcdef"),
+            proof: String::new(),
+        };
+        assert!(search.verify(&identity).is_err());
+        let search = search.sign(&identity);
+        assert!(search.verify(&identity).is_ok());
+        assert!(search
+            .verify(&Identity::new("idgener", Some("other")))
+            .is_err());
+
+        let announce = Message::Announce {
+            cluster: String::from("idgener"),
+            leader_id: 1,
+            term: 2,
+            address: String::from("10.0.0.1:7656"),
+            nonce: String::from("0123456789abcdef"),
+            proof: String::new(),
+        }
+        .sign(&identity);
+        assert!(announce.verify(&identity).is_ok());
+        // 篡改leader地址后证明失效
+        if let Message::Announce {
+            cluster,
+            term,
+            nonce,
+            proof,
+            ..
+        } = announce
+        {
+            let forged = Message::Announce {
+                cluster,
+                leader_id: 1,
+                term,
+                address: String::from("10.0.0.66:7656"),
+                nonce,
+                proof,
+            };
+            assert!(forged.verify(&identity).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Message::decode(&[0xAA, 0xBB, 0x01, 0x02]).is_err());
+        assert!(Message::decode(&[0x00, 0x00, 0x01, 0x01, 0x00, 0x00]).is_err());
+
+        let mut data = Message::Search {
+            cluster: String::from("idgener"),
+            nonce: String::from("0123456789abcdef"),
+            proof: String::new(),
+        }
+        .encode()
+        .unwrap();
+        data.truncate(data.len() - 1);
+        assert!(Message::decode(&data).is_err());
+    }
+
+    #[test]
+    fn without_proof() {
+        // 没有 proof 和 nonce 字段的早期报文
+        let data = [0xAA, 0xBB, 0x01, 0x01, 0x00, 0x04, 0x03, b'a', b'b', b'c'];
+        assert_eq!(
+            Message::Search {
+                cluster: String::from("abc"),
+                nonce: String::new(),
+                proof: String::new(),
+            },
+            Message::decode(&data).unwrap()
+        );
+    }
+
+    #[test]
+    fn resolve() {
+        let remote = "10.0.0.3:5000".parse::<SocketAddr>().unwrap();
+        let address = resolve_address("0.0.0.0:7656", &remote).unwrap();
+        assert_eq!("10.0.0.3:7656".parse::<SocketAddr>().unwrap(), address);
+
+        let address = resolve_address("[::1]:7656", &remote).unwrap();
+        assert_eq!("[::1]:7656".parse::<SocketAddr>().unwrap(), address);
+
+        let address = resolve_address("localhost:7656", &remote).unwrap();
+        assert!(address.ip().is_loopback());
+    }
+
+    #[test]
+    fn host() {
+        assert_eq!(None, host_name("10.0.0.3:7656"));
+        assert_eq!(None, host_name("[::1]:7656"));
+        assert_eq!(
+            Some(String::from("idgen-0.idgen")),
+            host_name("idgen-0.idgen:7656")
+        );
+    }
+}
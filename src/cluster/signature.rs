@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use rand::random;
+use sha2::Sha256;
+
+pub const HEADER_TIMESTAMP: &str = "x-idgend-timestamp";
+pub const HEADER_NONCE: &str = "x-idgend-nonce";
+pub const HEADER_SIGNATURE: &str = "x-idgend-signature";
+
+/// 请求时间和本机时间允许的最大误差，超过的请求视为重放
+pub const MAX_SKEW_MILLIS: i64 = 30_000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 节点间请求签名。
+///
+/// 签名内容为 `method \n path \n timestamp \n nonce \n body`，使用集群共享密钥计算 HMAC-SHA256。
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Signature {
+    pub timestamp: i64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl Signature {
+    /// 使用当前时间和随机nonce签名
+    pub fn sign(secret: &str, method: &str, path: &str, body: &[u8]) -> Self {
+        let timestamp = Utc::now().timestamp_millis();
+        let nonce = format!("{:016x}", random::<u64>());
+        let signature = hex::encode(
+            digest(secret, method, path, timestamp, &nonce, body)
+                .finalize()
+                .into_bytes(),
+        );
+        Signature {
+            timestamp,
+            nonce,
+            signature,
+        }
+    }
+
+    /// 对节点请求的响应签名，签名沿用请求的nonce，响应只能对应这一次请求，不能被伪造或者重放给其他请求
+    pub fn sign_response(
+        secret: &str,
+        request: &Signature,
+        status: u16,
+        path: &str,
+        body: &[u8],
+    ) -> Self {
+        let timestamp = Utc::now().timestamp_millis();
+        let method = response_method(status);
+        let signature = hex::encode(
+            digest(secret, &method, path, timestamp, &request.nonce, body)
+                .finalize()
+                .into_bytes(),
+        );
+        Signature {
+            timestamp,
+            nonce: request.nonce.clone(),
+            signature,
+        }
+    }
+
+    /// 从请求头中读取签名，[header]根据名称返回请求头的值
+    pub fn from_headers<'a, F>(header: F) -> Result<Self, String>
+    where
+        F: Fn(&str) -> Option<&'a str>,
+    {
+        let value = |name: &str| header(name).ok_or(format!("missing header {}", name));
+        Ok(Signature {
+            timestamp: value(HEADER_TIMESTAMP)?
+                .parse()
+                .map_err(|_| format!("invalid header {}", HEADER_TIMESTAMP))?,
+            nonce: value(HEADER_NONCE)?.to_string(),
+            signature: value(HEADER_SIGNATURE)?.to_string(),
+        })
+    }
+
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            (HEADER_TIMESTAMP, self.timestamp.to_string()),
+            (HEADER_NONCE, self.nonce.clone()),
+            (HEADER_SIGNATURE, self.signature.clone()),
+        ]
+    }
+
+    pub fn verify(
+        &self,
+        secret: &str,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(), String> {
+        let signature = hex::decode(&self.signature).map_err(|_| "invalid signature")?;
+        digest(secret, method, path, self.timestamp, &self.nonce, body)
+            .verify(&signature)
+            .map_err(|_| String::from("signature mismatch"))
+    }
+
+    /// 校验[request]请求的响应签名
+    pub fn verify_response(
+        &self,
+        secret: &str,
+        request: &Signature,
+        status: u16,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(), String> {
+        if self.nonce != request.nonce {
+            return Err(format!(
+                "response nonce {} mismatch request {}",
+                self.nonce, request.nonce
+            ));
+        }
+        self.verify(secret, &response_method(status), path, body)
+    }
+}
+
+/// 响应签名中代替请求方法的内容，包含状态码
+fn response_method(status: u16) -> String {
+    format!("response {}", status)
+}
+
+fn digest(
+    secret: &str,
+    method: &str,
+    path: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("hmac accept any key length");
+    mac.update(method.to_uppercase().as_bytes());
+    mac.update(b"\n");
+    mac.update(path.as_bytes());
+    mac.update(b"\n");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b"\n");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+    mac
+}
+
+/// 已经使用过的nonce，用于防止请求重放
+#[derive(Debug, Default)]
+pub struct Nonces {
+    seen: HashMap<String, i64>,
+}
+
+impl Nonces {
+    /// 检查签名的时间窗口和nonce，通过后记录该nonce
+    pub fn check(&mut self, signature: &Signature, now: i64) -> Result<(), String> {
+        if (now - signature.timestamp).abs() > MAX_SKEW_MILLIS {
+            return Err(format!(
+                "request timestamp {} out of window, now {}",
+                signature.timestamp, now
+            ));
+        }
+        self.seen
+            .retain(|_, timestamp| now - *timestamp <= MAX_SKEW_MILLIS);
+        if self.seen.contains_key(&signature.nonce) {
+            return Err(format!("replayed nonce {}", signature.nonce));
+        }
+        self.seen
+            .insert(signature.nonce.clone(), signature.timestamp);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::cluster::signature::{Nonces, Signature, MAX_SKEW_MILLIS};
+
+    #[test]
+    fn sign_and_verify() {
+        let body = br#"{"address":"127.0.0.1:7656"}"#;
+        let signature = Signature::sign("s3cret", "post", "/api/nodes", body);
+        assert!(signature
+            .verify("s3cret", "POST", "/api/nodes", body)
+            .is_ok());
+        assert!(signature
+            .verify("other", "POST", "/api/nodes", body)
+            .is_err());
+        assert!(signature
+            .verify("s3cret", "GET", "/api/nodes", body)
+            .is_err());
+        assert!(signature.verify("s3cret", "POST", "/api/g", body).is_err());
+        assert!(signature
+            .verify("s3cret", "POST", "/api/nodes", b"{}")
+            .is_err());
+
+        let mut forged = signature.clone();
+        forged.timestamp += 1;
+        assert!(forged.verify("s3cret", "POST", "/api/nodes", body).is_err());
+    }
+
+    #[test]
+    fn response() {
+        let request = Signature::sign("s3cret", "POST", "/api/nodes", b"{}");
+        let body = br#"{"current_id":1}"#;
+        let response = Signature::sign_response("s3cret", &request, 200, "/api/nodes", body);
+        assert_eq!(request.nonce, response.nonce);
+        assert!(response
+            .verify_response("s3cret", &request, 200, "/api/nodes", body)
+            .is_ok());
+        assert!(response
+            .verify_response("guess", &request, 200, "/api/nodes", body)
+            .is_err());
+        assert!(response
+            .verify_response("s3cret", &request, 409, "/api/nodes", body)
+            .is_err());
+        assert!(response
+            .verify_response("s3cret", &request, 200, "/api/nodes", b"{}")
+            .is_err());
+        // 其他请求的响应不能重放
+        let other = Signature::sign("s3cret", "POST", "/api/nodes", b"{}");
+        assert!(response
+            .verify_response("s3cret", &other, 200, "/api/nodes", body)
+            .is_err());
+        // 请求签名不能当作响应签名
+        assert!(request
+            .verify_response("s3cret", &request, 200, "/api/nodes", b"{}")
+            .is_err());
+    }
+
+    #[test]
+    fn headers() {
+        let signature = Signature::sign("s3cret", "POST", "/api/nodes", b"");
+        let headers = signature
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect::<HashMap<_, _>>();
+        let parsed = Signature::from_headers(|name| headers.get(name).map(|v| v.as_str()));
+        assert_eq!(Ok(signature), parsed);
+        assert!(Signature::from_headers(|_| None).is_err());
+    }
+
+    #[test]
+    fn replay() {
+        let mut nonces = Nonces::default();
+        let signature = Signature::sign("s3cret", "POST", "/api/nodes", b"");
+        let now = signature.timestamp;
+        assert!(nonces.check(&signature, now).is_ok());
+        assert!(nonces.check(&signature, now + 10).is_err());
+
+        let other = Signature::sign("s3cret", "POST", "/api/nodes", b"");
+        assert!(nonces
+            .check(&other, other.timestamp + MAX_SKEW_MILLIS + 1)
+            .is_err());
+        assert!(nonces.check(&other, other.timestamp).is_ok());
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Snapshot;
+
+const FILE_NAME: &str = "cluster.json";
+
+/// 持久化的集群状态，重启后用于使用原来的节点ID快速加入集群
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Persisted {
+    pub cluster: String,
+    /// 本机的节点ID
+    pub id: u16,
+    /// 本机对外公布的地址
+    pub address: SocketAddr,
+    pub snapshot: Snapshot,
+    pub saved_at: i64,
+}
+
+/// 集群状态文件，保存在[data_dir]目录下
+#[derive(Debug)]
+pub struct Store {
+    path: PathBuf,
+    /// 最后一次保存的 (id, term, version)，没有变化时不重复写入
+    saved: Mutex<Option<(u16, u64, u64)>>,
+}
+
+impl Store {
+    pub fn new<P: AsRef<Path>>(data_dir: P) -> Self {
+        Store {
+            path: data_dir.as_ref().join(FILE_NAME),
+            saved: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 读取保存的集群状态，文件不存在时返回[None]
+    pub fn load(&self) -> anyhow::Result<Option<Persisted>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read(&self.path)
+            .with_context(|| format!("read cluster state {}", self.path.display()))?;
+        let persisted = serde_json::from_slice::<Persisted>(&content)
+            .with_context(|| format!("parse cluster state {}", self.path.display()))?;
+        Ok(Some(persisted))
+    }
+
+    /// 先写入临时文件再重命名，避免进程退出时留下不完整的文件。返回是否写入了文件
+    pub fn save(&self, persisted: &Persisted) -> anyhow::Result<bool> {
+        let key = (
+            persisted.id,
+            persisted.snapshot.term,
+            persisted.snapshot.version,
+        );
+        let mut saved = self
+            .saved
+            .lock()
+            .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        if *saved == Some(key) {
+            return Ok(false);
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("create data dir {}", dir.display()))?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(persisted)?)
+            .with_context(|| format!("write cluster state {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("write cluster state {}", self.path.display()))?;
+        *saved = Some(key);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cluster::store::{Persisted, Store};
+    use crate::cluster::{Node, Nodes};
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("idgener-store-{}", rand::random::<u32>()));
+        let store = Store::new(&dir);
+        assert!(store.load().unwrap().is_none());
+
+        let address = "127.0.0.1:7656".parse().unwrap();
+        let mut nodes = Nodes::default();
+        nodes
+            .join(Node::new(2, address))
+            .set_current(2)
+            .set_leader(Some(2))
+            .set_term(3);
+        let persisted = Persisted {
+            cluster: String::from("idgener"),
+            id: 2,
+            address,
+            snapshot: nodes.snapshot(),
+            saved_at: 0,
+        };
+        assert!(store.save(&persisted).unwrap());
+        assert!(!store.save(&persisted).unwrap());
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(2, loaded.id);
+        assert_eq!(3, loaded.snapshot.term);
+        assert_eq!(Some(2), loaded.snapshot.leader);
+
+        std::fs::write(store.path(), b"{").unwrap();
+        assert!(store.load().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Secret;
+
+/// 接口的访问范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// 获取ID
+    Generate,
+    /// 解析ID
+    Decode,
+    /// 管理接口
+    Admin,
+    /// 节点间和数据中心间的接口
+    Cluster,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "generate" => Ok(Scope::Generate),
+            "decode" => Ok(Scope::Decode),
+            "admin" => Ok(Scope::Admin),
+            "cluster" => Ok(Scope::Cluster),
+            other => Err(format!("invalid scope {}", other)),
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Scope::Generate => "generate",
+            Scope::Decode => "decode",
+            Scope::Admin => "admin",
+            Scope::Cluster => "cluster",
+        })
+    }
+}
+
+/// 静态API key，请求携带 `Authorization: Bearer <key>` 或者 `X-Api-Key: <key>`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    /// 调用方名称，记录在审计日志中
+    pub id: String,
+    pub key: Secret,
+    pub scopes: Vec<Scope>,
+    /// 允许使用的ID序列，没有配置时允许所有序列
+    #[serde(default)]
+    pub datasets: Option<Vec<String>>,
+}
+
+/// 认证配置文件
+///
+/// ```yaml
+/// keys:
+///   - id: billing
+///     key: 0a1b2c3d
+///     scopes: [generate, decode]
+///     datasets: [default]
+/// token_secret: s3cret
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+    /// HMAC签名的bearer token的密钥，没有配置时不接受token
+    #[serde(default)]
+    pub token_secret: Option<Secret>,
+}
+
+impl AuthConfig {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("read auth config {}", path))?;
+        let config = serde_yaml::from_str::<AuthConfig>(&content)
+            .with_context(|| format!("parse auth config {}", path))?;
+        for (i, key) in config.keys.iter().enumerate() {
+            if key.key.expose().is_empty() {
+                bail!("api key {} has an empty key", key.id);
+            }
+            if config.keys[..i]
+                .iter()
+                .any(|other| other.key.expose() == key.key.expose() || other.id == key.id)
+            {
+                bail!("duplicate api key {}", key.id);
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::auth::{AuthConfig, Scope};
+
+    #[test]
+    fn load() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("idgener-auth-{}.yaml", rand::random::<u32>()));
+        std::fs::write(
+            &path,
+            "keys:\n  - id: billing\n    key: k1\n    scopes: [generate]\ntoken_secret: s\n",
+        )
+        .unwrap();
+        let config = AuthConfig::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(vec![Scope::Generate], config.keys[0].scopes);
+        assert_eq!(None, config.keys[0].datasets);
+
+        std::fs::write(
+            &path,
+            "keys:\n  - id: a\n    key: k1\n    scopes: [generate]\n  - id: b\n    key: k1\n    scopes: [admin]\n",
+        )
+        .unwrap();
+        assert!(AuthConfig::load(path.to_str().unwrap()).is_err());
+        std::fs::write(&path, "keys:\n  - id: a\n    key: k1\n    scopes: [root]\n").unwrap();
+        assert!(AuthConfig::load(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
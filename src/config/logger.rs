@@ -1,6 +1,270 @@
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::{bail, Context};
+use chrono::Local;
+use log::{LevelFilter, Record};
+use log4rs::append::console::ConsoleAppender;
+use log4rs::config::{Appender, Config, Deserializers, Logger, RawConfig, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::Encode;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Logging;
+
+const PATTERN: &str = "{h({l})} {d(%T)(local)} [{t}] {m}{n}";
+
+/// 日志输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Plain,
+    /// 每行一个JSON，带有节点ID、序列和请求ID
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            other => Err(format!(
+                "invalid log format {}, expect plain or json",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Plain => f.write_str("plain"),
+            Format::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// 本机的节点ID，还没有加入集群时为-1
+static NODE_ID: AtomicI32 = AtomicI32::new(-1);
+
+pub fn set_node_id(id: u16) {
+    NODE_ID.store(id as i32, Ordering::SeqCst);
+}
+
+/// 请求的上下文，请求处理过程中的JSON日志都带有这些字段
+pub struct RequestContext {
+    pub id: String,
+    dataset: RefCell<Option<String>>,
+}
+
+impl RequestContext {
+    pub fn new(id: String) -> Self {
+        RequestContext {
+            id,
+            dataset: RefCell::new(None),
+        }
+    }
+}
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+/// 在请求的上下文中执行[future]
+pub async fn scope<F: std::future::Future>(ctx: RequestContext, future: F) -> F::Output {
+    REQUEST.scope(ctx, future).await
+}
+
+/// 记录当前请求使用的ID序列
+pub fn set_dataset(dataset: &str) {
+    let _ = REQUEST.try_with(|ctx| ctx.dataset.replace(Some(dataset.to_string())));
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node_id: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dataset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+#[derive(Debug)]
+struct JsonEncoder;
+
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn log4rs::encode::Write, record: &Record) -> anyhow::Result<()> {
+        let node_id = NODE_ID.load(Ordering::SeqCst);
+        let (request_id, dataset) = REQUEST
+            .try_with(|ctx| (Some(ctx.id.clone()), ctx.dataset.borrow().clone()))
+            .unwrap_or((None, None));
+        let line = Line {
+            time: Local::now().to_rfc3339(),
+            level: record.level().as_str(),
+            target: record.target(),
+            message: record.args().to_string(),
+            node_id: if node_id < 0 {
+                None
+            } else {
+                Some(node_id as u16)
+            },
+            dataset,
+            request_id,
+        };
+        serde_json::to_writer(&mut *w, &line)?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// root级别和各个模块的级别
+type Levels = (Option<LevelFilter>, Vec<(String, LevelFilter)>);
+
+/// 解析日志级别，如 `info,idgener::cluster=debug`，没有模块名的为root级别
+fn parse_levels(spec: &str) -> anyhow::Result<Levels> {
+    let mut root = None;
+    let mut modules = vec![];
+    for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (module, level) = match item.split_once('=') {
+            Some((module, level)) => (Some(module.trim()), level.trim()),
+            None => (None, item),
+        };
+        let level = LevelFilter::from_str(level)
+            .map_err(|_| anyhow::anyhow!("invalid log level {} in {}", level, spec))?;
+        match module {
+            Some("") => bail!("empty module name in log level {}", spec),
+            Some(module) => modules.push((module.to_string(), level)),
+            None => root = Some(level),
+        }
+    }
+    Ok((root, modules))
+}
+
+/// 读取log4rs配置文件，配置有错误时返回错误而不是忽略
+fn load(path: &str) -> anyhow::Result<Config> {
+    let source =
+        std::fs::read_to_string(path).with_context(|| format!("read log config {}", path))?;
+    let raw = serde_yaml::from_str::<RawConfig>(&source)
+        .with_context(|| format!("parse log config {}", path))?;
+    let (appenders, errors) = raw.appenders_lossy(&Deserializers::default());
+    if !errors.is_empty() {
+        return Err(anyhow::Error::from(errors).context(format!("invalid log config {}", path)));
+    }
+    Config::builder()
+        .appenders(appenders)
+        .loggers(raw.loggers())
+        .build(raw.root())
+        .with_context(|| format!("invalid log config {}", path))
+}
+
+/// 根据命令行参数生成日志配置，[Logging::log_config]指定了配置文件时使用配置文件
+pub fn config(logging: &Logging, debug: bool) -> anyhow::Result<Config> {
+    if let Some(path) = &logging.log_config {
+        return load(path);
+    }
+    let encoder: Box<dyn Encode> = match logging.log_format.unwrap_or(Format::Plain) {
+        Format::Plain => Box::new(PatternEncoder::new(PATTERN)),
+        Format::Json => Box::new(JsonEncoder),
+    };
+    let (root, modules) = parse_levels(logging.log_level.as_deref().unwrap_or_default())?;
+    let default = if debug {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    let mut builder = Config::builder()
+        .appender(Appender::builder().build(
+            "stdout",
+            Box::new(ConsoleAppender::builder().encoder(encoder).build()),
+        ))
+        .logger(Logger::builder().build("actix_web::middleware::logger", LevelFilter::Warn));
+    for (module, level) in modules {
+        builder = builder.logger(Logger::builder().build(module, level));
+    }
+    builder
+        .build(
+            Root::builder()
+                .appender("stdout")
+                .build(root.unwrap_or(default)),
+        )
+        .context("invalid log config")
+}
+
+/// 初始化日志，配置错误时返回错误
+pub fn setup(logging: &Logging, debug: bool) -> anyhow::Result<()> {
+    log4rs::init_config(config(logging, debug)?).context("init logger")?;
+    Ok(())
+}
+
+/// 测试中使用，多次调用时忽略已经初始化的错误
 pub fn init(debug: bool) {
-    if let Ok(_) = log4rs::init_file("etc/log4rs.yaml", Default::default()) {}
-    if debug {
-        log::set_max_level(log::LevelFilter::Debug);
+    let _ = setup(&Logging::default(), debug);
+}
+
+#[cfg(test)]
+mod test {
+    use log::LevelFilter;
+
+    use crate::config::logger::{config, parse_levels, Format};
+    use crate::config::Logging;
+
+    #[test]
+    fn levels() {
+        let (root, modules) = parse_levels("warn, idgener::cluster=debug").unwrap();
+        assert_eq!(Some(LevelFilter::Warn), root);
+        assert_eq!(
+            vec![(String::from("idgener::cluster"), LevelFilter::Debug)],
+            modules
+        );
+        assert!(parse_levels("verbose").is_err());
+        assert!(parse_levels("=debug").is_err());
+    }
+
+    #[test]
+    fn invalid_config() {
+        let logging = Logging {
+            log_config: Some(String::from("etc/not-exists.yaml")),
+            ..Logging::default()
+        };
+        assert!(config(&logging, false).is_err());
+
+        let path = std::env::temp_dir().join(format!("idgener-log-{}.yaml", rand::random::<u32>()));
+        std::fs::write(
+            &path,
+            "appenders:\n  stdout:\n    kind: unknown\nroot:\n  level: info\n",
+        )
+        .unwrap();
+        let logging = Logging {
+            log_config: Some(path.display().to_string()),
+            ..Logging::default()
+        };
+        assert!(config(&logging, false).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        assert!(config(&Logging::default(), false).is_ok());
+        let logging = Logging {
+            log_config: Some(String::from("etc/log4rs.yaml")),
+            ..Logging::default()
+        };
+        assert!(config(&logging, false).is_ok());
+        assert!(config(
+            &Logging {
+                log_format: Some(Format::Json),
+                log_level: Some(String::from("info,actix_web=warn")),
+                ..Logging::default()
+            },
+            true
+        )
+        .is_ok());
     }
 }
@@ -1,4 +1,6 @@
+mod auth;
 pub mod logger;
 mod options;
 
-pub use options::{KeepAlive, Options};
+pub use auth::{ApiKey, AuthConfig, Scope};
+pub use options::{KeepAlive, Limits, Logging, Options, Secret, Tls};
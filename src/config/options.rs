@@ -1,8 +1,10 @@
 use std::ffi::OsString;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
 use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use log::info;
 use merge::Merge;
 use merge_yaml_hash::MergeYamlHash;
@@ -10,6 +12,10 @@ use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 use structopt_yaml::StructOptYaml;
 
+use crate::cluster::{local_ipaddress, Identity, Interface, DEFAULT_CLUSTER};
+use crate::config::logger::Format;
+use crate::trace::Exporter;
+
 pub fn overwrite<T>(left: &mut Option<T>, right: Option<T>) {
     if left.is_none() || right.is_some() {
         *left = right;
@@ -26,6 +32,178 @@ pub struct KeepAlive {
     #[structopt(long = "keep-alive-period-seconds", default_value = "3")]
     #[merge(strategy = merge::num::overwrite_zero)]
     pub period_seconds: u64,
+
+    /// timeout of one request to the other nodes, including reading the response [default: 700]
+    #[structopt(long = "keep-alive-timeout-millis")]
+    #[merge(strategy = overwrite)]
+    pub timeout_millis: Option<u64>,
+
+    /// stop issuing ids when the node can't confirm quorum in this window, 0 is disabled [default: 20]
+    #[structopt(long = "keep-alive-isolation-seconds")]
+    #[merge(strategy = overwrite)]
+    pub isolation_seconds: Option<u64>,
+
+    /// stop issuing ids when the clock offset to the leader exceeds this bound, 0 is disabled [default: 1000]
+    #[structopt(long = "keep-alive-max-clock-skew-millis")]
+    #[merge(strategy = overwrite)]
+    pub max_clock_skew_millis: Option<u64>,
+}
+
+impl KeepAlive {
+    /// 节点间请求的超时时间
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_millis.unwrap_or(700))
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.timeout_millis == Some(0) {
+            bail!("keep-alive timeout_millis must be positive");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Merge, Debug, Clone, Deserialize, Serialize, StructOpt, StructOptYaml)]
+#[structopt(name = "logging")]
+pub struct Logging {
+    /// log4rs config file, the other log options are ignored when it's set
+    #[structopt(long = "log-config", env = "IDGEND_LOG_CONFIG")]
+    #[merge(strategy = overwrite)]
+    pub log_config: Option<String>,
+
+    /// log levels, root and per module, e.g. `info,idgener::cluster=debug`
+    #[structopt(long = "log-level", env = "IDGEND_LOG_LEVEL")]
+    #[merge(strategy = overwrite)]
+    pub log_level: Option<String>,
+
+    /// log format, plain or json
+    #[structopt(long = "log-format", env = "IDGEND_LOG_FORMAT")]
+    #[merge(strategy = overwrite)]
+    pub log_format: Option<Format>,
+}
+
+impl Logging {
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Self {
+        Logging {
+            log_config: None,
+            log_level: None,
+            log_format: None,
+        }
+    }
+}
+
+#[derive(Merge, Debug, Clone, Deserialize, Serialize, StructOpt, StructOptYaml)]
+#[structopt(name = "tls")]
+pub struct Tls {
+    /// certificate chain (PEM) of the http listener, also presented to the other nodes,
+    /// enables https. Reloaded when the file changes
+    #[structopt(long = "tls-cert", env = "IDGEND_TLS_CERT")]
+    #[merge(strategy = overwrite)]
+    pub tls_cert: Option<String>,
+
+    /// private key (PEM) of the certificate
+    #[structopt(long = "tls-key", env = "IDGEND_TLS_KEY")]
+    #[merge(strategy = overwrite)]
+    pub tls_key: Option<String>,
+
+    /// CA certificates (PEM) trusted for the other nodes and for the client certificates,
+    /// default is the system roots
+    #[structopt(long = "tls-ca", env = "IDGEND_TLS_CA")]
+    #[merge(strategy = overwrite)]
+    pub tls_ca: Option<String>,
+
+    /// require client certificates signed by tls_ca (mutual TLS)
+    #[structopt(long = "tls-verify-client", env = "IDGEND_TLS_VERIFY_CLIENT")]
+    #[merge(strategy = merge::bool::overwrite_false)]
+    pub tls_verify_client: bool,
+}
+
+impl Tls {
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Self {
+        Tls {
+            tls_cert: None,
+            tls_key: None,
+            tls_ca: None,
+            tls_verify_client: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.tls_cert.is_some()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            bail!("tls_cert and tls_key must be set together");
+        }
+        if self.tls_verify_client && (!self.enabled() || self.tls_ca.is_none()) {
+            bail!("tls_verify_client requires tls_cert, tls_key and tls_ca");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Merge, Debug, Clone, Deserialize, Serialize, StructOpt, StructOptYaml)]
+#[structopt(name = "limits")]
+pub struct Limits {
+    /// ids per second for each client, the client is the api key or the ip
+    #[structopt(long = "rate-limit", env = "IDGEND_RATE_LIMIT")]
+    #[merge(strategy = overwrite)]
+    pub rate_limit: Option<f64>,
+
+    /// burst size of the per-client rate limit, default is one second of ids
+    #[structopt(long = "rate-burst", env = "IDGEND_RATE_BURST")]
+    #[merge(strategy = overwrite)]
+    pub rate_burst: Option<u32>,
+
+    /// ids per second for each dataset, shared by all clients
+    #[structopt(long = "dataset-rate-limit", env = "IDGEND_DATASET_RATE_LIMIT")]
+    #[merge(strategy = overwrite)]
+    pub dataset_rate_limit: Option<f64>,
+
+    /// burst size of the per-dataset rate limit, default is one second of ids
+    #[structopt(long = "dataset-rate-burst", env = "IDGEND_DATASET_RATE_BURST")]
+    #[merge(strategy = overwrite)]
+    pub dataset_rate_burst: Option<u32>,
+
+    /// ids for each client per day (UTC)
+    #[structopt(long = "daily-quota", env = "IDGEND_DAILY_QUOTA")]
+    #[merge(strategy = overwrite)]
+    pub daily_quota: Option<u64>,
+}
+
+impl Limits {
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Self {
+        Limits {
+            rate_limit: None,
+            rate_burst: None,
+            dataset_rate_limit: None,
+            dataset_rate_burst: None,
+            daily_quota: None,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.rate_limit.is_some() || self.dataset_rate_limit.is_some() || self.daily_quota.is_some()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for rate in [self.rate_limit, self.dataset_rate_limit].iter().flatten() {
+            if !rate.is_finite() || *rate <= 0.0 {
+                bail!("invalid rate limit {}, expect a positive number", rate);
+            }
+        }
+        if self.rate_burst == Some(0) {
+            bail!("rate_burst must be positive");
+        }
+        if self.dataset_rate_burst == Some(0) {
+            bail!("dataset_rate_burst must be positive");
+        }
+        Ok(())
+    }
 }
 
 /// 分布式ID生成器。
@@ -47,11 +225,27 @@ pub struct Options {
     #[structopt(env = "IDGEND_DATA_DIR", short = "D", long)]
     pub data_dir: Option<String>,
 
-    /// http address
+    /// http bind address
     #[merge(strategy = overwrite)]
     #[structopt(env = "IDGEND_HTTP_ADDRESS", short = "H", long, parse(try_from_str))]
     pub http_address: Option<SocketAddr>,
 
+    /// ip or host name advertised to other nodes (behind NAT, docker or a load balancer),
+    /// default is the bind ip or the detected local ip
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_ADVERTISE_HOST", long)]
+    pub advertise_host: Option<String>,
+
+    /// port advertised to other nodes, default is the bind port
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_ADVERTISE_PORT", long)]
+    pub advertise_port: Option<u16>,
+
+    /// detect the advertised ip from the interface, by name (eth0) or network (10.0.0.0/8, fd00::/8)
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_ADVERTISE_INTERFACE", long)]
+    pub advertise_interface: Option<Interface>,
+
     /// 使用指定的ID创建集群，ID集群判断的优先级高于组播发现机制
     #[merge(strategy = overwrite)]
     #[structopt(env = "IDGEND_ID", short = "i", long, parse(try_from_str))]
@@ -67,13 +261,136 @@ pub struct Options {
     )]
     pub multicast_address: Option<SocketAddr>,
 
+    /// cluster name, only nodes with the same name join together
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_CLUSTER_NAME", short = "N", long)]
+    pub cluster_name: Option<String>,
+
+    /// cluster shared secret
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_CLUSTER_SECRET", long, hide_env_values = true)]
+    pub cluster_secret: Option<Secret>,
+
+    /// bearer token of the admin api, the admin api is disabled without it
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_ADMIN_TOKEN", long, hide_env_values = true)]
+    pub admin_token: Option<Secret>,
+
+    /// datacenter id, unique among federated datacenters, enables the datacenter bits of the ids
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_DATACENTER_ID", long, parse(try_from_str))]
+    pub datacenter_id: Option<u8>,
+
+    /// datacenter name, unique among federated datacenters, default is the cluster name
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_DATACENTER_NAME", long)]
+    pub datacenter_name: Option<String>,
+
+    /// node addresses of the other federated datacenters
+    #[merge(strategy = overwrite)]
+    #[structopt(
+        env = "IDGEND_FEDERATION_PEERS",
+        long,
+        use_delimiter = true,
+        parse(try_from_str)
+    )]
+    pub federation_peers: Option<Vec<SocketAddr>>,
+
+    /// shared secret of the federated datacenters, signs the requests between datacenters
+    /// instead of the cluster secret
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_FEDERATION_SECRET", long, hide_env_values = true)]
+    pub federation_secret: Option<Secret>,
+
+    /// yaml file of api keys and the token secret, the generation and cluster apis require
+    /// authentication when it's set
+    #[merge(strategy = overwrite)]
+    #[structopt(long, env = "IDGEND_AUTH_CONFIG")]
+    pub auth_config: Option<String>,
+
+    /// record issued id ranges and callers to an append-only audit log under data_dir.
+    /// ranges are buffered for up to one second and fsynced every second, so a crash may lose
+    /// the records of about the last two seconds
+    #[structopt(long, env = "IDGEND_AUDIT_LOG")]
+    #[merge(strategy = merge::bool::overwrite_false)]
+    pub audit_log: bool,
+
+    /// rotate the audit log when it exceeds this size
+    #[merge(strategy = overwrite)]
+    #[structopt(long, env = "IDGEND_AUDIT_ROTATE_BYTES")]
+    pub audit_rotate_bytes: Option<u64>,
+
+    /// number of rotated audit logs to keep
+    #[merge(strategy = overwrite)]
+    #[structopt(long, env = "IDGEND_AUDIT_MAX_FILES")]
+    pub audit_max_files: Option<usize>,
+
+    /// listen address of the redis protocol (RESP) front-end, disabled when not set.
+    /// RESP is plain TCP and AUTH sends the api key in cleartext, so it can not be used with tls
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_RESP_ADDRESS", long, parse(try_from_str))]
+    pub resp_address: Option<SocketAddr>,
+
+    /// export tracing spans, `stdout` prints one json per line, or the OTLP/HTTP collector url
+    #[merge(strategy = overwrite)]
+    #[structopt(env = "IDGEND_TRACE_EXPORTER", long)]
+    pub trace_exporter: Option<Exporter>,
+
+    #[structopt(flatten)]
+    pub logging: Logging,
+
+    #[structopt(flatten)]
+    pub tls: Tls,
+
+    #[structopt(flatten)]
+    pub limits: Limits,
+
     #[structopt(flatten)]
     pub keep_alive: KeepAlive,
 }
 
+/// 敏感配置，输出日志时不显示具体内容
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("******")
+    }
+}
+
 const PORT: u16 = 7656;
 
+/// 解析IP或者主机名，IPv6地址可以带方括号
+fn resolve_host(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    let trimmed = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = trimmed.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("resolve advertise host {}", host))?
+        .next()
+        .with_context(|| format!("advertise host {} has no address", host))
+}
+
 impl Options {
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Self {
         Options {
             config: None,
@@ -83,6 +400,9 @@ impl Options {
                 Ipv4Addr::UNSPECIFIED,
                 PORT,
             ))),
+            advertise_host: None,
+            advertise_port: None,
+            advertise_interface: None,
             id: None,
             /*
             multicast_address: Some(SocketAddr::V4(SocketAddrV4::new(
@@ -91,13 +411,78 @@ impl Options {
             ))),
             */
             multicast_address: None,
+            cluster_name: Some(String::from(DEFAULT_CLUSTER)),
+            cluster_secret: None,
+            admin_token: None,
+            datacenter_id: None,
+            datacenter_name: None,
+            federation_peers: None,
+            federation_secret: None,
+            auth_config: None,
+            audit_log: false,
+            audit_rotate_bytes: Some(64 * 1024 * 1024),
+            audit_max_files: Some(10),
+            resp_address: None,
+            trace_exporter: None,
+            logging: Logging::default(),
+            tls: Tls::default(),
+            limits: Limits::default(),
             keep_alive: KeepAlive {
                 period_seconds: 3,
                 failure_threshold: 3,
+                timeout_millis: Some(700),
+                isolation_seconds: Some(20),
+                max_clock_skew_millis: Some(1000),
             },
         }
     }
 
+    /// 集群身份
+    pub fn identity(&self) -> Identity {
+        Identity::new(
+            self.cluster_name.as_deref().unwrap_or(DEFAULT_CLUSTER),
+            self.cluster_secret.as_ref().map(|s| s.expose()),
+        )
+    }
+
+    /// 本机监听的地址
+    pub fn bind_address(&self) -> anyhow::Result<SocketAddr> {
+        self.http_address.context("can't found http address")
+    }
+
+    /// 本机对外公布的地址，端口默认为监听端口。
+    /// 主机依次使用[advertise_host]、指定的监听IP、[advertise_interface]匹配的网卡IP和自动检测的IP
+    pub fn advertise_address(&self) -> anyhow::Result<SocketAddr> {
+        let bind = self.bind_address()?;
+        let port = self.advertise_port.unwrap_or_else(|| bind.port());
+        if let Some(host) = &self.advertise_host {
+            return resolve_host(host, port);
+        }
+        let ip = match self.advertise_interface {
+            None if !bind.ip().is_unspecified() => bind.ip(),
+            _ => local_ipaddress(self.advertise_interface.as_ref()).with_context(|| match &self
+                .advertise_interface
+            {
+                Some(interface) => format!("not found ip on interface {}", interface),
+                None => {
+                    String::from("not found local ip, set advertise_host or advertise_interface")
+                }
+            })?,
+        };
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    /// 对外公布的主机名，[advertise_host]是IP时返回[None]。其他节点连接时使用主机名重新解析，
+    /// 主机名对应的IP变化后（例如容器重建）仍然可以连接
+    pub fn advertise_host_name(&self) -> Option<String> {
+        let host = self.advertise_host.as_ref()?;
+        let trimmed = host.trim_start_matches('[').trim_end_matches(']');
+        match trimmed.parse::<IpAddr>() {
+            Ok(_) => None,
+            Err(_) => Some(host.clone()),
+        }
+    }
+
     pub fn parse() -> anyhow::Result<Options> {
         let args = std::env::args().collect::<Vec<_>>();
         Options::parse_custom_args(args)
@@ -139,3 +524,118 @@ impl Options {
         Ok(default_config)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::{KeepAlive, Options};
+
+    fn keep_alive(args: &[&str], yaml: Option<&str>) -> KeepAlive {
+        let mut args = args.to_vec();
+        args.insert(0, "idgend");
+        let path = std::env::temp_dir().join(format!("idgend-{}.yaml", std::process::id()));
+        if let Some(yaml) = yaml {
+            std::fs::write(&path, format!("keep_alive:\n{}", yaml)).unwrap();
+            args.extend_from_slice(&["--config", path.to_str().unwrap()]);
+        }
+        let options = Options::parse_custom_args(args);
+        if yaml.is_some() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        options.unwrap().keep_alive
+    }
+
+    /// 命令行和配置文件中的值都可以覆盖默认值，包括表示禁用的0
+    #[test]
+    fn keep_alive_options() {
+        let options = keep_alive(&[], None);
+        assert_eq!(Some(20), options.isolation_seconds);
+        assert_eq!(Some(1000), options.max_clock_skew_millis);
+        assert_eq!(Some(700), options.timeout_millis);
+        assert!(options.validate().is_ok());
+
+        let options = keep_alive(
+            &[
+                "--keep-alive-isolation-seconds",
+                "0",
+                "--keep-alive-max-clock-skew-millis",
+                "0",
+                "--keep-alive-timeout-millis",
+                "0",
+            ],
+            None,
+        );
+        assert_eq!(Some(0), options.isolation_seconds);
+        assert_eq!(Some(0), options.max_clock_skew_millis);
+        assert_eq!(Some(0), options.timeout_millis);
+        assert!(options.validate().is_err());
+
+        let options = keep_alive(
+            &[
+                "--keep-alive-isolation-seconds",
+                "45",
+                "--keep-alive-max-clock-skew-millis",
+                "250",
+                "--keep-alive-timeout-millis",
+                "1500",
+            ],
+            None,
+        );
+        assert_eq!(Some(45), options.isolation_seconds);
+        assert_eq!(Some(250), options.max_clock_skew_millis);
+        assert_eq!(Some(1500), options.timeout_millis);
+
+        let options = keep_alive(
+            &[],
+            Some("  isolation_seconds: 0\n  max_clock_skew_millis: 0\n  timeout_millis: 0\n"),
+        );
+        assert_eq!(Some(0), options.isolation_seconds);
+        assert_eq!(Some(0), options.max_clock_skew_millis);
+        assert_eq!(Some(0), options.timeout_millis);
+
+        let options = keep_alive(
+            &[],
+            Some("  isolation_seconds: 45\n  max_clock_skew_millis: 250\n  timeout_millis: 1500\n"),
+        );
+        assert_eq!(Some(45), options.isolation_seconds);
+        assert_eq!(Some(250), options.max_clock_skew_millis);
+        assert_eq!(Some(1500), options.timeout_millis);
+        assert_eq!(1500, options.timeout().as_millis());
+    }
+
+    #[test]
+    fn advertise_address() {
+        let mut options = Options {
+            http_address: Some("127.0.0.1:7656".parse().unwrap()),
+            ..Options::default()
+        };
+        assert_eq!(
+            "127.0.0.1:7656".parse(),
+            Ok(options.advertise_address().unwrap())
+        );
+
+        options.advertise_port = Some(17656);
+        assert_eq!(
+            "127.0.0.1:17656".parse(),
+            Ok(options.advertise_address().unwrap())
+        );
+
+        options.advertise_host = Some(String::from("[2001:db8::1]"));
+        assert_eq!(
+            "[2001:db8::1]:17656".parse(),
+            Ok(options.advertise_address().unwrap())
+        );
+
+        assert_eq!(None, options.advertise_host_name());
+
+        options.advertise_host = Some(String::from("localhost"));
+        assert!(options.advertise_address().unwrap().ip().is_loopback());
+        assert_eq!(
+            Some(String::from("localhost")),
+            options.advertise_host_name()
+        );
+
+        options.advertise_host = None;
+        options.advertise_interface = Some("eth-not-exists".parse().unwrap());
+        assert!(options.advertise_address().is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+const FILE_NAME: &str = "audit.log";
+
+/// 同一个worker连续发放给同一个调用方的ID合并为一个区间，区间最长的时间窗口
+const WINDOW_MILLIS: i64 = 1000;
+
+/// 同时合并中的区间数量，超过后先写入最早开始的区间
+const MAX_OPEN: usize = 1024;
+
+/// 一段连续发放给同一个调用方的ID，区间内的ID都发放给了该调用方
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Range {
+    pub dataset: String,
+    /// Snowflake 的 worker id，开启数据中心时包含数据中心ID
+    pub worker: u16,
+    pub first: u64,
+    pub last: u64,
+    pub count: u64,
+    /// 第一个和最后一个ID的发放时间
+    pub from: i64,
+    pub to: i64,
+    pub caller: String,
+}
+
+impl Range {
+    pub fn contains(&self, id: u64) -> bool {
+        self.first <= id && id <= self.last
+    }
+}
+
+/// 合并中区间的键：数据集和 worker
+type Key = (String, u16);
+
+struct Inner {
+    /// 每个worker正在合并的区间，其他调用方获取ID时结束当前区间，每个ID只属于一个区间
+    open: HashMap<Key, Range>,
+    file: Option<File>,
+    size: u64,
+    /// 有写入但还没有[File::sync_data]
+    dirty: bool,
+}
+
+/// 只追加的ID发放审计日志，保存在[data_dir]/audit 目录下。
+///
+/// 文件超过[rotate_bytes]后重命名为 `audit-{时间}.log`，最多保留[max_files]个历史文件。
+///
+/// 持久化窗口：区间在内存中合并最多[WINDOW_MILLIS]，之后由[AuditLog::flush]写入并 `fsync`，
+/// 按每秒刷新计算，进程崩溃或者掉电时最多丢失最近约2秒发放的ID记录。
+pub struct AuditLog {
+    dir: PathBuf,
+    rotate_bytes: u64,
+    max_files: usize,
+    inner: Mutex<Inner>,
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(data_dir: P, rotate_bytes: u64, max_files: usize) -> Self {
+        AuditLog {
+            dir: data_dir.as_ref().join("audit"),
+            rotate_bytes,
+            max_files,
+            inner: Mutex::new(Inner {
+                open: HashMap::new(),
+                file: None,
+                size: 0,
+                dirty: false,
+            }),
+        }
+    }
+
+    fn lock(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Inner>> {
+        self.inner
+            .lock()
+            .map_err(|err| anyhow::anyhow!(err.to_string()))
+    }
+
+    /// 记录发放的ID，需要按照发放的顺序调用。
+    /// 和该worker上一个区间的调用方相同并且在时间窗口内时合并，否则先写入上一个区间
+    pub fn record(
+        &self,
+        dataset: &str,
+        worker: u16,
+        id: u64,
+        caller: &str,
+        now: i64,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.lock()?;
+        let key = (dataset.to_string(), worker);
+        if let Some(range) = inner.open.get_mut(&key) {
+            if range.caller == caller && range.last < id && now - range.from < WINDOW_MILLIS {
+                range.last = id;
+                range.count += 1;
+                range.to = now;
+                return Ok(());
+            }
+        }
+        if let Some(range) = inner.open.remove(&key) {
+            self.append(&mut inner, &range)?;
+        } else if inner.open.len() >= MAX_OPEN {
+            let oldest = inner
+                .open
+                .iter()
+                .min_by_key(|(_, range)| range.from)
+                .map(|(key, _)| key.clone());
+            if let Some(range) = oldest.and_then(|key| inner.open.remove(&key)) {
+                self.append(&mut inner, &range)?;
+            }
+        }
+        inner.open.insert(
+            key,
+            Range {
+                dataset: dataset.to_string(),
+                worker,
+                first: id,
+                last: id,
+                count: 1,
+                from: now,
+                to: now,
+                caller: caller.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// 写入超过时间窗口的区间并同步到磁盘，[now]为[None]时写入所有区间
+    pub fn flush(&self, now: Option<i64>) -> anyhow::Result<()> {
+        let mut inner = self.lock()?;
+        let mut expired = inner
+            .open
+            .iter()
+            .filter(|(_, range)| match now {
+                Some(now) => now - range.from >= WINDOW_MILLIS,
+                None => true,
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        expired.sort_by_key(|key| inner.open[key].from);
+        for key in expired {
+            if let Some(range) = inner.open.remove(&key) {
+                self.append(&mut inner, &range)?;
+            }
+        }
+        if inner.dirty {
+            if let Some(file) = inner.file.as_mut() {
+                file.flush()?;
+                file.sync_data().context("sync audit log")?;
+            }
+            inner.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn append(&self, inner: &mut Inner, range: &Range) -> anyhow::Result<()> {
+        if inner.file.is_some() && inner.size >= self.rotate_bytes {
+            if let Some(file) = inner.file.take() {
+                file.sync_data().context("sync audit log")?;
+            }
+            self.rotate(range.from)?;
+        }
+        if inner.file.is_none() {
+            fs::create_dir_all(&self.dir)
+                .with_context(|| format!("create audit dir {}", self.dir.display()))?;
+            let path = self.dir.join(FILE_NAME);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("open audit log {}", path.display()))?;
+            inner.size = file.metadata()?.len();
+            inner.file = Some(file);
+        }
+        let mut line = serde_json::to_vec(range)?;
+        line.push(b'\n');
+        inner
+            .file
+            .as_mut()
+            .unwrap()
+            .write_all(&line)
+            .context("write audit log")?;
+        inner.size += line.len() as u64;
+        inner.dirty = true;
+        Ok(())
+    }
+
+    fn rotate(&self, now: i64) -> anyhow::Result<()> {
+        let path = self.dir.join(FILE_NAME);
+        let mut millis = now;
+        let mut rotated = self.dir.join(format!("audit-{}.log", millis));
+        while rotated.exists() {
+            millis += 1;
+            rotated = self.dir.join(format!("audit-{}.log", millis));
+        }
+        fs::rename(&path, &rotated)
+            .with_context(|| format!("rotate audit log {}", rotated.display()))?;
+        let files = self.rotated()?;
+        if files.len() > self.max_files {
+            for file in &files[..files.len() - self.max_files] {
+                fs::remove_file(file)
+                    .with_context(|| format!("remove audit log {}", file.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 历史文件，按时间从旧到新排序
+    fn rotated(&self) -> anyhow::Result<Vec<PathBuf>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut files = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter_map(|path| {
+                let millis = path
+                    .file_name()?
+                    .to_str()?
+                    .strip_prefix("audit-")?
+                    .strip_suffix(".log")?
+                    .parse::<i64>()
+                    .ok()?;
+                Some((millis, path))
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        Ok(files.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// 查找包含ID的区间，先查找还没有写入的区间，再从新到旧查找文件。查找文件时不持有锁，避免阻塞发放ID
+    pub fn lookup(&self, id: u64) -> anyhow::Result<Vec<Range>> {
+        let mut found = self
+            .lock()?
+            .open
+            .values()
+            .filter(|range| range.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut files = self.rotated()?;
+        files.push(self.dir.join(FILE_NAME));
+        for path in files.iter().rev() {
+            let file = match File::open(path) {
+                Ok(file) => file,
+                // 查找过程中文件被轮转或者删除
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("open audit log {}", path.display()))
+                }
+            };
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                // 进程退出时可能留下不完整的最后一行
+                if let Ok(range) = serde_json::from_str::<Range>(&line) {
+                    if range.contains(id) {
+                        found.push(range);
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::generator::audit::AuditLog;
+
+    #[test]
+    fn record_and_lookup() {
+        let dir = std::env::temp_dir().join(format!("idgener-audit-{}", rand::random::<u32>()));
+        let audit = AuditLog::new(&dir, 100, 2);
+        audit.record("default", 1, 10, "10.0.0.1", 0).unwrap();
+        audit.record("default", 1, 11, "10.0.0.1", 1).unwrap();
+        audit.record("default", 1, 12, "10.0.0.2", 2).unwrap();
+        audit.record("default", 1, 13, "10.0.0.2", 2000).unwrap();
+
+        let range = &audit.lookup(11).unwrap()[0];
+        assert_eq!((10, 11, 2), (range.first, range.last, range.count));
+        assert_eq!("10.0.0.1", range.caller);
+        assert_eq!("10.0.0.2", audit.lookup(12).unwrap()[0].caller);
+        assert_eq!(13, audit.lookup(13).unwrap()[0].first);
+        assert!(audit.lookup(9).unwrap().is_empty());
+
+        // 每个区间约150字节，超过100字节后轮转，最多保留2个历史文件
+        for i in 0..5 {
+            let caller = format!("10.0.1.{}", i);
+            audit
+                .record("default", 1, 100 + i, &caller, 3000 + i as i64)
+                .unwrap();
+        }
+        audit.flush(None).unwrap();
+        assert_eq!(2, audit.rotated().unwrap().len());
+        assert_eq!("10.0.1.4", audit.lookup(104).unwrap()[0].caller);
+        assert!(audit.lookup(10).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interleaved_callers() {
+        let dir = std::env::temp_dir().join(format!("idgener-audit-{}", rand::random::<u32>()));
+        let audit = AuditLog::new(&dir, 1024 * 1024, 2);
+        for id in 0..10 {
+            let caller = format!("10.0.0.{}", id / 3 % 2);
+            audit.record("default", 1, id, &caller, id as i64).unwrap();
+        }
+        // 调用方切换时结束区间，区间不重叠
+        let path = dir.join("audit").join("audit.log");
+        audit.flush(Some(500)).unwrap();
+        assert_eq!(3, std::fs::read_to_string(&path).unwrap().lines().count());
+        audit.flush(None).unwrap();
+        assert_eq!(4, std::fs::read_to_string(&path).unwrap().lines().count());
+        for id in 0..10 {
+            let ranges = audit.lookup(id).unwrap();
+            assert_eq!(1, ranges.len());
+            assert_eq!(format!("10.0.0.{}", id / 3 % 2), ranges[0].caller);
+        }
+        let range = &audit.lookup(7).unwrap()[0];
+        assert_eq!((6, 8, 3), (range.first, range.last, range.count));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,7 +1,9 @@
+mod audit;
 mod snowflake;
 
 use actix_web::Responder;
-pub use snowflake::Snowflake;
+pub use audit::{AuditLog, Range};
+pub use snowflake::{Decoded, SnowFlakeId, Snowflake};
 
 pub trait Idgend<T>
 where
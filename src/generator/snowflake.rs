@@ -1,17 +1,19 @@
+use crate::cluster::{DATACENTER_BITS, MAX_DATACENTER_ID};
 use crate::generator::Idgend;
-use actix_http::Response;
-use actix_web::{HttpRequest, HttpResponse, Responder};
+use crate::metrics;
 use anyhow::bail;
 use chrono::Local;
 use num_traits::cast::ToPrimitive;
 use rand::random;
-use std::future::{ready, Ready};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 // temp var for test, 2018-01-01 00:00:00
-pub const STANDARD_EPOCH: u64 = 1514736000_000u64;
+pub const STANDARD_EPOCH: u64 = 1_514_736_000_000u64;
 
 // shift
 const WORKER_ID_SHIFT: u8 = 12;
+const WORKER_ID_BITS: u8 = 10;
 const TIMESTAMP_LEFT_SHIFT: u8 = 22;
 
 // mask
@@ -27,6 +29,37 @@ pub struct Snowflake {
 #[derive(Debug)]
 pub struct SnowFlakeId(u64);
 
+/// 解析后的ID
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Decoded {
+    pub id: u64,
+    /// 生成时间的毫秒数
+    pub timestamp: u64,
+    pub worker_id: u16,
+    pub sequence: u16,
+}
+
+impl From<u64> for SnowFlakeId {
+    fn from(id: u64) -> Self {
+        SnowFlakeId(id)
+    }
+}
+
+impl SnowFlakeId {
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub fn decode(&self) -> Decoded {
+        Decoded {
+            id: self.0,
+            timestamp: self.snowflake_timestamp(),
+            worker_id: self.worker_id(),
+            sequence: self.sequence(),
+        }
+    }
+}
+
 impl SnowFlakeId {
     fn snowflake_timestamp(&self) -> u64 {
         (self.0 >> TIMESTAMP_LEFT_SHIFT) + STANDARD_EPOCH
@@ -39,16 +72,6 @@ impl SnowFlakeId {
     }
 }
 
-impl Responder for SnowFlakeId {
-    type Error = actix_web::Error;
-    type Future = Ready<Result<Response, actix_web::Error>>;
-    fn respond_to(self, _: &HttpRequest) -> Self::Future {
-        ready(Ok(HttpResponse::Ok()
-            .content_type("text/plain; charset=utf-8")
-            .body(format!("{}", self.0))))
-    }
-}
-
 impl Snowflake {
     pub fn new(worker_id: u16) -> Self {
         Snowflake {
@@ -58,6 +81,32 @@ impl Snowflake {
         }
     }
 
+    /// 开启多数据中心时使用，worker id 的高位为数据中心ID，低位为节点ID
+    pub fn with_datacenter(datacenter_id: u8, worker_id: u16) -> anyhow::Result<Self> {
+        let worker_bits = WORKER_ID_BITS - DATACENTER_BITS;
+        if datacenter_id > MAX_DATACENTER_ID {
+            bail!(
+                "datacenter id {} exceed {}",
+                datacenter_id,
+                MAX_DATACENTER_ID
+            );
+        }
+        if worker_id >= 1 << worker_bits {
+            bail!(
+                "node id {} exceed {} in datacenter mode",
+                worker_id,
+                (1 << worker_bits) - 1
+            );
+        }
+        Ok(Snowflake::new(
+            (datacenter_id as u16) << worker_bits | worker_id,
+        ))
+    }
+
+    pub fn worker_id(&self) -> u16 {
+        self.worker_id
+    }
+
     fn wait_for_next_milli_sec(&self) -> u64 {
         let mut curr_timestamp = Snowflake::current_timestamp_millis();
         while self.last_timestamp >= curr_timestamp {
@@ -66,6 +115,20 @@ impl Snowflake {
         curr_timestamp
     }
 
+    /// 最后一次生成的ID，还没有生成过ID时返回[None]
+    pub fn high_water_mark(&self) -> Option<u64> {
+        if self.last_timestamp == 0 {
+            return None;
+        }
+        Some(self.compose(self.last_timestamp))
+    }
+
+    fn compose(&self, timestamp: u64) -> u64 {
+        (timestamp - STANDARD_EPOCH) << TIMESTAMP_LEFT_SHIFT
+            | (self.worker_id as u64) << WORKER_ID_SHIFT
+            | (self.sequence as u64)
+    }
+
     fn current_timestamp_millis() -> u64 {
         Local::now().timestamp_millis().to_u64().unwrap()
     }
@@ -75,6 +138,7 @@ impl Idgend<SnowFlakeId> for Snowflake {
     fn get(&mut self, jump: bool) -> anyhow::Result<SnowFlakeId> {
         let mut current_timestamp = Snowflake::current_timestamp_millis();
         if current_timestamp < self.last_timestamp {
+            metrics::CLOCK_ROLLBACK.inc();
             bail!(format!(
                 "Clock moved backwards. Refusing to generate id for {} milliseconds",
                 self.last_timestamp
@@ -89,6 +153,7 @@ impl Idgend<SnowFlakeId> for Snowflake {
 
         if current_timestamp == self.last_timestamp {
             if self.sequence == 0 {
+                metrics::SEQUENCE_EXHAUSTED.inc();
                 current_timestamp = self.wait_for_next_milli_sec();
             }
         } else {
@@ -97,11 +162,7 @@ impl Idgend<SnowFlakeId> for Snowflake {
 
         self.last_timestamp = current_timestamp;
 
-        Ok(SnowFlakeId(
-            (current_timestamp - STANDARD_EPOCH) << TIMESTAMP_LEFT_SHIFT
-                | (self.worker_id as u64) << WORKER_ID_SHIFT
-                | (self.sequence as u64),
-        ))
+        Ok(SnowFlakeId(self.compose(current_timestamp)))
     }
 }
 
@@ -135,6 +196,23 @@ mod test {
         assert_eq!(if time1 == time2 { 1 } else { 0 }, seq);
     }
 
+    #[test]
+    fn high_water_mark() {
+        let mut id_gen = Snowflake::new(3);
+        assert_eq!(None, id_gen.high_water_mark());
+        let id = id_gen.get(false).unwrap();
+        assert_eq!(Some(id.0), id_gen.high_water_mark());
+    }
+
+    #[test]
+    fn datacenter() {
+        let mut id_gen = Snowflake::with_datacenter(3, 5).unwrap();
+        let id = id_gen.get(false).unwrap();
+        assert_eq!(3 << 5 | 5, id.worker_id());
+        assert!(Snowflake::with_datacenter(32, 0).is_err());
+        assert!(Snowflake::with_datacenter(0, 32).is_err());
+    }
+
     #[test]
     fn loop_test() {
         let mut id_gen = Snowflake::new(0);
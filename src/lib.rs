@@ -1,6 +1,8 @@
 mod cluster;
 pub mod config;
 mod generator;
+mod metrics;
 mod server;
+mod trace;
 
 pub use server::embedded;
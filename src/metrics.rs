@@ -0,0 +1,91 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    /// 发放的ID数量
+    pub static ref IDS_ISSUED: IntCounterVec = register_int_counter_vec!(
+        "idgener_ids_issued_total",
+        "Number of ids issued",
+        &["dataset", "mode"]
+    )
+    .unwrap();
+    /// 同一毫秒内序列号用完，等待下一毫秒的次数
+    pub static ref SEQUENCE_EXHAUSTED: IntCounter = register_int_counter!(
+        "idgener_sequence_exhausted_total",
+        "Number of waits for the next millisecond after the sequence is exhausted"
+    )
+    .unwrap();
+    /// 检测到时钟回拨，拒绝发放ID的次数
+    pub static ref CLOCK_ROLLBACK: IntCounter = register_int_counter!(
+        "idgener_clock_rollback_total",
+        "Number of ids refused because the clock moved backwards"
+    )
+    .unwrap();
+    /// keep-alive 结果，result 为 success 或 failure
+    pub static ref KEEP_ALIVE: IntCounterVec = register_int_counter_vec!(
+        "idgener_keep_alive_total",
+        "Number of keep-alive heartbeats by result",
+        &["result"]
+    )
+    .unwrap();
+    /// 超过限流或者配额被拒绝的请求，reason 为 client、dataset 或 quota
+    pub static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "idgener_rate_limited_total",
+        "Number of requests rejected by rate limits or quotas",
+        &["dataset", "reason"]
+    )
+    .unwrap();
+    /// 本机观察到的leader变化次数
+    pub static ref LEADER_CHANGES: IntCounter = register_int_counter!(
+        "idgener_leader_changes_total",
+        "Number of leader changes observed by this node"
+    )
+    .unwrap();
+    /// 集群成员数量
+    pub static ref MEMBERS: IntGauge = register_int_gauge!(
+        "idgener_cluster_members",
+        "Number of cluster members known by this node"
+    )
+    .unwrap();
+    /// leader 时钟减去本机时钟的毫秒数
+    pub static ref CLOCK_OFFSET: IntGauge = register_int_gauge!(
+        "idgener_clock_offset_milliseconds",
+        "Clock offset from the leader in milliseconds"
+    )
+    .unwrap();
+    /// HTTP 请求耗时，path 为路由模板，避免ID等参数导致标签过多
+    pub static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "idgener_http_request_duration_seconds",
+        "HTTP request latency in seconds",
+        &["method", "path", "status"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap();
+}
+
+/// 以 Prometheus 文本格式输出所有指标
+pub fn gather() -> anyhow::Result<Vec<u8>> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::{gather, IDS_ISSUED, KEEP_ALIVE};
+
+    #[test]
+    fn text_format() {
+        IDS_ISSUED
+            .with_label_values(&["default", "snowflake"])
+            .inc();
+        KEEP_ALIVE.with_label_values(&["success"]).inc();
+        let text = String::from_utf8(gather().unwrap()).unwrap();
+        assert!(text.contains("# TYPE idgener_ids_issued_total counter"));
+        assert!(text.contains(r#"idgener_ids_issued_total{dataset="default",mode="snowflake"}"#));
+        assert!(text.contains(r#"idgener_keep_alive_total{result="success"}"#));
+    }
+}
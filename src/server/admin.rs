@@ -0,0 +1,599 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{delete, get, put, web, HttpRequest, HttpResponse, Result, Scope};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::cluster::{Change, Node, Nodes, Snapshot, Tombstone};
+use crate::config;
+use crate::server::audit;
+use crate::server::auth::Principal;
+use crate::server::ext::Actix;
+use crate::server::negotiate;
+use crate::server::nodes::parse;
+use crate::server::server::{push_membership, replicate};
+use crate::server::AppState;
+
+/// 管理接口，请求需要携带 `Authorization: Bearer <admin token>`
+pub fn route() -> Scope {
+    Scope::new("/admin")
+        .service(dump)
+        .service(change_leader)
+        .service(evict)
+        .service(reserve)
+        .service(release)
+        .service(pardon)
+        .service(drain)
+        .service(audit::route())
+}
+
+/// 校验管理令牌，没有配置令牌时关闭管理接口
+pub fn authorize(req: &HttpRequest, state: &AppState) -> Result<()> {
+    if Principal::of(req).is_some_and(|p| p.allows(config::Scope::Admin, None)) {
+        return Ok(());
+    }
+    let token = match &state.admin_token {
+        Some(token) => token,
+        None => return Err(actix_web::error::ErrorForbidden("admin api is disabled")),
+    };
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match bearer {
+        Some(bearer) if constant_eq(bearer.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => {
+            log::warn!(
+                "reject admin request {} from {:?}",
+                req.path(),
+                req.peer_addr()
+            );
+            Err(actix_web::error::ErrorUnauthorized("invalid admin token"))
+        }
+    }
+}
+
+/// 比较耗时和内容无关，避免通过响应时间猜测令牌
+pub fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 集群变更只能在leader上执行
+fn leader_only(nodes: &Nodes) -> Result<()> {
+    if !nodes.self_is_leader() {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "not leader, leader is {:?}",
+            nodes.get_leader().map(|leader| leader.address)
+        )));
+    }
+    Ok(())
+}
+
+/// 节点租约，[last_alive_timestamp]为本机最后一次收到该节点keep-alive的时间
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Lease {
+    pub id: u16,
+    #[schema(value_type = String)]
+    pub address: SocketAddr,
+    pub last_alive_timestamp: i64,
+    /// 距离最后一次keep-alive的毫秒数
+    pub age: i64,
+}
+
+/// 当前节点保存的完整集群状态
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AdminState {
+    pub current: Option<u16>,
+    pub leader: Option<u16>,
+    pub term: u64,
+    pub version: u64,
+    pub leases: Vec<Lease>,
+    #[schema(value_type = BTreeMap<u16, Option<String>>)]
+    pub reserved: BTreeMap<u16, Option<SocketAddr>>,
+    /// 被移除的节点，清除之前不能重新加入
+    pub evicted: BTreeMap<u16, Tombstone>,
+    pub draining: bool,
+    pub isolated: bool,
+    pub clock_offset: Option<i64>,
+    pub high_water_mark: Option<u64>,
+}
+
+/// 当前节点的集群状态
+#[utoipa::path(
+    get,
+    path = "/api/nodes/admin/state",
+    tag = "admin",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = AdminState),
+        (status = 401, description = "invalid admin token"),
+        (status = 403, description = "admin api is disabled"),
+    )
+)]
+#[get("/state")]
+pub async fn dump(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    authorize(&req, &data)?;
+    let now = Utc::now().timestamp_millis();
+    let nodes = data.nodes.read().actix()?;
+    let state = AdminState {
+        current: nodes.get_current().map(|node| node.id),
+        leader: nodes.get_leader().map(|node| node.id),
+        term: nodes.term(),
+        version: nodes.version(),
+        leases: nodes
+            .nodes
+            .iter()
+            .map(|node| Lease {
+                id: node.id,
+                address: node.address,
+                last_alive_timestamp: node.last_alive_timestamp,
+                age: now - node.last_alive_timestamp,
+            })
+            .collect(),
+        reserved: nodes.reserved().clone(),
+        evicted: nodes.evicted().clone(),
+        draining: data.draining.load(Ordering::SeqCst),
+        isolated: data.fencing.is_isolated(),
+        clock_offset: data.clock.offset(),
+        high_water_mark: data
+            .snowflake
+            .read()
+            .actix()?
+            .and_then(|snowflake| snowflake.high_water_mark()),
+    };
+    negotiate::respond(&req, HttpResponse::Ok(), &state)
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ForceLeader {
+    pub id: u16,
+}
+
+/// 强制切换leader，由当前leader记录变更并推送给所有节点
+#[utoipa::path(
+    put,
+    path = "/api/nodes/admin/leader",
+    tag = "admin",
+    request_body = ForceLeader,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Snapshot),
+        (status = 401, description = "invalid admin token"),
+        (status = 404, description = "node not found"),
+        (status = 409, description = "not leader"),
+    )
+)]
+#[put("/leader")]
+pub async fn change_leader(
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    authorize(&req, &data)?;
+    let force = parse::<ForceLeader>(&body)?;
+
+    let mut nodes = data.nodes.write().actix()?;
+    leader_only(&nodes)?;
+    if nodes.get(force.id).is_none() {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "not found node {}",
+            force.id
+        )));
+    }
+    if nodes.is_leader(force.id) {
+        return negotiate::respond(&req, HttpResponse::Ok(), &nodes.snapshot());
+    }
+
+    let current = nodes.get_current().map(|node| node.id).actix()?;
+    let term = nodes.term() + 1;
+    log::info!("force leader change to {}, term: {}", force.id, term);
+    let delta = nodes.record(Change::Leader { id: force.id, term });
+    let followers = nodes
+        .nodes
+        .iter()
+        .filter(|node| node.id != current)
+        .cloned()
+        .collect();
+    actix_web::rt::spawn(push_membership(
+        data.clone(),
+        current,
+        term,
+        nodes.snapshot(),
+        followers,
+        vec![delta],
+    ));
+    negotiate::respond(&req, HttpResponse::Ok(), &nodes.snapshot())
+}
+
+/// 把节点移出集群并记录墓碑，清除墓碑之前节点不能重新加入
+#[utoipa::path(
+    delete,
+    path = "/api/nodes/admin/nodes/{id}",
+    tag = "admin",
+    params(("id" = u16, Path, description = "节点ID")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Node),
+        (status = 401, description = "invalid admin token"),
+        (status = 404, description = "node not found"),
+        (status = 409, description = "not leader, or evicting the leader"),
+    )
+)]
+#[delete("/nodes/{id}")]
+pub async fn evict(
+    req: HttpRequest,
+    id: web::Path<u16>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    authorize(&req, &data)?;
+    let id = id.into_inner();
+
+    let mut nodes = data.nodes.write().actix()?;
+    leader_only(&nodes)?;
+    if nodes.is_leader(id) {
+        return Err(actix_web::error::ErrorConflict(
+            "can't evict leader, change leader first",
+        ));
+    }
+    match nodes.get(id).cloned() {
+        Some(node) => {
+            log::info!("evict node: [{}]:{}", node.id, node.address);
+            let term = nodes.term();
+            let delta = nodes.record(Change::Evict(Tombstone {
+                id,
+                address: node.address,
+                term,
+            }));
+            actix_web::rt::spawn(replicate(data.clone(), vec![delta]));
+            negotiate::respond(&req, HttpResponse::Ok(), &node)
+        }
+        None => Err(actix_web::error::ErrorNotFound(format!(
+            "not found node {}",
+            id
+        ))),
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct Reservation {
+    /// 允许使用该ID的节点地址，为空时禁止任何节点使用
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub address: Option<SocketAddr>,
+}
+
+/// 保留或者禁用节点ID
+#[utoipa::path(
+    put,
+    path = "/api/nodes/admin/reservations/{id}",
+    tag = "admin",
+    params(("id" = u16, Path, description = "节点ID")),
+    request_body(content = Option<Reservation>, description = "为空时禁止任何节点使用该ID"),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = BTreeMap<u16, Option<String>>, description = "所有保留的节点ID"),
+        (status = 401, description = "invalid admin token"),
+        (status = 409, description = "not leader, or the id is used by another node"),
+    )
+)]
+#[put("/reservations/{id}")]
+pub async fn reserve(
+    req: HttpRequest,
+    id: web::Path<u16>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    authorize(&req, &data)?;
+    let id = id.into_inner();
+    let reservation = match body.is_empty() {
+        true => Reservation::default(),
+        false => parse::<Reservation>(&body)?,
+    };
+
+    let mut nodes = data.nodes.write().actix()?;
+    leader_only(&nodes)?;
+    if let Some(node) = nodes.get(id) {
+        if Some(node.address) != reservation.address {
+            return Err(actix_web::error::ErrorConflict(format!(
+                "node id {} is used by {}, evict it first",
+                id, node.address
+            )));
+        }
+    }
+    log::info!("reserve node id {} for {:?}", id, reservation.address);
+    let delta = nodes.record(Change::Reserve {
+        id,
+        address: reservation.address,
+    });
+    actix_web::rt::spawn(replicate(data.clone(), vec![delta]));
+    negotiate::respond(&req, HttpResponse::Ok(), &nodes.reserved())
+}
+
+/// 取消保留的节点ID
+#[utoipa::path(
+    delete,
+    path = "/api/nodes/admin/reservations/{id}",
+    tag = "admin",
+    params(("id" = u16, Path, description = "节点ID")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = BTreeMap<u16, Option<String>>, description = "所有保留的节点ID"),
+        (status = 401, description = "invalid admin token"),
+        (status = 409, description = "not leader"),
+    )
+)]
+#[delete("/reservations/{id}")]
+pub async fn release(
+    req: HttpRequest,
+    id: web::Path<u16>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    authorize(&req, &data)?;
+    let id = id.into_inner();
+
+    let mut nodes = data.nodes.write().actix()?;
+    leader_only(&nodes)?;
+    if !nodes.reserved().contains_key(&id) {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "node id {} is not reserved",
+            id
+        )));
+    }
+    log::info!("release node id {}", id);
+    let delta = nodes.record(Change::Release(id));
+    actix_web::rt::spawn(replicate(data.clone(), vec![delta]));
+    negotiate::respond(&req, HttpResponse::Ok(), &nodes.reserved())
+}
+
+/// 清除被移除节点的墓碑，允许它重新加入
+#[utoipa::path(
+    delete,
+    path = "/api/nodes/admin/evictions/{id}",
+    tag = "admin",
+    params(("id" = u16, Path, description = "节点ID")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = BTreeMap<u16, Tombstone>, description = "所有被移除的节点"),
+        (status = 401, description = "invalid admin token"),
+        (status = 404, description = "node is not evicted"),
+        (status = 409, description = "not leader"),
+    )
+)]
+#[delete("/evictions/{id}")]
+pub async fn pardon(
+    req: HttpRequest,
+    id: web::Path<u16>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    authorize(&req, &data)?;
+    let id = id.into_inner();
+
+    let mut nodes = data.nodes.write().actix()?;
+    leader_only(&nodes)?;
+    if !nodes.evicted().contains_key(&id) {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "node {} is not evicted",
+            id
+        )));
+    }
+    log::info!("pardon evicted node {}", id);
+    let delta = nodes.record(Change::Pardon(id));
+    actix_web::rt::spawn(replicate(data.clone(), vec![delta]));
+    negotiate::respond(&req, HttpResponse::Ok(), &nodes.evicted())
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Drain {
+    pub draining: bool,
+}
+
+/// 设置当前节点的维护状态，维护中的节点不再发放ID
+#[utoipa::path(
+    put,
+    path = "/api/nodes/admin/drain",
+    tag = "admin",
+    request_body = Drain,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Drain),
+        (status = 401, description = "invalid admin token"),
+    )
+)]
+#[put("/drain")]
+pub async fn drain(
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    authorize(&req, &data)?;
+    let drain = parse::<Drain>(&body)?;
+    log::info!("set draining: {}", drain.draining);
+    data.draining.store(drain.draining, Ordering::SeqCst);
+    negotiate::respond(&req, HttpResponse::Ok(), &drain)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestServer;
+    use actix_web::web::Buf;
+    use actix_web::{test, web, App};
+
+    use crate::cluster::{Identity, Node};
+    use crate::config::logger;
+    use crate::generator::Snowflake;
+    use crate::server::admin::{route, AdminState, Drain, ForceLeader, Reservation};
+    use crate::server::AppState;
+
+    const TOKEN: &str = "Bearer s3cret";
+
+    fn start() -> (web::Data<AppState>, TestServer) {
+        let state = AppState {
+            admin_token: Some(String::from("s3cret")),
+            ..AppState::new(Identity::default())
+        };
+        let state = web::Data::new(state);
+        state
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+            .join(Node::new(1, "127.0.1.1:1024".parse().unwrap()))
+            .set_leader(Some(0))
+            .set_current(0);
+        let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+        let data = state.clone();
+        let srv = test::start(move || App::new().app_data(data.clone()).service(route()));
+        (state, srv)
+    }
+
+    #[actix_rt::test]
+    async fn authorize() {
+        logger::init(true);
+        let (_, srv) = start();
+        let response = srv.get("/admin/state").send().await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let response = srv
+            .get("/admin/state")
+            .header("Authorization", "Bearer other")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let mut response = srv
+            .get("/admin/state")
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let body = response.body().await.unwrap();
+        let state = serde_json::from_slice::<AdminState>(body.bytes()).unwrap();
+        assert_eq!(2, state.leases.len());
+        assert_eq!(Some(0), state.leader);
+
+        let disabled = test::start(|| {
+            App::new()
+                .app_data(web::Data::new(AppState::default()))
+                .service(route())
+        });
+        let response = disabled
+            .get("/admin/state")
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+    }
+
+    #[actix_rt::test]
+    async fn manage() {
+        logger::init(true);
+        let (state, srv) = start();
+
+        let response = srv
+            .put("/admin/reservations/1")
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+
+        let response = srv
+            .delete("/admin/nodes/0")
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+        let response = srv
+            .delete("/admin/nodes/1")
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        {
+            let nodes = state.nodes.read().unwrap();
+            assert!(nodes.get(1).is_none());
+            assert_eq!(Some(1), nodes.evicted().get(&1).map(|t| t.id));
+        }
+        let response = srv
+            .delete("/admin/evictions/1")
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert!(state.nodes.read().unwrap().evicted().is_empty());
+        let response = srv
+            .delete("/admin/evictions/1")
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let address = "127.0.2.1:1024".parse::<SocketAddr>().unwrap();
+        let response = srv
+            .put("/admin/reservations/1")
+            .header("Authorization", TOKEN)
+            .send_json(&Reservation {
+                address: Some(address),
+            })
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(Ok(1), state.nodes.read().unwrap().assign_id(&address, None));
+        let response = srv
+            .delete("/admin/reservations/1")
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert!(state.nodes.read().unwrap().reserved().is_empty());
+
+        let response = srv
+            .put("/admin/drain")
+            .header("Authorization", TOKEN)
+            .send_json(&Drain { draining: true })
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert!(state.draining.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[actix_rt::test]
+    async fn force_leader() {
+        logger::init(true);
+        let (state, srv) = start();
+        let response = srv
+            .put("/admin/leader")
+            .header("Authorization", TOKEN)
+            .send_json(&ForceLeader { id: 9 })
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let response = srv
+            .put("/admin/leader")
+            .header("Authorization", TOKEN)
+            .send_json(&ForceLeader { id: 1 })
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let nodes = state.nodes.read().unwrap();
+        assert!(nodes.is_leader(1));
+        assert_eq!(1, nodes.term());
+        assert!(!nodes.self_is_leader());
+    }
+}
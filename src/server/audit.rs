@@ -0,0 +1,112 @@
+use actix_web::{get, web, HttpRequest, HttpResponse, Result, Scope};
+
+use crate::generator::Range;
+use crate::server::admin::authorize;
+use crate::server::ext::Actix;
+use crate::server::negotiate;
+use crate::server::AppState;
+
+/// 审计日志查询，和管理接口使用相同的令牌
+pub fn route() -> Scope {
+    Scope::new("/audit").service(lookup)
+}
+
+/// 查找包含ID的发放区间和调用方，只能查到本节点发放的ID。多个调用方交替获取ID时可能返回多个区间
+#[utoipa::path(
+    get,
+    path = "/api/nodes/admin/audit/{id}",
+    tag = "admin",
+    params(("id" = u64, Path, description = "ID")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Vec<Range>),
+        (status = 401, description = "invalid admin token"),
+        (status = 404, description = "audit log is disabled, or the id is not issued by this node"),
+    )
+)]
+#[get("/{id}")]
+pub async fn lookup(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    authorize(&req, &state)?;
+    let audit = state
+        .audit
+        .as_ref()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("audit log is disabled"))?;
+    let id = id.into_inner();
+    let ranges: Vec<Range> = audit.lookup(id).actix()?;
+    if ranges.is_empty() {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "id {} is not issued by this node",
+            id
+        )));
+    }
+    negotiate::respond(&req, HttpResponse::Ok(), &ranges)
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::StatusCode;
+    use actix_web::web::Buf;
+    use actix_web::{test, web, App};
+
+    use crate::config::logger;
+    use crate::generator::{AuditLog, Range, Snowflake};
+    use crate::server::admin;
+    use crate::server::generator::snowflake;
+    use crate::server::AppState;
+
+    #[actix_rt::test]
+    async fn lookup() {
+        logger::init(true);
+        let dir = std::env::temp_dir().join(format!("idgener-audit-{}", rand::random::<u32>()));
+        let audit_dir = dir.clone();
+        let srv = test::start(move || {
+            let state = AppState {
+                admin_token: Some(String::from("secret")),
+                audit: Some(AuditLog::new(&audit_dir, 1024, 2)),
+                ..AppState::default()
+            };
+            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(3));
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(snowflake)
+                .service(admin::route())
+        });
+
+        let mut response = srv.get("/snowflake").send().await.unwrap();
+        let body = response.body().await.unwrap();
+        let id = std::str::from_utf8(body.bytes()).unwrap().to_string();
+
+        let mut response = srv
+            .get(format!("/admin/audit/{}", id))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let body = response.body().await.unwrap();
+        let ranges = serde_json::from_slice::<Vec<Range>>(body.bytes()).unwrap();
+        let range = &ranges[0];
+        assert_eq!(id, range.first.to_string());
+        assert_eq!(3, range.worker);
+        assert_eq!("127.0.0.1", range.caller);
+
+        let response = srv
+            .get(format!("/admin/audit/{}", id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let response = srv
+            .get("/admin/audit/1")
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
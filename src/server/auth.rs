@@ -0,0 +1,534 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use chrono::Utc;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use futures::StreamExt;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::cluster::{Signature, HEADER_SIGNATURE};
+use crate::config::{ApiKey, AuthConfig, Scope};
+use crate::server::admin::constant_eq;
+use crate::server::generator::DATASET;
+use crate::server::AppState;
+
+pub const HEADER_API_KEY: &str = "x-api-key";
+
+/// 认证后的调用方
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Principal {
+    /// 调用方名称，记录在审计日志中
+    #[serde(rename = "sub")]
+    pub id: String,
+    pub scopes: Vec<Scope>,
+    /// 允许使用的ID序列，[None]时允许所有序列
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datasets: Option<Vec<String>>,
+}
+
+impl Principal {
+    pub fn allows(&self, scope: Scope, dataset: Option<&str>) -> bool {
+        if !self.scopes.contains(&scope) {
+            return false;
+        }
+        match (dataset, &self.datasets) {
+            (Some(dataset), Some(datasets)) => datasets.iter().any(|d| d == dataset || d == "*"),
+            _ => true,
+        }
+    }
+
+    /// 请求认证后的调用方
+    pub fn of(req: &HttpRequest) -> Option<Principal> {
+        req.extensions().get::<Principal>().cloned()
+    }
+}
+
+/// 根据请求携带的凭证认证调用方，无法识别时返回[None]
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, credential: &str) -> Option<Principal>;
+}
+
+/// 配置文件中的静态API key
+pub struct StaticKeys(Vec<ApiKey>);
+
+impl Authenticator for StaticKeys {
+    fn authenticate(&self, credential: &str) -> Option<Principal> {
+        self.0
+            .iter()
+            .find(|key| constant_eq(key.key.expose().as_bytes(), credential.as_bytes()))
+            .map(|key| Principal {
+                id: key.id.clone(),
+                scopes: key.scopes.clone(),
+                datasets: key.datasets.clone(),
+            })
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// token的内容，[exp]为过期时间的秒数
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    #[serde(flatten)]
+    principal: Principal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+}
+
+/// HMAC-SHA256签名的bearer token，格式为 `hex(claims json).hex(signature)`
+pub struct HmacTokens {
+    secret: String,
+}
+
+impl HmacTokens {
+    pub fn new(secret: &str) -> Self {
+        HmacTokens {
+            secret: secret.to_string(),
+        }
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_varkey(self.secret.as_bytes()).expect("HMAC can take key of any size");
+        mac.update(payload);
+        mac
+    }
+
+    /// 签发token，token由外部系统使用相同的算法签发
+    #[cfg(test)]
+    pub fn issue(&self, principal: &Principal, exp: Option<i64>) -> String {
+        let claims = serde_json::to_vec(&Claims {
+            principal: principal.clone(),
+            exp,
+        })
+        .unwrap();
+        let signature = self.mac(&claims).finalize().into_bytes();
+        format!("{}.{}", hex::encode(&claims), hex::encode(signature))
+    }
+}
+
+impl Authenticator for HmacTokens {
+    fn authenticate(&self, credential: &str) -> Option<Principal> {
+        let (payload, signature) = credential.split_once('.')?;
+        let payload = hex::decode(payload).ok()?;
+        let signature = hex::decode(signature).ok()?;
+        self.mac(&payload).verify(&signature).ok()?;
+        let claims = serde_json::from_slice::<Claims>(&payload).ok()?;
+        match claims.exp {
+            Some(exp) if exp < Utc::now().timestamp() => None,
+            _ => Some(claims.principal),
+        }
+    }
+}
+
+/// 依次使用各个认证方式
+pub struct Auth {
+    authenticators: Vec<Box<dyn Authenticator>>,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Self {
+        let mut authenticators: Vec<Box<dyn Authenticator>> =
+            vec![Box::new(StaticKeys(config.keys.clone()))];
+        if let Some(secret) = &config.token_secret {
+            authenticators.push(Box::new(HmacTokens::new(secret.expose())));
+        }
+        Auth { authenticators }
+    }
+
+    pub fn authenticate(&self, credential: &str) -> Option<Principal> {
+        self.authenticators
+            .iter()
+            .find_map(|authenticator| authenticator.authenticate(credential))
+    }
+}
+
+/// 请求需要的访问范围和ID序列，[None]表示不需要认证。
+/// [path]必须是路由使用的解码后的路径，否则 `/api/%67/snowflake` 这样编码过的路径可以绕过认证
+fn required(path: &str) -> Option<(Scope, Option<&'static str>)> {
+    if let Some(rest) = path.strip_prefix("/api/g/") {
+        return match rest.strip_prefix("snowflake/") {
+            Some(_) => Some((Scope::Decode, Some(DATASET))),
+            None => Some((Scope::Generate, Some(DATASET))),
+        };
+    }
+    if path.starts_with("/api/nodes/admin") {
+        return Some((Scope::Admin, None));
+    }
+    if path.starts_with("/api/nodes") || path.starts_with("/api/datacenters") {
+        return Some((Scope::Cluster, None));
+    }
+    None
+}
+
+fn credential(req: &ServiceRequest) -> Option<&str> {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    header(AUTHORIZATION.as_str())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| header(HEADER_API_KEY))
+}
+
+/// 校验签名时读取的最大请求体
+const MAX_SIGNED_BODY: usize = 256 * 1024;
+
+/// 读取请求体用于校验签名，读取后放回请求中交给接口处理
+async fn read_body(req: &mut ServiceRequest) -> Result<Bytes, Error> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_SIGNED_BODY {
+            return Err(actix_web::error::ErrorPayloadTooLarge(
+                "request body too large",
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    let (_, mut restored) = actix_http::h1::Payload::create(true);
+    restored.unread_data(body.clone());
+    req.set_payload(restored.into());
+    Ok(body)
+}
+
+/// 节点间和数据中心间的请求是否带有有效的签名。只校验签名，nonce由接口自己检查
+async fn signed(req: &mut ServiceRequest, state: &AppState, path: &str) -> Result<bool, Error> {
+    if !req.headers().contains_key(HEADER_SIGNATURE) {
+        return Ok(false);
+    }
+    let secret = match path.starts_with("/api/datacenters") {
+        true => state.federation_secret.clone(),
+        false => state.cluster.secret().map(String::from),
+    };
+    let secret = match secret {
+        Some(secret) => secret,
+        None => return Ok(false),
+    };
+    let body = read_body(req).await?;
+    let verified = Signature::from_headers(|name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    })
+    .and_then(|signature| signature.verify(&secret, req.method().as_str(), req.path(), &body));
+    Ok(verified.is_ok())
+}
+
+/// 认证中间件，用于 `App::wrap`。没有配置认证时不检查。
+///
+/// 节点间请求使用集群密钥签名，签名校验通过时不需要其他凭证；管理令牌由管理接口自己校验。
+pub struct Guard;
+
+impl<S> Transform<S> for Guard
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = GuardMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(GuardMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        }))
+    }
+}
+
+pub struct GuardMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S> Service for GuardMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<ServiceResponse, Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let req = match guard(req).await {
+                Ok(req) => req,
+                Err(rejected) => return Ok(rejected),
+            };
+            let response = service.borrow_mut().call(req);
+            response.await
+        })
+    }
+}
+
+/// 检查请求的凭证，通过时返回请求，否则返回带有错误的拒绝响应
+async fn guard(mut req: ServiceRequest) -> Result<ServiceRequest, ServiceResponse> {
+    let state = match req.app_data::<web::Data<AppState>>() {
+        Some(state) if state.auth.is_some() => state.clone(),
+        _ => return Ok(req),
+    };
+    // 使用和路由相同的解码后的路径
+    let path = req.match_info().path().to_string();
+    let (scope, dataset) = match required(&path) {
+        Some(required) => required,
+        None => return Ok(req),
+    };
+    if scope == Scope::Cluster {
+        match signed(&mut req, &state, &path).await {
+            Ok(true) => return Ok(req),
+            Ok(false) => {}
+            Err(err) => return Err(req.error_response(err)),
+        }
+    }
+    let credential = credential(&req);
+    if let (Scope::Admin, Some(token), Some(credential)) =
+        (scope, state.admin_token.as_ref(), credential)
+    {
+        if constant_eq(token.as_bytes(), credential.as_bytes()) {
+            return Ok(req);
+        }
+    }
+    let principal = credential.and_then(|credential| state.auth.as_ref()?.authenticate(credential));
+    let (message, rejected) = match principal {
+        None => {
+            let message = String::from("invalid or missing credential");
+            let response = HttpResponse::Unauthorized()
+                .header(WWW_AUTHENTICATE, "Bearer")
+                .body(message.clone());
+            (message, response)
+        }
+        Some(principal) if !principal.allows(scope, dataset) => {
+            let message = format!("{} is not allowed to {}", principal.id, scope);
+            (message.clone(), HttpResponse::Forbidden().body(message))
+        }
+        Some(principal) => {
+            req.extensions_mut().insert(principal);
+            return Ok(req);
+        }
+    };
+    log::warn!(
+        "reject request {} from {:?}: {}",
+        req.path(),
+        req.peer_addr(),
+        rejected.status()
+    );
+    // 带上错误，由[crate::server::negotiate::errors]按照 `Accept` 输出
+    Err(req.error_response(InternalError::from_response(message, rejected)))
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::header::{ACCEPT, WWW_AUTHENTICATE};
+    use actix_web::http::StatusCode;
+    use actix_web::web::Buf;
+    use actix_web::{test, web, App};
+
+    use crate::cluster::{Identity, Node, Signature};
+    use crate::config::logger;
+    use crate::config::{ApiKey, AuthConfig, Scope};
+    use crate::generator::Snowflake;
+    use crate::server::auth::{Auth, Authenticator, Guard, HmacTokens, Principal};
+    use crate::server::negotiate::{self, Failure};
+    use crate::server::nodes::JoinInfo;
+    use crate::server::routers::route;
+    use crate::server::AppState;
+
+    fn principal(scopes: Vec<Scope>, datasets: Option<Vec<String>>) -> Principal {
+        Principal {
+            id: String::from("billing"),
+            scopes,
+            datasets,
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens = HmacTokens::new("secret");
+        let billing = principal(vec![Scope::Generate], None);
+        let token = tokens.issue(&billing, None);
+        assert_eq!(Some(billing.clone()), tokens.authenticate(&token));
+        assert_eq!(None, HmacTokens::new("other").authenticate(&token));
+        assert_eq!(None, tokens.authenticate(&tokens.issue(&billing, Some(1))));
+        assert_eq!(None, tokens.authenticate("not-a-token"));
+
+        let limited = principal(vec![Scope::Generate], Some(vec![String::from("orders")]));
+        assert!(limited.allows(Scope::Generate, Some("orders")));
+        assert!(!limited.allows(Scope::Generate, Some("default")));
+        assert!(!limited.allows(Scope::Admin, None));
+    }
+
+    #[actix_rt::test]
+    async fn generate() {
+        logger::init(true);
+        let config = AuthConfig {
+            keys: vec![
+                ApiKey {
+                    id: String::from("billing"),
+                    key: "k1".parse().unwrap(),
+                    scopes: vec![Scope::Generate],
+                    datasets: None,
+                },
+                ApiKey {
+                    id: String::from("monitor"),
+                    key: "k2".parse().unwrap(),
+                    scopes: vec![Scope::Decode],
+                    datasets: None,
+                },
+            ],
+            token_secret: Some("secret".parse().unwrap()),
+        };
+        let srv = test::start(move || {
+            let state = AppState {
+                auth: Some(Auth::new(&config)),
+                ..AppState::default()
+            };
+            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+            App::new()
+                .wrap(Guard)
+                .wrap_fn(negotiate::errors)
+                .app_data(web::Data::new(state))
+                .service(route())
+        });
+
+        let response = srv.get("/api/g/snowflake").send().await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+        let mut response = srv
+            .get("/api/g/snowflake")
+            .header(ACCEPT, "application/json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let failure: Failure = response.json().await.unwrap();
+        assert_eq!("invalid or missing credential", failure.error);
+        let response = srv
+            .get("/api/g/snowflake")
+            .bearer_auth("k2")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        let mut response = srv
+            .get("/api/g/snowflake")
+            .header("x-api-key", "k1")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let body = response.body().await.unwrap();
+        let id = std::str::from_utf8(body.bytes()).unwrap().to_string();
+
+        let response = srv
+            .get(format!("/api/g/snowflake/{}", id))
+            .bearer_auth("k2")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let token = HmacTokens::new("secret").issue(
+            &principal(vec![Scope::Generate], Some(vec![String::from("orders")])),
+            None,
+        );
+        let response = srv
+            .get("/api/g/snowflake")
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        let response = srv.get("/api/nodes").send().await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[actix_rt::test]
+    async fn encoded_path() {
+        logger::init(true);
+        let srv = test::start(move || {
+            let state = AppState {
+                auth: Some(Auth::new(&AuthConfig::default())),
+                ..AppState::default()
+            };
+            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+            App::new()
+                .wrap(Guard)
+                .app_data(web::Data::new(state))
+                .service(route())
+        });
+        // 路由使用解码后的路径，认证也必须检查解码后的路径
+        let response = srv.get("/api/%67/snowflake").send().await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let response = srv.get("/api/%6Eodes").send().await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    /// 只有签名校验通过的节点请求不需要凭证，只带签名头的请求仍然需要认证
+    #[actix_rt::test]
+    async fn signed_cluster_request() {
+        logger::init(true);
+        let cluster = Identity::new("idgener", Some("s3cret"));
+        let identity = cluster.clone();
+        let srv = test::start(move || {
+            let state = AppState {
+                auth: Some(Auth::new(&AuthConfig::default())),
+                ..AppState::new(identity.clone())
+            };
+            state
+                .nodes
+                .write()
+                .unwrap()
+                .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+                .set_leader(Some(0))
+                .set_current(0);
+            App::new()
+                .wrap(Guard)
+                .app_data(web::Data::new(state))
+                .service(route())
+        });
+        let body = serde_json::to_vec(&JoinInfo::new(
+            &cluster,
+            "127.0.1.1:1024".parse().unwrap(),
+            None,
+        ))
+        .unwrap();
+        let request = |signature: &Signature| {
+            let mut request = srv.post("/api/nodes").content_type("application/json");
+            for (name, value) in signature.headers().iter() {
+                request = request.header(*name, value.as_str());
+            }
+            request
+        };
+
+        let forged = Signature {
+            timestamp: 0,
+            nonce: String::from("0"),
+            signature: String::from("00"),
+        };
+        let response = request(&forged).send_body(body.clone()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let other = Signature::sign("other", "POST", "/api/nodes", &body);
+        let response = request(&other).send_body(body.clone()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        // 中间件读取请求体校验签名后，接口仍然能读到请求体
+        let signature = Signature::sign("s3cret", "POST", "/api/nodes", &body);
+        let response = request(&signature).send_body(body).await.unwrap();
+        assert!(response.status().is_success());
+    }
+}
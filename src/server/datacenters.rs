@@ -0,0 +1,202 @@
+use std::sync::RwLock;
+
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Result, Scope};
+use chrono::Utc;
+
+use crate::cluster::{Datacenter, Federation, View};
+use crate::server::ext::Actix;
+use crate::server::negotiate;
+use crate::server::nodes::{authenticate_with, parse, signed_with};
+use crate::server::AppState;
+
+pub fn route() -> Scope {
+    Scope::new("/datacenters").service(view).service(register)
+}
+
+fn federation(state: &AppState) -> Result<&RwLock<Federation>> {
+    state
+        .federation
+        .as_ref()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("datacenter is not configured"))
+}
+
+/// 联邦中所有数据中心的视图
+#[utoipa::path(
+    get,
+    path = "/api/datacenters",
+    tag = "datacenters",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = View),
+        (status = 404, description = "datacenter is not configured"),
+    )
+)]
+#[get("")]
+pub async fn view(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    let federation = federation(&state)?.read().actix()?;
+    let view: View = federation.view(Utc::now().timestamp_millis());
+    negotiate::respond(&req, HttpResponse::Ok(), &view)
+}
+
+/// 其他数据中心注册，响应本数据中心的信息，ID冲突时返回409。
+/// 不同数据中心的集群密钥不同，注册请求和响应使用联邦密钥签名
+#[utoipa::path(
+    post,
+    path = "/api/datacenters",
+    tag = "cluster",
+    request_body = Datacenter,
+    responses(
+        (status = 200, body = Datacenter),
+        (status = 403, description = "invalid signature"),
+        (status = 404, description = "datacenter is not configured"),
+        (status = 409, body = Datacenter, description = "datacenter id conflict"),
+    )
+)]
+#[post("")]
+pub async fn register(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let secret = state.federation_secret.as_deref();
+    authenticate_with(&req, &body, &state, secret)?;
+    let datacenter = parse::<Datacenter>(&body)?;
+    let mut federation = federation(&state)?.write().actix()?;
+    let local = federation.local().clone();
+    match federation.register(datacenter, Utc::now().timestamp_millis()) {
+        Ok(_) => signed_with(&req, secret, StatusCode::OK, &local),
+        Err(err) => {
+            log::error!("{}", err);
+            signed_with(&req, secret, StatusCode::CONFLICT, &local)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::RwLock;
+
+    use actix_web::http::StatusCode;
+    use actix_web::web::Buf;
+    use actix_web::{test, web, App};
+
+    use crate::cluster::{Datacenter, Federation, Identity, Signature, View};
+    use crate::config::logger;
+    use crate::server::datacenters::route;
+    use crate::server::AppState;
+
+    fn datacenter(id: u8, name: &str) -> Datacenter {
+        Datacenter {
+            id,
+            name: name.to_string(),
+            address: "127.0.0.1:7656".parse().unwrap(),
+            last_seen: 0,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn register() {
+        logger::init(true);
+        let srv = test::start(|| {
+            let state = AppState {
+                federation: Some(RwLock::new(Federation::new(
+                    datacenter(1, "beijing"),
+                    vec![],
+                    60_000,
+                ))),
+                ..AppState::default()
+            };
+            App::new().app_data(web::Data::new(state)).service(route())
+        });
+
+        let mut response = srv
+            .post("/datacenters")
+            .send_json(&datacenter(2, "shanghai"))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let body = response.body().await.unwrap();
+        let local = serde_json::from_slice::<Datacenter>(body.bytes()).unwrap();
+        assert_eq!("beijing", local.name);
+
+        let response = srv
+            .post("/datacenters")
+            .send_json(&datacenter(1, "hangzhou"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+
+        let mut response = srv.get("/datacenters").send().await.unwrap();
+        let body = response.body().await.unwrap();
+        let view = serde_json::from_slice::<View>(body.bytes()).unwrap();
+        assert_eq!(1, view.datacenters.len());
+        assert_eq!(1, view.conflicts.len());
+
+        let disabled = test::start(|| {
+            App::new()
+                .app_data(web::Data::new(AppState::default()))
+                .service(route())
+        });
+        let response = disabled.get("/datacenters").send().await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    /// 数据中心之间使用联邦密钥签名，不使用各自的集群密钥
+    #[actix_rt::test]
+    async fn signed_register() {
+        logger::init(true);
+        let srv = test::start(|| {
+            let state = AppState {
+                federation: Some(RwLock::new(Federation::new(
+                    datacenter(1, "beijing"),
+                    vec![],
+                    60_000,
+                ))),
+                federation_secret: Some(String::from("federation")),
+                ..AppState::new(Identity::new("beijing", Some("cluster")))
+            };
+            App::new().app_data(web::Data::new(state)).service(route())
+        });
+        let body = serde_json::to_vec(&datacenter(2, "shanghai")).unwrap();
+        let register = |secret: &str| {
+            let signature = Signature::sign(secret, "POST", "/datacenters", &body);
+            let mut request = srv.post("/datacenters").content_type("application/json");
+            for (name, value) in signature.headers().iter() {
+                request = request.header(*name, value.as_str());
+            }
+            (signature, request)
+        };
+
+        let response = srv
+            .post("/datacenters")
+            .send_body(body.clone())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let (_, request) = register("cluster");
+        let response = request.send_body(body.clone()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let (signature, request) = register("federation");
+        let mut response = request.send_body(body.clone()).await.unwrap();
+        assert!(response.status().is_success());
+        let reply = Signature::from_headers(|name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        })
+        .unwrap();
+        let payload = response.body().await.unwrap();
+        assert!(reply
+            .verify_response(
+                "federation",
+                &signature,
+                200,
+                "/datacenters",
+                payload.bytes()
+            )
+            .is_ok());
+    }
+}
@@ -1,30 +1,193 @@
-use actix_web::{get, web, Responder, Result, Scope};
+use std::future::{ready, Ready};
+use std::sync::atomic::Ordering;
 
-use crate::generator::Idgend;
+use actix_http::Response;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder, Result, Scope};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::logger;
+use crate::generator::{Decoded, Idgend, SnowFlakeId};
+use crate::metrics;
+use crate::server::auth::Principal;
 use crate::server::ext::Actix;
+use crate::server::negotiate::{self, Format};
+use crate::server::stream;
 use crate::server::AppState;
 
+/// 目前只有一个ID序列
+pub const DATASET: &str = "default";
+
 pub fn route() -> Scope {
-    Scope::new("/g").service(snowflake)
+    Scope::new("/g")
+        .service(snowflake)
+        .service(decode)
+        .service(stream::events)
+        .service(stream::add_credits)
+        .service(stream::websocket)
+}
+
+/// 审计日志和限流使用的调用方，认证后为调用方名称，否则为来源IP
+pub fn caller(req: &HttpRequest) -> String {
+    if let Some(principal) = Principal::of(req) {
+        return principal.id;
+    }
+    req.peer_addr()
+        .map(|peer| peer.ip().to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// 解析ID的生成时间、worker id和序列号
+#[utoipa::path(
+    get,
+    path = "/api/g/snowflake/{id}",
+    tag = "generation",
+    params(("id" = u64, Path, description = "ID")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Decoded),
+        (status = 401, description = "invalid or missing credential"),
+        (status = 403, description = "scope not allowed"),
+    )
+)]
+#[get("/snowflake/{id}")]
+pub async fn decode(req: HttpRequest, id: web::Path<u64>) -> Result<HttpResponse> {
+    let decoded = SnowFlakeId::from(id.into_inner()).decode();
+    negotiate::respond(&req, HttpResponse::Ok(), &decoded)
+}
+
+/// `application/json` 格式的ID
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Id {
+    pub id: u64,
 }
 
+impl Responder for SnowFlakeId {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Response, actix_web::Error>>;
+    /// 默认输出十进制文本，按照 `Accept` 也可以输出 `{"id": n}` 或者大端序的8个字节
+    fn respond_to(self, req: &HttpRequest) -> Self::Future {
+        let format = match negotiate::format(req, &[Format::Text, Format::Json, Format::Binary]) {
+            Ok(format) => format,
+            Err(err) => return ready(Err(err)),
+        };
+        let body = match format {
+            Format::Text => self.value().to_string().into_bytes(),
+            Format::Json => serde_json::to_vec(&Id { id: self.value() }).unwrap(),
+            Format::Binary => self.value().to_be_bytes().to_vec(),
+        };
+        ready(Ok(HttpResponse::Ok()
+            .content_type(format.mime())
+            .body(body)))
+    }
+}
+
+/// 获取一个ID
+#[utoipa::path(
+    get,
+    path = "/api/g/snowflake",
+    tag = "generation",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "`application/octet-stream` 为大端序的8个字节", content(
+            (String = "text/plain", example = "6870508399902851072"),
+            (Id = "application/json"),
+            (String = "application/octet-stream"),
+        )),
+        (status = 401, description = "invalid or missing credential"),
+        (status = 403, description = "scope or dataset not allowed"),
+        (status = 406, description = "none of the accepted formats is supported"),
+        (status = 429, description = "rate limit or daily quota exceeded",
+            headers(("Retry-After" = u64, description = "seconds"))),
+        (status = 503, description = "isolated, draining, clock skewed or datacenter conflict"),
+    )
+)]
 #[get("/snowflake")]
-pub async fn snowflake(data: web::Data<AppState>) -> Result<impl Responder> {
-    let snowflake = *data.snowflake.read().actix()?;
-    if let None = snowflake {
-        return Err(actix_web::error::ErrorNotFound("not ready"));
+pub async fn snowflake(req: HttpRequest, data: web::Data<AppState>) -> Result<impl Responder> {
+    logger::set_dataset(DATASET);
+    issue(&data, &caller(&req), "snowflake")
+}
+
+/// 发放一个ID，节点不可用、超过限流或者审计日志写入失败时返回错误。[mode]为指标中的发放方式
+pub fn issue(data: &AppState, caller: &str, mode: &str) -> Result<SnowFlakeId> {
+    issue_many(data, caller, mode, 1).map(|mut ids| ids.remove(0))
+}
+
+/// 发放[count]个ID，按[count]一次检查限流，被拒绝时一个也不发放
+pub fn issue_many(
+    data: &AppState,
+    caller: &str,
+    mode: &str,
+    count: usize,
+) -> Result<Vec<SnowFlakeId>> {
+    if data.fencing.is_isolated() {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "isolated from cluster",
+        ));
+    }
+    if data.draining.load(Ordering::SeqCst) {
+        return Err(actix_web::error::ErrorServiceUnavailable("draining"));
+    }
+    if data.datacenter_conflicted() {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "datacenter id conflict",
+        ));
     }
-    snowflake.unwrap().get(true).actix()
+    if data.clock.is_skewed() {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "clock skewed from leader",
+        ));
+    }
+    if let Some(limiter) = &data.limiter {
+        limiter.check(caller, DATASET, count as u64, Utc::now().timestamp_millis())?;
+    }
+    let mut current = data.snowflake.write().actix()?;
+    let generator = current.as_mut().actix()?;
+    let mut ids = Vec::with_capacity(count);
+    for _ in 0..count {
+        let id = generator.get(true).actix()?;
+        // 持有生成器的锁写审计日志，记录顺序和发放顺序一致，每个ID只属于一个区间。
+        // 区间在内存中合并，只有结束区间时才追加写入文件
+        if let Some(audit) = &data.audit {
+            // 审计日志写入失败时不发放ID
+            audit
+                .record(
+                    DATASET,
+                    generator.worker_id(),
+                    id.value(),
+                    caller,
+                    Utc::now().timestamp_millis(),
+                )
+                .map_err(|err| {
+                    log::error!("audit: {:#}", err);
+                    actix_web::error::ErrorServiceUnavailable("audit log unavailable")
+                })?;
+        }
+        ids.push(id);
+    }
+    drop(current);
+    metrics::IDS_ISSUED
+        .with_label_values(&[DATASET, mode])
+        .inc_by(count as u64);
+    Ok(ids)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::cluster::Node;
+    use std::time::Duration;
+
+    use crate::cluster::{Clock, Fencing, Node};
+    use actix_web::http::header::{ACCEPT, CONTENT_TYPE};
+    use actix_web::http::StatusCode;
     use actix_web::{test, web, App};
+    use std::convert::TryInto;
 
     use crate::config::logger;
+    use crate::config::Limits;
     use crate::generator::Snowflake;
-    use crate::server::generator::snowflake;
+    use crate::server::generator::{decode, snowflake};
+    use crate::server::limit::RateLimiter;
     use crate::server::AppState;
 
     #[actix_rt::test]
@@ -49,4 +212,161 @@ mod tests {
             "response status code is not 200"
         );
     }
+
+    #[actix_rt::test]
+    async fn increasing() {
+        logger::init(true);
+        let srv = test::start(|| {
+            let state = web::Data::new(AppState::default());
+            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+            App::new().app_data(state).service(snowflake)
+        });
+        let mut last = 0;
+        for _ in 0..100 {
+            let mut response = srv.get("/snowflake").send().await.unwrap();
+            let body = response.body().await.unwrap();
+            let id = std::str::from_utf8(&body).unwrap().parse::<u64>().unwrap();
+            assert!(id > last, "{} after {}", id, last);
+            last = id;
+        }
+    }
+
+    #[actix_rt::test]
+    async fn negotiate() {
+        logger::init(true);
+        let srv = test::start(|| {
+            let state = web::Data::new(AppState::default());
+            state
+                .nodes
+                .write()
+                .unwrap()
+                .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+                .set_leader(Some(0))
+                .set_current(0);
+            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+            App::new()
+                .app_data(state)
+                .service(snowflake)
+                .service(decode)
+        });
+        let mut response = srv.get("/snowflake").send().await.unwrap();
+        let text = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+        let id = text.parse::<u64>().unwrap();
+
+        let mut response = srv
+            .get("/snowflake")
+            .header(ACCEPT, "application/json")
+            .send()
+            .await
+            .unwrap();
+        let json: serde_json::Value = response.json().await.unwrap();
+        assert!(json["id"].as_u64().unwrap() > id);
+
+        let mut response = srv
+            .get("/snowflake")
+            .header(ACCEPT, "application/octet-stream")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            "application/octet-stream",
+            response.headers().get(CONTENT_TYPE).unwrap()
+        );
+        let bytes = response.body().await.unwrap();
+        assert_eq!(8, bytes.len());
+        assert!(u64::from_be_bytes(bytes[..].try_into().unwrap()) > id);
+
+        let response = srv
+            .get("/snowflake")
+            .header(ACCEPT, "text/html")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_ACCEPTABLE, response.status());
+
+        let mut response = srv
+            .get(format!("/snowflake/{}", id))
+            .header(ACCEPT, "text/plain")
+            .send()
+            .await
+            .unwrap();
+        let text = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+        assert!(text.contains(&format!("id: {}", id)), "{}", text);
+        // 只有ID支持二进制，其他接口不支持时返回406
+        let response = srv
+            .get(format!("/snowflake/{}", id))
+            .header(ACCEPT, "application/octet-stream")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_ACCEPTABLE, response.status());
+        let mut response = srv
+            .get(format!("/snowflake/{}", id))
+            .header(ACCEPT, "*/*")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let json: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(id, json["id"].as_u64().unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn isolated() {
+        logger::init(true);
+        let srv = test::start(|| {
+            let state = AppState {
+                fencing: Fencing::new(Duration::from_millis(1)),
+                ..AppState::default()
+            };
+            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(snowflake)
+        });
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        let response = srv.get("/snowflake").send().await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+    }
+
+    #[actix_rt::test]
+    async fn clock_skewed() {
+        logger::init(true);
+        let srv = test::start(|| {
+            let state = AppState {
+                clock: Clock::new(Duration::from_millis(100)),
+                ..AppState::default()
+            };
+            state.clock.sample(0, 5000, 5000, 10);
+            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(snowflake)
+        });
+        let response = srv.get("/snowflake").send().await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+    }
+
+    #[actix_rt::test]
+    async fn rate_limited() {
+        logger::init(true);
+        let srv = test::start(|| {
+            let state = AppState {
+                limiter: Some(RateLimiter::new(&Limits {
+                    rate_limit: Some(1.0),
+                    ..Limits::default()
+                })),
+                ..AppState::default()
+            };
+            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(snowflake)
+        });
+        let response = srv.get("/snowflake").send().await.unwrap();
+        assert!(response.status().is_success());
+        let response = srv.get("/snowflake").send().await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("1", response.headers().get("retry-after").unwrap());
+    }
 }
@@ -1,37 +1,192 @@
-use actix_web::{web, HttpResponse, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+use actix_web::{get, web, HttpRequest, HttpResponse, Result, Scope};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use crate::server::ext::Actix;
+use crate::server::negotiate;
 use crate::server::AppState;
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub fn route() -> Scope {
+    Scope::new("/health")
+        .service(health)
+        .service(live)
+        .service(ready)
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, ToSchema)]
 pub enum HealthState {
-    Prepare,
+    /// 正在加入集群，还没有分配节点ID
+    Joining,
     Running,
+    /// 和集群多数派失联，停止发放ID
+    Isolated,
+    /// 和leader的时钟偏差超过上限，停止发放ID
+    ClockSkewed,
+    /// 数据中心ID和其他数据中心冲突，停止发放ID
+    DatacenterConflict,
+    /// 正在退出，不再接收新的请求
+    Draining,
 }
 
 ///监控数据
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct Health {
     pub state: HealthState,
     pub leader: Option<u16>,
     pub id: u16,
+    #[schema(value_type = Option<String>)]
+    pub leader_address: Option<SocketAddr>,
+    pub term: u64,
+    /// 集群成员数量
+    pub members: usize,
+    /// 集群成员版本
+    pub version: u64,
+    /// 距离上一次成功keep-alive的毫秒数
+    pub keep_alive_age: Option<i64>,
+    /// 最后一次生成的ID
+    pub high_water_mark: Option<u64>,
+    /// leader 时钟减去本机时钟的毫秒数
+    pub clock_offset: Option<i64>,
 }
 
-pub async fn health(data: web::Data<AppState>) -> Result<HttpResponse> {
-    let nodes = &*data.nodes.read().actix()?;
-    return match nodes.get_current() {
-        None => Ok(HttpResponse::InternalServerError().json(Health {
-            state: HealthState::Prepare,
-            leader: None,
-            id: 0,
-        })),
-        Some(current) => {
-            return Ok(HttpResponse::Ok().json(Health {
-                state: HealthState::Running,
-                leader: nodes.get_leader().and_then(|n| Some(n.id)),
-                id: current.id,
-            }));
-        }
+pub fn check(data: &AppState) -> Result<Health> {
+    let nodes = data.nodes.read().actix()?;
+    let current = nodes.get_current();
+    let state = if current.is_none() {
+        HealthState::Joining
+    } else if data.draining.load(Ordering::SeqCst) {
+        HealthState::Draining
+    } else if data.fencing.is_isolated() {
+        HealthState::Isolated
+    } else if data.datacenter_conflicted() {
+        HealthState::DatacenterConflict
+    } else if data.clock.is_skewed() {
+        HealthState::ClockSkewed
+    } else {
+        HealthState::Running
     };
+    let keep_alive = data.last_keep_alive.load(Ordering::SeqCst);
+    Ok(Health {
+        state,
+        leader: nodes.get_leader().map(|n| n.id),
+        id: current.map(|n| n.id).unwrap_or_default(),
+        leader_address: nodes.get_leader().map(|n| n.address),
+        term: nodes.term(),
+        members: nodes.nodes.len(),
+        version: nodes.version(),
+        keep_alive_age: match keep_alive {
+            0 => None,
+            _ => Some(Utc::now().timestamp_millis() - keep_alive),
+        },
+        high_water_mark: data
+            .snowflake
+            .read()
+            .actix()?
+            .and_then(|snowflake| snowflake.high_water_mark()),
+        clock_offset: data.clock.offset(),
+    })
+}
+
+/// 兼容旧版本，等同于[ready]
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "health",
+    responses((status = 200, body = Health), (status = 503, body = Health))
+)]
+#[get("")]
+pub async fn health(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    readiness(&req, &data)
+}
+
+/// 存活检查，进程可以正常响应就返回200
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    tag = "health",
+    responses((status = 200, body = Health))
+)]
+#[get("/live")]
+pub async fn live(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    negotiate::respond(&req, HttpResponse::Ok(), &check(&data)?)
+}
+
+/// 就绪检查，只有[HealthState::Running]状态可以接收请求
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "health",
+    responses((status = 200, body = Health), (status = 503, body = Health))
+)]
+#[get("/ready")]
+pub async fn ready(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    readiness(&req, &data)
+}
+
+fn readiness(req: &HttpRequest, data: &AppState) -> Result<HttpResponse> {
+    let status = check(data)?;
+    if status.state == HealthState::Running {
+        return negotiate::respond(req, HttpResponse::Ok(), &status);
+    }
+    negotiate::respond(req, HttpResponse::ServiceUnavailable(), &status)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use actix_web::http::StatusCode;
+    use actix_web::web::Buf;
+    use actix_web::{test, web, App};
+
+    use crate::cluster::Node;
+    use crate::config::logger;
+    use crate::server::health::{route, Health, HealthState};
+    use crate::server::AppState;
+
+    #[actix_rt::test]
+    async fn live_and_ready() {
+        logger::init(true);
+        let state = web::Data::new(AppState::default());
+        let data = state.clone();
+        let srv = test::start(move || App::new().app_data(data.clone()).service(route()));
+
+        let response = srv.get("/health/live").send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let mut response = srv.get("/health/ready").send().await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        let body = response.body().await.unwrap();
+        let health = serde_json::from_slice::<Health>(body.bytes()).unwrap();
+        assert_eq!(HealthState::Joining, health.state);
+
+        state
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+            .set_leader(Some(0))
+            .set_current(0);
+        let mut response = srv.get("/health").send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = response.body().await.unwrap();
+        let health = serde_json::from_slice::<Health>(body.bytes()).unwrap();
+        assert_eq!(HealthState::Running, health.state);
+        assert_eq!(1, health.members);
+        assert_eq!(
+            Some("127.0.0.1:1024".parse().unwrap()),
+            health.leader_address
+        );
+
+        assert_eq!(None, health.clock_offset);
+
+        state.draining.store(true, Ordering::SeqCst);
+        let response = srv.get("/health/ready").send().await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        let response = srv.get("/health/live").send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+    }
 }
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+use actix_web::error::InternalError;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::HttpResponse;
+
+use crate::config::Limits;
+use crate::metrics;
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+/// 令牌桶数量超过该值时清理已经装满的桶，避免大量不同来源IP占用内存
+const MAX_BUCKETS: usize = 10000;
+
+/// 每日配额统计的调用方数量上限，超过后移除用量最少的调用方
+const MAX_QUOTA_CLIENTS: usize = MAX_BUCKETS;
+
+/// 令牌桶，[rate]为每秒放入的令牌数
+struct Bucket {
+    tokens: f64,
+    updated: i64,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: i64) -> f64 {
+        let elapsed = (now - self.updated).max(0) as f64;
+        self.tokens = (self.tokens + elapsed * rate / 1000.0).min(burst);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+/// 取出[key]对应的桶，令牌不足[count]个时返回需要等待的毫秒数。
+/// [count]超过桶的容量时桶满即可取出，扣减后令牌为负数，之后的请求等待相应的时间
+fn take<'a>(
+    buckets: &'a mut HashMap<String, Bucket>,
+    key: &str,
+    rate: f64,
+    burst: f64,
+    count: f64,
+    now: i64,
+) -> Result<&'a mut Bucket, i64> {
+    if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+        buckets.retain(|_, bucket| bucket.refill(rate, burst, now) < burst);
+    }
+    let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+        tokens: burst,
+        updated: now,
+    });
+    let tokens = bucket.refill(rate, burst, now);
+    let wanted = count.min(burst);
+    if tokens >= wanted {
+        Ok(bucket)
+    } else {
+        Err(((wanted - tokens) * 1000.0 / rate).ceil() as i64)
+    }
+}
+
+/// 请求被拒绝的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    /// 超过单个调用方的速率
+    Client,
+    /// 超过ID序列的速率
+    Dataset,
+    /// 超过每日配额
+    Quota,
+}
+
+impl Display for Limited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Limited::Client => "client",
+            Limited::Dataset => "dataset",
+            Limited::Quota => "quota",
+        })
+    }
+}
+
+struct Inner {
+    clients: HashMap<String, Bucket>,
+    datasets: HashMap<String, Bucket>,
+    /// 当前配额统计的日期（UTC天数）和各调用方已经使用的数量
+    day: i64,
+    used: HashMap<String, u64>,
+}
+
+/// 按调用方和ID序列限流，以及调用方的每日配额
+pub struct RateLimiter {
+    limits: Limits,
+    inner: Mutex<Inner>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &Limits) -> Self {
+        RateLimiter {
+            limits: limits.clone(),
+            inner: Mutex::new(Inner {
+                clients: HashMap::new(),
+                datasets: HashMap::new(),
+                day: 0,
+                used: HashMap::new(),
+            }),
+        }
+    }
+
+    /// 桶的容量，没有配置时为一秒的令牌数
+    fn burst(burst: Option<u32>, rate: f64) -> f64 {
+        match burst {
+            Some(burst) => burst as f64,
+            None => rate.ceil(),
+        }
+    }
+
+    /// 获取[count]个ID的许可，被拒绝时返回原因和需要等待的毫秒数。
+    /// 所有限制都满足时才扣减，避免被一个限制拒绝的请求消耗其他限制的令牌，也不会只扣减一部分
+    pub fn acquire(
+        &self,
+        client: &str,
+        dataset: &str,
+        count: u64,
+        now: i64,
+    ) -> Result<(), (Limited, i64)> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        if let Some(quota) = self.limits.daily_quota {
+            let day = now.div_euclid(DAY_MILLIS);
+            if inner.day != day {
+                inner.day = day;
+                inner.used.clear();
+            }
+            if inner.used.get(client).copied().unwrap_or_default() + count > quota {
+                return Err((Limited::Quota, (day + 1) * DAY_MILLIS - now));
+            }
+        }
+        let client_bucket = match self.limits.rate_limit {
+            Some(rate) => Some(
+                take(
+                    &mut inner.clients,
+                    client,
+                    rate,
+                    Self::burst(self.limits.rate_burst, rate),
+                    count as f64,
+                    now,
+                )
+                .map_err(|wait| (Limited::Client, wait))?,
+            ),
+            None => None,
+        };
+        let dataset_bucket = match self.limits.dataset_rate_limit {
+            Some(rate) => Some(
+                take(
+                    &mut inner.datasets,
+                    dataset,
+                    rate,
+                    Self::burst(self.limits.dataset_rate_burst, rate),
+                    count as f64,
+                    now,
+                )
+                .map_err(|wait| (Limited::Dataset, wait))?,
+            ),
+            None => None,
+        };
+        for bucket in client_bucket.into_iter().chain(dataset_bucket) {
+            bucket.tokens -= count as f64;
+        }
+        if self.limits.daily_quota.is_some() {
+            if inner.used.len() >= MAX_QUOTA_CLIENTS && !inner.used.contains_key(client) {
+                let least = inner
+                    .used
+                    .iter()
+                    .min_by_key(|(_, used)| **used)
+                    .map(|(client, _)| client.clone());
+                if let Some(least) = least {
+                    inner.used.remove(&least);
+                }
+            }
+            *inner.used.entry(client.to_string()).or_default() += count;
+        }
+        Ok(())
+    }
+
+    /// 获取[count]个ID的许可，被拒绝时返回带有 `Retry-After` 的429响应
+    pub fn check(
+        &self,
+        client: &str,
+        dataset: &str,
+        count: u64,
+        now: i64,
+    ) -> actix_web::Result<()> {
+        self.acquire(client, dataset, count, now)
+            .map_err(|(limited, wait)| {
+                metrics::RATE_LIMITED
+                    .with_label_values(&[dataset, &limited.to_string()])
+                    .inc();
+                let message = format!("{} rate limit exceeded", limited);
+                let seconds = ((wait + 999) / 1000).max(1);
+                let response = HttpResponse::TooManyRequests()
+                    .header(RETRY_AFTER, seconds.to_string())
+                    .body(message.clone());
+                InternalError::from_response(message, response).into()
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Limits;
+    use crate::server::limit::{Limited, RateLimiter};
+
+    #[test]
+    fn buckets_and_quota() {
+        let limiter = RateLimiter::new(&Limits {
+            rate_limit: Some(2.0),
+            dataset_rate_limit: Some(3.0),
+            ..Limits::default()
+        });
+        assert!(limiter.acquire("a", "default", 1, 0).is_ok());
+        assert!(limiter.acquire("a", "default", 1, 0).is_ok());
+        assert_eq!(
+            Err((Limited::Client, 500)),
+            limiter.acquire("a", "default", 1, 0)
+        );
+        assert!(limiter.acquire("b", "default", 1, 0).is_ok());
+        // 被序列限流拒绝的请求不消耗调用方的令牌
+        assert_eq!(
+            Err((Limited::Dataset, 334)),
+            limiter.acquire("c", "default", 1, 0)
+        );
+        assert!(limiter.acquire("c", "other", 1, 0).is_ok());
+        assert!(limiter.acquire("c", "other", 1, 0).is_ok());
+        assert!(limiter.acquire("a", "default", 1, 500).is_ok());
+
+        let limiter = RateLimiter::new(&Limits {
+            daily_quota: Some(2),
+            ..Limits::default()
+        });
+        let now = 10 * super::DAY_MILLIS + 1000;
+        assert!(limiter.acquire("a", "default", 1, now).is_ok());
+        assert!(limiter.acquire("a", "default", 1, now).is_ok());
+        assert_eq!(
+            Err((Limited::Quota, super::DAY_MILLIS - 1000)),
+            limiter.acquire("a", "default", 1, now)
+        );
+        assert!(limiter.acquire("b", "default", 1, now).is_ok());
+        assert!(limiter
+            .acquire("a", "default", 1, now + super::DAY_MILLIS)
+            .is_ok());
+    }
+
+    #[test]
+    fn many() {
+        let limiter = RateLimiter::new(&Limits {
+            rate_limit: Some(2.0),
+            rate_burst: Some(4),
+            ..Limits::default()
+        });
+        // 令牌不足时一个也不扣减
+        assert!(limiter.acquire("b", "default", 3, 0).is_ok());
+        assert_eq!(
+            Err((Limited::Client, 500)),
+            limiter.acquire("b", "default", 2, 0)
+        );
+        assert!(limiter.acquire("b", "default", 1, 0).is_ok());
+
+        // 超过桶容量的请求在桶满时可以通过，之后的请求等待补足
+        assert!(limiter.acquire("a", "default", 5, 0).is_ok());
+        assert_eq!(
+            Err((Limited::Client, 1000)),
+            limiter.acquire("a", "default", 1, 0)
+        );
+
+        let limiter = RateLimiter::new(&Limits {
+            daily_quota: Some(3),
+            ..Limits::default()
+        });
+        assert!(limiter.acquire("c", "default", 4, 0).is_err());
+        assert!(limiter.acquire("c", "default", 3, 0).is_ok());
+        assert!(limiter.acquire("c", "default", 1, 0).is_err());
+    }
+
+    #[test]
+    fn dataset_burst() {
+        // 序列的桶默认容量为一秒的令牌数，不使用调用方的 rate_burst
+        let limiter = RateLimiter::new(&Limits {
+            rate_limit: Some(100.0),
+            rate_burst: Some(1),
+            dataset_rate_limit: Some(3.0),
+            ..Limits::default()
+        });
+        for client in ["a", "b", "c"].iter() {
+            assert!(limiter.acquire(client, "default", 1, 0).is_ok());
+        }
+        assert_eq!(
+            Err((Limited::Dataset, 334)),
+            limiter.acquire("d", "default", 1, 0)
+        );
+
+        let limiter = RateLimiter::new(&Limits {
+            dataset_rate_limit: Some(1.0),
+            dataset_rate_burst: Some(2),
+            ..Limits::default()
+        });
+        assert!(limiter.acquire("a", "default", 1, 0).is_ok());
+        assert!(limiter.acquire("b", "default", 1, 0).is_ok());
+        assert!(limiter.acquire("c", "default", 1, 0).is_err());
+    }
+
+    #[test]
+    fn bounded_quota() {
+        let limiter = RateLimiter::new(&Limits {
+            daily_quota: Some(2),
+            ..Limits::default()
+        });
+        assert!(limiter.acquire("busy", "default", 1, 0).is_ok());
+        assert!(limiter.acquire("busy", "default", 1, 0).is_ok());
+        for i in 0..super::MAX_QUOTA_CLIENTS + 10 {
+            assert!(limiter.acquire(&i.to_string(), "default", 1, 0).is_ok());
+        }
+        assert_eq!(
+            super::MAX_QUOTA_CLIENTS,
+            limiter.inner.lock().unwrap().used.len()
+        );
+        // 移除用量最少的调用方，用完配额的调用方仍然被拒绝
+        assert!(limiter.acquire("busy", "default", 1, 0).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use std::future::{ready, Future};
+use std::time::Instant;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{get, web, Error, HttpResponse, Result};
+use futures::FutureExt;
+
+use crate::metrics;
+use crate::server::ext::Actix;
+use crate::server::AppState;
+
+/// Prometheus 文本格式的监控指标
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    responses((status = 200, content_type = "text/plain", body = String))
+)]
+#[get("/metrics")]
+pub async fn scrape(state: web::Data<AppState>) -> Result<HttpResponse> {
+    {
+        let nodes = state.nodes.read().actix()?;
+        metrics::MEMBERS.set(nodes.nodes.len() as i64);
+    }
+    metrics::CLOCK_OFFSET.set(state.clock.offset().unwrap_or_default());
+    let body = metrics::gather().actix()?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(body))
+}
+
+/// 统计leader变化，只计入从一个已知leader切换到另一个leader，首次发现leader不计入
+pub fn leader_changed(before: Option<u16>, after: Option<u16>) {
+    if let (Some(before), Some(after)) = (before, after) {
+        if before != after {
+            metrics::LEADER_CHANGES.inc();
+        }
+    }
+}
+
+/// 记录请求耗时，用于 `App::wrap_fn`
+pub fn observe<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let path = req
+        .match_pattern()
+        .unwrap_or_else(|| String::from("unmatched"));
+    srv.call(req).then(move |res| {
+        let status = match &res {
+            Ok(res) => res.status().as_u16().to_string(),
+            Err(err) => err.as_response_error().status_code().as_u16().to_string(),
+        };
+        metrics::REQUEST_DURATION
+            .with_label_values(&[&method, &path, &status])
+            .observe(start.elapsed().as_secs_f64());
+        ready(res)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::web::Buf;
+    use actix_web::{test, web, App};
+
+    use crate::cluster::Node;
+    use crate::config::logger;
+    use crate::metrics;
+    use crate::server::metrics::{leader_changed, observe, scrape};
+    use crate::server::AppState;
+
+    #[actix_rt::test]
+    async fn scrape_metrics() {
+        logger::init(true);
+        let srv = test::start(|| {
+            let state = web::Data::new(AppState::default());
+            state
+                .nodes
+                .write()
+                .unwrap()
+                .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+                .join(Node::new(1, "127.0.0.1:1025".parse().unwrap()));
+            App::new().wrap_fn(observe).app_data(state).service(scrape)
+        });
+        let _ = srv.get("/metrics").send().await.unwrap();
+        let mut response = srv.get("/metrics").send().await.unwrap();
+        assert!(response.status().is_success());
+        let body = response.body().await.unwrap();
+        let text = String::from_utf8(body.bytes().to_vec()).unwrap();
+        assert!(text.contains("idgener_cluster_members 2"));
+        assert!(text.contains(
+            r#"idgener_http_request_duration_seconds_count{method="GET",path="/metrics",status="200"}"#
+        ));
+    }
+
+    #[test]
+    fn count_leader_change() {
+        let before = metrics::LEADER_CHANGES.get();
+        leader_changed(Some(1), Some(2));
+        assert!(metrics::LEADER_CHANGES.get() > before);
+    }
+}
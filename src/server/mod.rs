@@ -1,26 +1,111 @@
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicI64};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::sync::broadcast;
 
 pub use server::embedded;
 
-use crate::cluster::Nodes;
-use crate::generator::Snowflake;
+use crate::cluster::{Clock, Federation, Fencing, Identity, Nodes, Nonces, Store};
+use crate::generator::{AuditLog, Snowflake};
+use crate::server::auth::Auth;
+use crate::server::limit::RateLimiter;
+use crate::server::stream::Streams;
 
+mod admin;
+mod audit;
+mod auth;
+mod datacenters;
 mod generator;
 mod health;
+mod limit;
+mod metrics;
+pub mod negotiate;
 mod nodes;
+mod openapi;
+mod resp;
 mod routers;
+#[allow(clippy::module_inception)]
 mod server;
+mod stream;
+mod tls;
+mod trace;
 
 pub struct AppState {
     pub snowflake: RwLock<Option<Snowflake>>,
     pub nodes: RwLock<Nodes>,
+    pub cluster: Identity,
+    /// 已处理过的节点请求nonce
+    pub nonces: Mutex<Nonces>,
+    pub fencing: Fencing,
+    pub clock: Clock,
+    /// 正在退出集群或者被管理员设置为维护状态，不再发放ID
+    pub draining: AtomicBool,
+    /// 最后一次成功keep-alive的时间，0表示还没有
+    pub last_keep_alive: AtomicI64,
+    /// 节点间请求的超时时间
+    pub timeout: Duration,
+    /// 本机公布的主机名，通过[crate::cluster::Node::host]让其他节点使用主机名连接本机
+    pub advertise_host: Option<String>,
+    /// 管理接口的访问令牌，[None]时关闭管理接口
+    pub admin_token: Option<String>,
+    /// 多数据中心联邦，没有配置数据中心ID时为[None]
+    pub federation: Option<RwLock<Federation>>,
+    /// 数据中心之间请求的签名密钥，没有设置时不签名
+    pub federation_secret: Option<String>,
+    /// 集群状态文件，没有配置[data_dir]时为[None]
+    pub store: Option<Store>,
+    /// ID发放审计日志，没有开启时为[None]
+    pub audit: Option<AuditLog>,
+    /// 接口认证，没有配置时不检查
+    pub auth: Option<Auth>,
+    /// 限流和每日配额，没有配置时不限制
+    pub limiter: Option<RateLimiter>,
+    /// 服务退出的通知，推送ID的流收到后结束
+    pub stopper: Option<Arc<broadcast::Sender<u64>>>,
+    pub streams: Streams,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        AppState::new(Identity::default())
+    }
 }
 
 impl AppState {
-    pub fn default() -> Self {
+    pub fn new(cluster: Identity) -> Self {
         AppState {
             snowflake: RwLock::new(None),
             nodes: RwLock::new(Nodes::default()),
+            cluster,
+            nonces: Mutex::new(Nonces::default()),
+            fencing: Fencing::disabled(),
+            clock: Clock::disabled(),
+            draining: AtomicBool::new(false),
+            last_keep_alive: AtomicI64::new(0),
+            timeout: Duration::from_millis(700),
+            advertise_host: None,
+            admin_token: None,
+            federation: None,
+            federation_secret: None,
+            store: None,
+            audit: None,
+            auth: None,
+            limiter: None,
+            stopper: None,
+            streams: Streams::default(),
+        }
+    }
+
+    /// 本数据中心的ID是否和其他数据中心冲突
+    pub fn datacenter_conflicted(&self) -> bool {
+        match &self.federation {
+            Some(federation) => federation
+                .read()
+                .map(|federation| federation.is_conflicted(Utc::now().timestamp_millis()))
+                .unwrap_or(true),
+            None => false,
         }
     }
 }
@@ -0,0 +1,206 @@
+use actix_http::body::{Body, ResponseBody};
+use actix_web::dev::{HttpResponseBuilder, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use actix_web::http::HeaderMap;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use futures::{Future, FutureExt};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// 响应支持的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `text/plain`，结构化数据使用YAML
+    Text,
+    /// `application/json`
+    Json,
+    /// `application/octet-stream`，只有ID支持，大端序的8个字节
+    Binary,
+}
+
+impl Format {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Text => "text/plain; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Binary => "application/octet-stream",
+        }
+    }
+
+    fn matches(&self, range: &str) -> bool {
+        match range {
+            "*/*" => true,
+            "text/*" | "text/plain" => *self == Format::Text,
+            "application/*" => *self != Format::Text,
+            "application/json" => *self == Format::Json,
+            "application/octet-stream" => *self == Format::Binary,
+            _ => false,
+        }
+    }
+}
+
+/// 按照 `Accept` 的权重选择[supported]中的格式，没有 `Accept` 时使用第一个，都不支持时返回[None]
+pub fn preferred(accept: Option<&str>, supported: &[Format]) -> Option<Format> {
+    let accept = match accept.map(str::trim).filter(|accept| !accept.is_empty()) {
+        Some(accept) => accept,
+        None => return supported.first().copied(),
+    };
+    let mut ranges = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((range, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect::<Vec<_>>();
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    ranges.iter().find_map(|(range, _)| {
+        supported
+            .iter()
+            .find(|format| format.matches(range))
+            .copied()
+    })
+}
+
+/// 请求接受的格式，都不支持时返回406
+pub fn format(req: &HttpRequest, supported: &[Format]) -> actix_web::Result<Format> {
+    preferred(accept(req.headers()), supported).ok_or_else(|| {
+        let supported = supported
+            .iter()
+            .map(|format| format.mime())
+            .collect::<Vec<_>>()
+            .join(", ");
+        actix_web::error::ErrorNotAcceptable(format!("supported: {}", supported))
+    })
+}
+
+fn accept(headers: &HeaderMap) -> Option<&str> {
+    headers.get(ACCEPT).and_then(|value| value.to_str().ok())
+}
+
+/// 按照 `Accept` 输出结构化数据，没有 `Accept` 或者 `*/*` 时输出JSON，`text/plain` 输出YAML，
+/// 和ID一样按照[format]的规则，都不支持时返回406
+pub fn respond<T: Serialize>(
+    req: &HttpRequest,
+    mut builder: HttpResponseBuilder,
+    value: &T,
+) -> actix_web::Result<HttpResponse> {
+    let format = format(req, &[Format::Json, Format::Text])?;
+    let body = match format {
+        Format::Text => {
+            serde_yaml::to_string(value).map_err(actix_web::error::ErrorInternalServerError)?
+        }
+        _ => serde_json::to_string(value)?,
+    };
+    Ok(builder.content_type(format.mime()).body(body))
+}
+
+/// 错误的响应体
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Failure {
+    pub error: String,
+}
+
+/// 按照 `Accept` 输出错误，默认和 `text/plain` 保持文本，JSON输出[Failure]。用于 `App::wrap_fn`
+pub fn errors<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let json =
+        preferred(accept(req.headers()), &[Format::Text, Format::Json]) == Some(Format::Json);
+    srv.call(req).map(move |res| {
+        let res = res?;
+        let error = match res.response().error() {
+            Some(error) if json => error.to_string(),
+            _ => return Ok(res),
+        };
+        Ok(res.map_body(move |head, _| {
+            head.headers
+                .insert(CONTENT_TYPE, HeaderValue::from_static(Format::Json.mime()));
+            let body = serde_json::to_string(&Failure { error }).unwrap_or_default();
+            ResponseBody::Other(Body::from(body))
+        }))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::header::{ACCEPT, CONTENT_TYPE};
+    use actix_web::http::StatusCode;
+    use actix_web::{get, test, App, HttpResponse};
+
+    use crate::server::negotiate::{errors, preferred, Failure, Format};
+
+    #[test]
+    fn accept() {
+        let all = [Format::Text, Format::Json, Format::Binary];
+        assert_eq!(Some(Format::Text), preferred(None, &all));
+        assert_eq!(
+            Some(Format::Json),
+            preferred(Some("application/json"), &all)
+        );
+        assert_eq!(
+            Some(Format::Binary),
+            preferred(Some("text/plain;q=0.5, application/octet-stream"), &all)
+        );
+        assert_eq!(
+            Some(Format::Json),
+            preferred(
+                Some("text/html, application/*;q=0.9"),
+                &[Format::Json, Format::Text]
+            )
+        );
+        assert_eq!(Some(Format::Text), preferred(Some("*/*"), &all));
+        // 结构化数据默认JSON
+        let structured = [Format::Json, Format::Text];
+        assert_eq!(Some(Format::Json), preferred(None, &structured));
+        assert_eq!(Some(Format::Json), preferred(Some("*/*"), &structured));
+        assert_eq!(None, preferred(Some("text/html"), &all));
+        assert_eq!(
+            None,
+            preferred(
+                Some("application/octet-stream"),
+                &[Format::Json, Format::Text]
+            )
+        );
+        assert_eq!(
+            None,
+            preferred(Some("application/json;q=0"), &[Format::Json])
+        );
+    }
+
+    #[get("/failed")]
+    async fn failed() -> actix_web::Result<HttpResponse> {
+        Err(actix_web::error::ErrorServiceUnavailable("draining"))
+    }
+
+    #[actix_rt::test]
+    async fn error_body() {
+        let mut app = test::init_service(App::new().wrap_fn(errors).service(failed)).await;
+        let req = test::TestRequest::get().uri("/failed").to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("draining", test::read_body(response).await);
+
+        let req = test::TestRequest::get()
+            .uri("/failed")
+            .header(ACCEPT, "application/json")
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!(
+            "application/json",
+            response.headers().get(CONTENT_TYPE).unwrap()
+        );
+        let failure: Failure = test::read_body_json(response).await;
+        assert_eq!("draining", failure.error);
+    }
+}
@@ -1,76 +1,656 @@
-
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 
-
-use crate::cluster::{Node};
-use actix_web::{get, post, web, HttpResponse, Result, Scope};
-use chrono::Local;
+use crate::cluster::{
+    Change, Delta, Identity, Node, Nodes, Signature, Snapshot, Update, DEFAULT_CLUSTER,
+};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Result, Scope};
+use chrono::{Local, Utc};
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+use opentelemetry::KeyValue;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
+use crate::server::admin;
+use crate::server::metrics;
+use crate::server::negotiate;
+use crate::server::server::replicate;
 use crate::server::{ext::Actix, AppState};
+use crate::trace;
 
 pub fn route() -> Scope {
-    Scope::new("/nodes").service(all).service(join)
+    Scope::new("/nodes")
+        .service(all)
+        .service(admin::route())
+        .service(join)
+        .service(heartbeat)
+        .service(handoff)
+        .service(membership)
+        .service(leave)
 }
 
+/// 集群成员快照，所有节点上相同版本的输出一致
+#[utoipa::path(
+    get,
+    path = "/api/nodes",
+    tag = "nodes",
+    security(("bearer" = []), ("api_key" = [])),
+    responses((status = 200, body = Snapshot))
+)]
 #[get("")]
-pub async fn all(data: web::Data<AppState>) -> Result<HttpResponse> {
+pub async fn all(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
     let nodes = &*data.nodes.read().actix()?;
-    Ok(HttpResponse::Ok().json(nodes))
+    negotiate::respond(&req, HttpResponse::Ok(), &nodes.snapshot())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct JoinInfo {
+    /// 节点对外公布的地址，和监听地址可以不同
+    #[schema(value_type = String)]
     pub address: SocketAddr,
+    /// 节点公布的主机名，[address]为加入时解析的结果
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
     pub current_id: Option<u16>,
     pub nodes: Option<Vec<Node>>,
+    /// 集群名称，密钥由请求签名校验
+    #[serde(default)]
+    pub cluster: Option<String>,
+    /// 请求时为follower的成员版本，响应时为leader的成员版本
+    #[serde(default)]
+    pub version: Option<u64>,
+    /// leader 响应的成员变更
+    #[serde(default)]
+    pub membership: Option<Update>,
+}
+
+impl JoinInfo {
+    pub fn new(cluster: &Identity, address: SocketAddr, current_id: Option<u16>) -> Self {
+        JoinInfo {
+            address,
+            host: None,
+            current_id,
+            nodes: None,
+            cluster: Some(cluster.name.clone()),
+            version: None,
+            membership: None,
+        }
+    }
+
+    /// 校验是否是[cluster]集群的节点
+    pub fn verify(&self, cluster: &Identity) -> Result<(), String> {
+        cluster.verify_name(self.cluster.as_deref().unwrap_or(DEFAULT_CLUSTER))
+    }
+}
+
+impl From<JoinInfo> for actix_http::body::Body {
+    fn from(val: JoinInfo) -> Self {
+        actix_http::body::Body::from(serde_json::to_string(&val).expect("serialize JoinInfo"))
+    }
+}
+
+impl From<JoinInfo> for http_client::Body {
+    fn from(val: JoinInfo) -> Self {
+        http_client::Body::from_json(&val).unwrap()
+    }
+}
+
+/// 校验节点间请求的签名和重放，集群没有设置密钥时不校验
+pub fn authenticate(req: &HttpRequest, body: &[u8], state: &AppState) -> Result<()> {
+    authenticate_with(req, body, state, state.cluster.secret())
+}
+
+/// 使用[secret]校验请求的签名和重放，[secret]为[None]时不校验。
+/// 响应只有签名的JSON，请求不接受JSON时在修改状态之前返回406
+pub fn authenticate_with(
+    req: &HttpRequest,
+    body: &[u8],
+    state: &AppState,
+    secret: Option<&str>,
+) -> Result<()> {
+    negotiate::format(req, &[negotiate::Format::Json])?;
+    let secret = match secret {
+        Some(secret) => secret,
+        None => return Ok(()),
+    };
+    let checked = Signature::from_headers(|name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    })
+    .and_then(|signature| {
+        signature.verify(secret, req.method().as_str(), req.path(), body)?;
+        state
+            .nonces
+            .lock()
+            .map_err(|err| err.to_string())?
+            .check(&signature, Utc::now().timestamp_millis())
+    });
+    if let Err(err) = checked {
+        log::warn!(
+            "reject unauthenticated request {} from {:?}: {}",
+            req.path(),
+            req.peer_addr(),
+            err
+        );
+        return Err(actix_web::error::ErrorUnauthorized(err));
+    }
+    Ok(())
+}
+
+/// 把[body]序列化为发送给节点的响应，集群设置了密钥时使用请求的nonce签名，
+/// 节点据此确认响应来自持有密钥的节点并且对应自己的请求
+pub fn signed<T: Serialize>(
+    req: &HttpRequest,
+    state: &AppState,
+    status: StatusCode,
+    body: &T,
+) -> Result<HttpResponse> {
+    signed_with(req, state.cluster.secret(), status, body)
+}
+
+/// 使用[secret]签名响应，[secret]为[None]时不签名。
+/// 签名的响应只有JSON格式，请求不接受JSON时按照[negotiate::format]返回406
+pub fn signed_with<T: Serialize>(
+    req: &HttpRequest,
+    secret: Option<&str>,
+    status: StatusCode,
+    body: &T,
+) -> Result<HttpResponse> {
+    let format = negotiate::format(req, &[negotiate::Format::Json])?;
+    let body = serde_json::to_vec(body)?;
+    let mut resp = HttpResponse::build(status);
+    resp.content_type(format.mime());
+    if let Some(secret) = secret {
+        let request = Signature::from_headers(|name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        })
+        .map_err(actix_web::error::ErrorUnauthorized)?;
+        let signature =
+            Signature::sign_response(secret, &request, status.as_u16(), req.path(), &body);
+        for (name, value) in signature.headers().iter() {
+            resp.header(*name, value.as_str());
+        }
+    }
+    Ok(resp.body(body))
+}
+
+/// leader 移交消息，退出的leader发送给集群中的其他节点
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Handoff {
+    /// 退出的leader
+    pub from: u16,
+    /// 继任的leader
+    pub leader: u16,
+    pub term: u64,
+    /// 集群名称，密钥由请求签名校验
+    #[serde(default)]
+    pub cluster: Option<String>,
 }
 
-impl Into<actix_http::body::Body> for JoinInfo {
-    fn into(self) -> actix_http::body::Body {
-        actix_http::body::Body::from(serde_json::to_string(&self).expect("serialize JoinInfo"))
+impl Handoff {
+    pub fn new(cluster: &Identity, from: u16, leader: u16, term: u64) -> Self {
+        Handoff {
+            from,
+            leader,
+            term,
+            cluster: Some(cluster.name.clone()),
+        }
     }
+
+    /// 校验是否是[cluster]集群的节点
+    pub fn verify(&self, cluster: &Identity) -> Result<(), String> {
+        cluster.verify_name(self.cluster.as_deref().unwrap_or(DEFAULT_CLUSTER))
+    }
+}
+
+/// leader 推送给follower的成员状态
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Replicate {
+    pub leader: u16,
+    pub term: u64,
+    pub update: Update,
 }
 
-impl Into<http_client::Body> for JoinInfo {
-    fn into(self) -> http_client::Body {
-        http_client::Body::from_json(&self).unwrap()
+pub fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    serde_json::from_slice::<T>(body)
+        .map_err(|err| actix_web::error::ErrorBadRequest(err.to_string()))
+}
+
+/// 读取节点发送的[JoinInfo]，并校验集群信息和请求签名
+fn node_request(req: &HttpRequest, body: &[u8], state: &AppState) -> Result<JoinInfo> {
+    let info = parse::<JoinInfo>(body)?;
+    if let Err(err) = info.verify(&state.cluster) {
+        log::warn!("reject node {}: {}", &info.address, err);
+        return Err(actix_web::error::ErrorForbidden(err));
     }
+    authenticate(req, body, state)?;
+    Ok(info)
 }
 
+/// 新节点加入集群，已经加入的节点重复加入时返回原来的ID
+#[utoipa::path(
+    post,
+    path = "/api/nodes",
+    tag = "cluster",
+    request_body = JoinInfo,
+    responses(
+        (status = 200, body = JoinInfo),
+        (status = 403, description = "invalid signature or cluster, or node evicted"),
+        (status = 409, description = "not leader or node id in use"),
+    )
+)]
 #[post("")]
-pub async fn join(body: web::Json<JoinInfo>, state: web::Data<AppState>) -> Result<HttpResponse> {
-    let mut nodes = state.nodes.write().unwrap();
+pub async fn join(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let mut body = node_request(&req, &body, &state)?;
+    // 公布的IP不确定时使用请求的来源IP
+    if body.address.ip().is_unspecified() {
+        if let Some(peer) = req.peer_addr() {
+            body.address.set_ip(peer.ip());
+        }
+    }
+    let cx = trace::start("join", SpanKind::Internal);
+    cx.span()
+        .set_attribute(KeyValue::new("node.address", body.address.to_string()));
+
+    let mut nodes = state.nodes.write().actix()?;
     if nodes.self_is_none() {
         return Err(actix_web::error::ErrorInternalServerError("not ready"));
     }
+    let version = body.version.unwrap_or(0);
 
     if let Some(node) = nodes.get_node_by_address(&body.address) {
-        log::debug!("node keep-alive: [{}]:{}", &node.id, &node.address);
+        log::info!("node rejoin: [{}]:{}", &node.id, &node.address);
         node.last_alive_timestamp = Local::now().timestamp_millis();
-        return Ok(HttpResponse::Ok().body(node.id.to_string()));
+        let id = node.id;
+        cx.span().set_attribute(KeyValue::new("node.id", id as i64));
+        let info = membership_info(&state, &nodes, id, version);
+        return signed(&req, &state, StatusCode::OK, &info);
+    }
+
+    if !nodes.self_is_leader() {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "not leader, leader is {:?}",
+            nodes.get_leader().map(|leader| leader.address)
+        )));
+    }
+
+    if let Some(tombstone) = nodes.tombstone(&body.address, body.current_id) {
+        log::warn!(
+            "reject evicted node [{}]:{}, evicted in term {}",
+            tombstone.id,
+            tombstone.address,
+            tombstone.term
+        );
+        return Err(actix_web::error::ErrorForbidden(format!(
+            "node [{}]:{} was evicted in term {}",
+            tombstone.id, tombstone.address, tombstone.term
+        )));
     }
 
     log::info!("not found node: {}, joining", &body.address);
 
-    let new_id = body.current_id.or(Some(nodes.new_node_id())).unwrap();
+    // 重启的节点使用保存的ID加入时，ID可能已经分配给了其他节点
+    let new_id = nodes
+        .assign_id(&body.address, body.current_id)
+        .map_err(actix_web::error::ErrorConflict)?;
 
-    nodes.join(Node::new(new_id, body.address));
+    cx.span()
+        .set_attribute(KeyValue::new("node.id", new_id as i64));
+    let node = Node::new(new_id, body.address).with_host(body.host.clone());
+    let delta = nodes.record(Change::Join(node));
+    let info = membership_info(&state, &nodes, new_id, version);
+    actix_web::rt::spawn(replicate(state.clone(), vec![delta]));
+    signed(&req, &state, StatusCode::OK, &info)
+}
+
+/// leader 对join的响应，携带[version]之后的成员变更
+fn membership_info(state: &AppState, nodes: &Nodes, id: u16, version: u64) -> JoinInfo {
+    let leader = nodes.get_leader().unwrap();
+    let mut info = JoinInfo::new(&state.cluster, leader.address, Some(id));
+    info.host = leader.host.clone();
+    info.version = Some(nodes.version());
+    info.membership = Some(nodes.update_since(version));
+    info
+}
 
-    Ok(HttpResponse::Ok().body(JoinInfo {
-        address: nodes.get_leader().unwrap().address,
-        current_id: Some(new_id),
-        nodes: Some(nodes.nodes.clone()),
-    }))
+/// follower 定期发送给leader的心跳
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Heartbeat {
+    #[schema(value_type = String)]
+    pub address: SocketAddr,
+    /// 集群名称，密钥由请求签名校验
+    #[serde(default)]
+    pub cluster: Option<String>,
+    /// follower 的成员版本
+    pub version: u64,
+    /// follower 的任期，和leader不同时需要完整快照，旧版本的节点没有该字段
+    #[serde(default)]
+    pub term: Option<u64>,
+    /// 发送时间，用于估算节点之间的时钟偏差
+    pub sent_at: i64,
+}
+
+impl Heartbeat {
+    pub fn new(cluster: &Identity, address: SocketAddr, version: u64, term: u64) -> Self {
+        Heartbeat {
+            address,
+            cluster: Some(cluster.name.clone()),
+            version,
+            term: Some(term),
+            sent_at: Utc::now().timestamp_millis(),
+        }
+    }
+
+    /// 校验是否是[cluster]集群的节点
+    pub fn verify(&self, cluster: &Identity) -> Result<(), String> {
+        cluster.verify_name(self.cluster.as_deref().unwrap_or(DEFAULT_CLUSTER))
+    }
+}
+
+/// leader 对心跳的响应
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct HeartbeatAck {
+    pub leader: Option<u16>,
+    pub term: u64,
+    /// leader 的成员版本
+    pub version: u64,
+    /// follower 落后时携带的成员变更
+    #[serde(default)]
+    pub membership: Option<Update>,
+    /// leader 收到心跳的时间
+    pub received_at: i64,
+    /// leader 发送响应的时间
+    pub sent_at: i64,
+}
+
+/// follower 的心跳，leader响应落后的成员变更
+#[utoipa::path(
+    put,
+    path = "/api/nodes/{id}/heartbeat",
+    tag = "cluster",
+    params(("id" = u16, Path, description = "节点ID")),
+    request_body = Heartbeat,
+    responses(
+        (status = 200, body = HeartbeatAck),
+        (status = 403, description = "invalid signature or cluster"),
+        (status = 404, description = "node not found"),
+        (status = 409, description = "not leader"),
+    )
+)]
+#[put("/{id}/heartbeat")]
+pub async fn heartbeat(
+    req: HttpRequest,
+    id: web::Path<u16>,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let received_at = Utc::now().timestamp_millis();
+    let id = id.into_inner();
+    let beat = parse::<Heartbeat>(&body)?;
+    if let Err(err) = beat.verify(&state.cluster) {
+        log::warn!("reject node {}: {}", &beat.address, err);
+        return Err(actix_web::error::ErrorForbidden(err));
+    }
+    authenticate(&req, &body, &state)?;
+
+    let mut nodes = state.nodes.write().actix()?;
+    if !nodes.self_is_leader() {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "not leader, leader is {:?}",
+            nodes.get_leader().map(|leader| leader.address)
+        )));
+    }
+    match nodes.get_node_by_address(&beat.address) {
+        Some(node) if node.id == id => {
+            log::debug!("node keep-alive: [{}]:{}", &node.id, &node.address);
+            node.last_alive_timestamp = Local::now().timestamp_millis();
+        }
+        _ => {
+            return Err(actix_web::error::ErrorNotFound(format!(
+                "not found node [{}]:{}",
+                id, beat.address
+            )))
+        }
+    }
+    state
+        .last_keep_alive
+        .store(Utc::now().timestamp_millis(), Ordering::SeqCst);
+
+    let update = match beat.term {
+        Some(term) if term != nodes.term() => Some(Update::Snapshot(nodes.snapshot())),
+        _ if beat.version < nodes.version() => Some(nodes.update_since(beat.version)),
+        _ => None,
+    };
+    let ack = HeartbeatAck {
+        leader: nodes.get_leader().map(|leader| leader.id),
+        term: nodes.term(),
+        version: nodes.version(),
+        membership: update,
+        received_at,
+        sent_at: Utc::now().timestamp_millis(),
+    };
+    signed(&req, &state, StatusCode::OK, &ack)
+}
+
+/// 节点退出集群，leader需要先通过[handoff]移交leader身份
+#[utoipa::path(
+    delete,
+    path = "/api/nodes/{id}",
+    tag = "cluster",
+    params(("id" = u16, Path, description = "节点ID")),
+    request_body = JoinInfo,
+    responses(
+        (status = 200, body = Node),
+        (status = 404, description = "node not found"),
+        (status = 409, description = "leader must hand off first, or not leader"),
+    )
+)]
+#[delete("/{id}")]
+pub async fn leave(
+    req: HttpRequest,
+    id: web::Path<u16>,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let info = node_request(&req, &body, &state)?;
+    let id = id.into_inner();
+    if info.current_id != Some(id) {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "node {:?} can't leave for {}",
+            info.current_id, id
+        )));
+    }
+
+    let mut nodes = state.nodes.write().actix()?;
+    if nodes.is_leader(id) {
+        return Err(actix_web::error::ErrorConflict(
+            "leader must hand off before leaving",
+        ));
+    }
+    if !nodes.self_is_leader() {
+        return Err(actix_web::error::ErrorConflict("not leader"));
+    }
+    match nodes.get(id).cloned() {
+        Some(node) => {
+            log::info!("node leave: [{}]:{}", node.id, node.address);
+            let delta = nodes.record(Change::Leave(id));
+            actix_web::rt::spawn(replicate(state.clone(), vec![delta]));
+            signed(&req, &state, StatusCode::OK, &node)
+        }
+        None => Err(actix_web::error::ErrorNotFound(format!(
+            "not found node {}",
+            id
+        ))),
+    }
+}
+
+/// 接收退出的leader移交的leader身份
+#[utoipa::path(
+    put,
+    path = "/api/nodes/leader",
+    tag = "cluster",
+    request_body = Handoff,
+    responses(
+        (status = 200, body = u64, content_type = "application/json", description = "接受移交后的任期"),
+        (status = 400, description = "successor is the leaving leader"),
+        (status = 403, description = "invalid signature or cluster"),
+        (status = 404, description = "node not found"),
+        (status = 409, description = "stale term or not from leader"),
+    )
+)]
+#[put("/leader")]
+pub async fn handoff(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let handoff = parse::<Handoff>(&body)?;
+    if let Err(err) = handoff.verify(&state.cluster) {
+        log::warn!("reject hand off from {}: {}", handoff.from, err);
+        return Err(actix_web::error::ErrorForbidden(err));
+    }
+    authenticate(&req, &body, &state)?;
+
+    let mut nodes = state.nodes.write().actix()?;
+    // 只有当前的leader可以移交，继任者是它环形顺序之后的其他节点
+    if nodes.leader_id() != Some(handoff.from) {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "node {} is not leader, leader is {:?}",
+            handoff.from,
+            nodes.leader_id()
+        )));
+    }
+    if handoff.leader == handoff.from {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "leader {} can't hand off to itself",
+            handoff.from
+        )));
+    }
+    if handoff.term <= nodes.term() {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "stale term {}, current {}",
+            handoff.term,
+            nodes.term()
+        )));
+    }
+    if !nodes
+        .successors(handoff.from)
+        .iter()
+        .any(|node| node.id == handoff.leader)
+    {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "not found node {}",
+            handoff.leader
+        )));
+    }
+    log::info!(
+        "leader {} hand off to {}, term: {}",
+        handoff.from,
+        handoff.leader,
+        handoff.term
+    );
+    // 旧leader由继任者记录的变更移除，follower不单独修改成员，避免成员版本不一致
+    let before = nodes.leader_id();
+    nodes
+        .set_leader(Some(handoff.leader))
+        .set_term(handoff.term);
+    metrics::leader_changed(before, nodes.leader_id());
+    if nodes.self_is_leader() {
+        // 继任的leader把变更同步给所有follower，统一成员版本
+        let deltas = take_over(&mut nodes, handoff.from);
+        actix_web::rt::spawn(replicate(state.clone(), deltas));
+    }
+    signed(&req, &state, StatusCode::OK, &nodes.term())
+}
+
+/// 当前节点成为leader后记录旧leader退出和leader变更
+pub fn take_over(nodes: &mut Nodes, from: u16) -> Vec<Delta> {
+    let current = nodes.get_current().map(|node| node.id).unwrap();
+    let term = nodes.term();
+    vec![
+        nodes.record(Change::Leave(from)),
+        nodes.record(Change::Leader { id: current, term }),
+    ]
+}
+
+/// follower 接收leader推送的成员状态
+#[utoipa::path(
+    put,
+    path = "/api/nodes/membership",
+    tag = "cluster",
+    request_body = Replicate,
+    responses(
+        (status = 200, body = u64, content_type = "application/json", description = "应用后的成员版本"),
+        (status = 403, description = "invalid signature"),
+        (status = 409, description = "stale term, not leader or membership version behind"),
+    )
+)]
+#[put("/membership")]
+pub async fn membership(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    authenticate(&req, &body, &state)?;
+    let replicate = parse::<Replicate>(&body)?;
+
+    let mut nodes = state.nodes.write().actix()?;
+    if replicate.term < nodes.term() {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "stale term {}, current {}",
+            replicate.term,
+            nodes.term()
+        )));
+    }
+    // 同一任期只有一个leader，其他节点推送的成员状态不可信
+    match nodes.leader_id() {
+        Some(leader) if replicate.term == nodes.term() && leader != replicate.leader => {
+            return Err(actix_web::error::ErrorConflict(format!(
+                "node {} is not leader of term {}, leader is {}",
+                replicate.leader, replicate.term, leader
+            )));
+        }
+        _ => {}
+    }
+    let before = nodes.leader_id();
+    match nodes.apply(&replicate.update) {
+        Ok(changed) => {
+            if changed {
+                metrics::leader_changed(before, nodes.leader_id());
+                log::debug!(
+                    "apply membership from leader {}, version: {}",
+                    replicate.leader,
+                    nodes.version()
+                );
+            }
+            signed(&req, &state, StatusCode::OK, &nodes.version())
+        }
+        Err(version) => Err(actix_web::error::ErrorConflict(format!(
+            "membership version {} is behind",
+            version
+        ))),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::cluster::{Node};
+    use crate::cluster::{Change, Nodes, Tombstone, Update};
+    use crate::cluster::{Identity, Node, Signature};
     use crate::config::logger;
     use crate::generator::Snowflake;
-    use crate::server::nodes::{route, JoinInfo};
+    use crate::server::nodes::{route, Handoff, Heartbeat, HeartbeatAck, JoinInfo, Replicate};
     use crate::server::AppState;
+    use actix_web::http::header::ACCEPT;
+    use actix_web::http::StatusCode;
     use actix_web::test::TestServer;
     use actix_web::web::Buf;
     use actix_web::{test, web, App};
@@ -78,16 +658,42 @@ mod test {
 
     #[test]
     fn nodes_serialize() {
-        let out = JoinInfo {
-            address: "127.0.0.1:1024".parse().unwrap(),
-            current_id: Some(1),
-            nodes: Some(vec![Node::new(1, "127.0.0.1:1025".parse().unwrap())]),
-        };
+        let mut out = JoinInfo::new(
+            &Identity::default(),
+            "127.0.0.1:1024".parse().unwrap(),
+            Some(1),
+        );
+        out.nodes = Some(vec![Node::new(1, "127.0.0.1:1025".parse().unwrap())]);
         let json = serde_json::to_string_pretty(&out).unwrap();
         println!("json: {}", json);
 
         let info = serde_json::from_str::<JoinInfo>(json.as_str());
         print!("info: {:?}", info);
+        assert!(info.unwrap().verify(&Identity::default()).is_ok());
+
+        // 未携带集群信息的请求属于默认集群
+        let info = serde_json::from_str::<JoinInfo>(
+            r#"{"address": "127.0.0.1:1024", "current_id": null, "nodes": null}"#,
+        )
+        .unwrap();
+        assert!(info.verify(&Identity::default()).is_ok());
+        assert!(info.verify(&Identity::new("staging", None)).is_err());
+    }
+
+    fn start(cluster: Identity) -> TestServer {
+        start_with(web::Data::new(AppState::new(cluster)))
+    }
+
+    fn start_with(state: web::Data<AppState>) -> TestServer {
+        state
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+            .set_leader(Some(0))
+            .set_current(0);
+        let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+        test::start(move || App::new().app_data(state.clone()).service(route()))
     }
 
     async fn send(srv: &TestServer) {
@@ -95,11 +701,11 @@ mod test {
         let current_id = 3;
         let mut response = request
             .content_type("application/json")
-            .send_body(JoinInfo {
-                address: "127.0.1.1:1024".parse::<SocketAddr>().unwrap(),
-                current_id: Some(current_id),
-                nodes: None,
-            })
+            .send_body(JoinInfo::new(
+                &Identity::default(),
+                "127.0.1.1:1024".parse::<SocketAddr>().unwrap(),
+                Some(current_id),
+            ))
             .await
             .unwrap();
         assert!(
@@ -115,23 +721,457 @@ mod test {
         let info = info.unwrap();
         println!("body: {:?}", info);
         assert_eq!(Some(current_id), info.current_id);
+        assert!(info.membership.is_some());
     }
 
     #[actix_rt::test]
     async fn test_body() {
         logger::init(true);
-        let srv = test::start(|| {
-            let state = web::Data::new(AppState::default());
+        let srv = start(Identity::default());
+        send(&srv).await;
+    }
+
+    #[actix_rt::test]
+    async fn join_unspecified_address() {
+        logger::init(true);
+        let state = web::Data::new(AppState::default());
+        let srv = start_with(state.clone());
+        let response = srv
+            .post("/nodes")
+            .send_json(&JoinInfo::new(
+                &Identity::default(),
+                "0.0.0.0:1030".parse().unwrap(),
+                None,
+            ))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let nodes = state.nodes.read().unwrap();
+        let node = nodes.get(1).unwrap();
+        assert_eq!(
+            "127.0.0.1:1030".parse::<SocketAddr>().unwrap(),
+            node.address
+        );
+    }
+
+    /// 公布主机名的节点，其他节点使用主机名连接
+    #[actix_rt::test]
+    async fn join_with_host() {
+        logger::init(true);
+        let state = web::Data::new(AppState::default());
+        let srv = start_with(state.clone());
+        let mut info = JoinInfo::new(
+            &Identity::default(),
+            "127.0.0.1:1031".parse().unwrap(),
+            None,
+        );
+        info.host = Some(String::from("localhost"));
+        let response = srv.post("/nodes").send_json(&info).await.unwrap();
+        assert!(response.status().is_success());
+        let nodes = state.nodes.read().unwrap();
+        assert_eq!("localhost:1031", nodes.get(1).unwrap().endpoint());
+    }
+
+    #[actix_rt::test]
+    async fn reject_evicted() {
+        logger::init(true);
+        let state = web::Data::new(AppState::default());
+        let srv = start_with(state.clone());
+        let address = "127.0.1.1:1024".parse::<SocketAddr>().unwrap();
+        state
+            .nodes
+            .write()
+            .unwrap()
+            .record(Change::Evict(Tombstone {
+                id: 1,
+                address,
+                term: 0,
+            }));
+
+        let join = |address, current_id| {
+            srv.post("/nodes")
+                .send_json(&JoinInfo::new(&Identity::default(), address, current_id))
+        };
+        let response = join(address, None).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        let other = "127.0.1.2:1024".parse::<SocketAddr>().unwrap();
+        let response = join(other, Some(1)).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        state.nodes.write().unwrap().record(Change::Pardon(1));
+        let response = join(address, Some(1)).await.unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn reject_other_cluster() {
+        logger::init(true);
+        let srv = start(Identity::new("staging", Some("s3cret")));
+        for cluster in [Identity::default(), Identity::new("laptop", Some("s3cret"))] {
+            let response = srv
+                .post("/nodes")
+                .content_type("application/json")
+                .send_body(JoinInfo::new(
+                    &cluster,
+                    "127.0.1.1:1024".parse().unwrap(),
+                    None,
+                ))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::FORBIDDEN, response.status());
+        }
+
+        // 集群名称正确但是没有签名，密钥不在请求中传输
+        for cluster in [
+            Identity::new("staging", None),
+            Identity::new("staging", Some("s3cret")),
+        ] {
+            let response = srv
+                .post("/nodes")
+                .content_type("application/json")
+                .send_body(JoinInfo::new(
+                    &cluster,
+                    "127.0.1.1:1024".parse().unwrap(),
+                    None,
+                ))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn signed_join() {
+        logger::init(true);
+        let cluster = Identity::new("staging", Some("s3cret"));
+        let srv = start(cluster.clone());
+        let body = serde_json::to_vec(&JoinInfo::new(
+            &cluster,
+            "127.0.1.1:1024".parse().unwrap(),
+            None,
+        ))
+        .unwrap();
+
+        let signed = |signature: &Signature| {
+            let mut request = srv.post("/nodes").content_type("application/json");
+            for (name, value) in signature.headers().iter() {
+                request = request.header(*name, value.as_str());
+            }
+            request
+        };
+
+        let signature = Signature::sign("s3cret", "POST", "/nodes", &body);
+        let mut response = signed(&signature).send_body(body.clone()).await.unwrap();
+        assert!(response.status().is_success());
+        // 响应使用请求的nonce签名
+        let reply = Signature::from_headers(|name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        })
+        .unwrap();
+        let payload = response.body().await.unwrap();
+        assert!(reply
+            .verify_response("s3cret", &signature, 200, "/nodes", payload.bytes())
+            .is_ok());
+
+        // 重放相同的请求
+        let response = signed(&signature).send_body(body.clone()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        // 篡改请求内容
+        let signature = Signature::sign("s3cret", "POST", "/nodes", &body);
+        let tampered = JoinInfo::new(&cluster, "127.0.1.2:1024".parse().unwrap(), Some(0));
+        let response = signed(&signature).send_body(tampered).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let signature = Signature::sign("guess", "POST", "/nodes", &body);
+        let response = signed(&signature).send_body(body).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[actix_rt::test]
+    async fn leave() {
+        logger::init(true);
+        let state = web::Data::new(AppState::default());
+        let srv = start_with(state.clone());
+        state
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(1, "127.0.1.1:1024".parse().unwrap()));
+
+        let info = || {
+            JoinInfo::new(
+                &Identity::default(),
+                "127.0.1.1:1024".parse().unwrap(),
+                Some(1),
+            )
+        };
+        let response = srv.delete("/nodes/0").send_json(&info()).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        // 不接受JSON时不移除节点
+        let response = srv
+            .delete("/nodes/1")
+            .header(ACCEPT, "text/plain")
+            .send_json(&info())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_ACCEPTABLE, response.status());
+        assert!(state.nodes.read().unwrap().get(1).is_some());
+
+        let response = srv.delete("/nodes/1").send_json(&info()).await.unwrap();
+        assert!(response.status().is_success());
+        assert!(state.nodes.read().unwrap().get(1).is_none());
+
+        let response = srv.delete("/nodes/1").send_json(&info()).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let leader = JoinInfo::new(
+            &Identity::default(),
+            "127.0.0.1:1024".parse().unwrap(),
+            Some(0),
+        );
+        let response = srv.delete("/nodes/0").send_json(&leader).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+    }
+
+    #[actix_rt::test]
+    async fn heartbeat() {
+        logger::init(true);
+        let state = web::Data::new(AppState::default());
+        let srv = start_with(state.clone());
+        let address = "127.0.1.1:1024".parse::<SocketAddr>().unwrap();
+        let beat = |version| Heartbeat::new(&Identity::default(), address, version, 0);
+
+        let response = srv
+            .put("/nodes/1/heartbeat")
+            .send_json(&beat(0))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        {
+            let mut nodes = state.nodes.write().unwrap();
+            nodes.record(Change::Join(Node::new(1, address)));
+            nodes
+                .get_node_by_address(&address)
+                .unwrap()
+                .last_alive_timestamp = 0;
+        }
+        let mut response = srv
+            .put("/nodes/1/heartbeat")
+            .send_json(&beat(0))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let body = response.body().await.unwrap();
+        let ack = serde_json::from_slice::<HeartbeatAck>(body.bytes()).unwrap();
+        assert_eq!(Some(0), ack.leader);
+        assert_eq!(1, ack.version);
+        assert!(ack.membership.is_some());
+        assert!(ack.received_at <= ack.sent_at);
+        assert!(
             state
                 .nodes
-                .write()
+                .read()
                 .unwrap()
-                .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
-                .set_leader(Some(0))
-                .set_current(0);
-            let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
-            App::new().app_data(state).service(route())
-        });
-        send(&srv).await;
+                .get(1)
+                .unwrap()
+                .last_alive_timestamp
+                > 0
+        );
+
+        let mut response = srv
+            .put("/nodes/1/heartbeat")
+            .send_json(&beat(1))
+            .await
+            .unwrap();
+        let body = response.body().await.unwrap();
+        let ack = serde_json::from_slice::<HeartbeatAck>(body.bytes()).unwrap();
+        assert!(ack.membership.is_none());
+
+        // 任期不同的follower即使版本相同也需要完整快照
+        let mut response = srv
+            .put("/nodes/1/heartbeat")
+            .send_json(&Heartbeat::new(&Identity::default(), address, 1, 3))
+            .await
+            .unwrap();
+        let body = response.body().await.unwrap();
+        let ack = serde_json::from_slice::<HeartbeatAck>(body.bytes()).unwrap();
+        assert!(matches!(ack.membership, Some(Update::Snapshot(_))));
+
+        // 节点ID和地址不匹配
+        let response = srv
+            .put("/nodes/0/heartbeat")
+            .send_json(&beat(1))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let other = Heartbeat::new(&Identity::new("staging", None), address, 1, 0);
+        let response = srv
+            .put("/nodes/1/heartbeat")
+            .send_json(&other)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+    }
+
+    #[actix_rt::test]
+    async fn handoff() {
+        logger::init(true);
+        let state = web::Data::new(AppState::default());
+        let srv = start_with(state.clone());
+        state
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(1, "127.0.1.1:1024".parse().unwrap()))
+            .join(Node::new(2, "127.0.1.2:1024".parse().unwrap()))
+            .set_current(1);
+        let cluster = Identity::default();
+        let put = |handoff: Handoff| srv.put("/nodes/leader").send_json(&handoff);
+
+        let response = put(Handoff::new(&cluster, 0, 5, 1)).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        // 不是leader发出的移交
+        let response = put(Handoff::new(&cluster, 2, 1, 1)).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+
+        let response = put(Handoff::new(&cluster, 0, 0, 1)).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let other = Identity::new("staging", None);
+        let response = put(Handoff::new(&other, 0, 2, 1)).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert_eq!(0, state.nodes.read().unwrap().get_leader().unwrap().id);
+
+        let mut response = put(Handoff::new(&cluster, 0, 2, 1)).await.unwrap();
+        assert!(response.status().is_success());
+        let body = response.body().await.unwrap();
+        assert_eq!(1, serde_json::from_slice::<u64>(body.bytes()).unwrap());
+        {
+            let nodes = state.nodes.read().unwrap();
+            assert_eq!(2, nodes.get_leader().unwrap().id);
+            assert_eq!(1, nodes.term());
+            // follower 等待继任者推送旧leader退出的变更
+            assert!(nodes.get(0).is_some());
+        }
+
+        let response = put(Handoff::new(&cluster, 2, 1, 1)).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+    }
+
+    #[actix_rt::test]
+    async fn signed_handoff() {
+        logger::init(true);
+        let cluster = Identity::new("staging", Some("s3cret"));
+        let state = web::Data::new(AppState::new(cluster.clone()));
+        let srv = start_with(state.clone());
+        state
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(1, "127.0.1.1:1024".parse().unwrap()))
+            .set_current(1);
+
+        let body = serde_json::to_vec(&Handoff::new(&cluster, 0, 1, 1)).unwrap();
+        let response = srv
+            .put("/nodes/leader")
+            .content_type("application/json")
+            .send_body(body.clone())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let signature = Signature::sign("s3cret", "PUT", "/nodes/leader", &body);
+        let mut request = srv.put("/nodes/leader").content_type("application/json");
+        for (name, value) in signature.headers().iter() {
+            request = request.header(*name, value.as_str());
+        }
+        let response = request.send_body(body).await.unwrap();
+        assert!(response.status().is_success());
+        let nodes = state.nodes.read().unwrap();
+        assert!(nodes.self_is_leader());
+        assert!(nodes.get(0).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn replicate_membership() {
+        logger::init(true);
+        let mut leader = Nodes::default();
+        leader
+            .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+            .set_leader(Some(0))
+            .set_current(0);
+        leader.record(Change::Join(Node::new(
+            1,
+            "127.0.1.1:1024".parse().unwrap(),
+        )));
+
+        let state = web::Data::new(AppState::default());
+        state.nodes.write().unwrap().set_current(1);
+        let srv = test::start(move || App::new().app_data(state.clone()).service(route()));
+
+        let term = leader.term();
+        let push = |update: Update| Replicate {
+            leader: 0,
+            term,
+            update,
+        };
+        let response = srv
+            .put("/nodes/membership")
+            .send_json(&push(leader.update_since(0)))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        // 同一任期其他节点推送的成员状态
+        let response = srv
+            .put("/nodes/membership")
+            .send_json(&Replicate {
+                leader: 1,
+                term,
+                update: leader.update_since(0),
+            })
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+
+        // 缺少中间的变更
+        let version = leader.version();
+        leader.record(Change::Join(Node::new(
+            2,
+            "127.0.1.2:1024".parse().unwrap(),
+        )));
+        leader.record(Change::Leave(2));
+        let last = match leader.update_since(version + 1) {
+            Update::Deltas(deltas) => Update::Deltas(deltas),
+            Update::Snapshot(_) => unreachable!(),
+        };
+        let response = srv
+            .put("/nodes/membership")
+            .send_json(&push(last))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+
+        let response = srv
+            .put("/nodes/membership")
+            .send_json(&push(leader.update_since(version)))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let mut response = srv.get("/nodes").send().await.unwrap();
+        let body = response.body().await.unwrap();
+        assert_eq!(
+            serde_json::to_string(&leader.snapshot()).unwrap(),
+            String::from_utf8(body.to_vec()).unwrap()
+        );
     }
 }
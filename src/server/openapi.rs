@@ -0,0 +1,224 @@
+use actix_web::{get, HttpRequest, HttpResponse, Result};
+use utoipa::openapi::content::Content;
+use utoipa::openapi::response::Response;
+use utoipa::openapi::schema::{ObjectBuilder, Ref, Type};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::RefOr;
+use utoipa::{Modify, OpenApi};
+
+use crate::server::auth::HEADER_API_KEY;
+use crate::server::negotiate::{self, Failure};
+use crate::server::{admin, audit, datacenters, generator, health, metrics, nodes, stream};
+
+lazy_static::lazy_static! {
+    static ref SPEC: utoipa::openapi::OpenApi = Api::openapi();
+}
+
+/// 由各个接口上的 `#[utoipa::path]` 和请求、响应类型的 `ToSchema` 生成的 OpenAPI 文档
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "idgener", description = "Distributed snowflake id generator"),
+    paths(
+        generator::snowflake,
+        generator::decode,
+        stream::events,
+        stream::add_credits,
+        stream::websocket,
+        nodes::all,
+        nodes::join,
+        nodes::heartbeat,
+        nodes::leave,
+        nodes::handoff,
+        nodes::membership,
+        admin::dump,
+        admin::change_leader,
+        admin::evict,
+        admin::reserve,
+        admin::release,
+        admin::pardon,
+        admin::drain,
+        audit::lookup,
+        datacenters::view,
+        datacenters::register,
+        health::health,
+        health::live,
+        health::ready,
+        metrics::scrape,
+        document,
+    ),
+    components(schemas(Failure)),
+    modifiers(&Security, &Negotiation),
+    tags(
+        (name = "generation", description = "获取和解析ID"),
+        (name = "nodes", description = "集群成员"),
+        (name = "admin", description = "管理接口，使用管理令牌或者有admin权限的凭证"),
+        (name = "datacenters", description = "多数据中心"),
+        (name = "cluster", description = "节点间和数据中心间的接口，请求和响应使用密钥签名"),
+        (name = "health", description = "健康检查和监控"),
+    )
+)]
+struct Api;
+
+/// 认证方式，见[crate::server::auth]
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(HEADER_API_KEY))),
+        );
+    }
+}
+
+/// 按照[crate::server::negotiate]补充响应格式：结构化数据都可以输出YAML，错误可以输出[Failure]，
+/// 都不接受时返回406。节点间的接口只使用签名的JSON
+struct Negotiation;
+
+impl Modify for Negotiation {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let text = |description: &str| {
+            let schema = ObjectBuilder::new()
+                .schema_type(Type::String)
+                .description(Some(description))
+                .build();
+            Content::new(Some(schema))
+        };
+        for item in openapi.paths.paths.values_mut() {
+            let operations = vec![
+                &mut item.get,
+                &mut item.put,
+                &mut item.post,
+                &mut item.delete,
+            ];
+            for operation in operations.into_iter().flatten() {
+                let negotiated = operation
+                    .responses
+                    .responses
+                    .iter()
+                    .any(|(status, response)| {
+                        status.starts_with('2')
+                            && matches!(response, RefOr::T(response)
+                            if response.content.contains_key("application/json"))
+                    });
+                if negotiated {
+                    operation
+                        .responses
+                        .responses
+                        .entry(String::from("406"))
+                        .or_insert_with(|| {
+                            RefOr::T(Response::new("none of the accepted formats is supported"))
+                        });
+                }
+                let signed = operation
+                    .tags
+                    .as_ref()
+                    .is_some_and(|tags| tags.iter().any(|tag| tag == "cluster"));
+                for (status, response) in operation.responses.responses.iter_mut() {
+                    let response = match response {
+                        RefOr::T(response) => response,
+                        RefOr::Ref(_) => continue,
+                    };
+                    if status.starts_with(['4', '5']) && response.content.is_empty() {
+                        let failure = Content::new(Some(Ref::from_schema_name("Failure")));
+                        response
+                            .content
+                            .insert(String::from("application/json"), failure);
+                        response
+                            .content
+                            .insert(String::from("text/plain"), text("error message"));
+                    } else if !signed
+                        && response.content.contains_key("application/json")
+                        && !response.content.contains_key("text/plain")
+                    {
+                        response
+                            .content
+                            .insert(String::from("text/plain"), text("YAML"));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// OpenAPI 文档，不需要认证
+#[utoipa::path(
+    get,
+    path = "/api/openapi.json",
+    tag = "health",
+    responses((status = 200, content_type = "application/json", body = Object))
+)]
+#[get("/openapi.json")]
+pub async fn document(req: HttpRequest) -> Result<HttpResponse> {
+    negotiate::respond(&req, HttpResponse::Ok(), &*SPEC)
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    #[actix_rt::test]
+    async fn document() {
+        let mut app = test::init_service(
+            App::new().service(actix_web::Scope::new("/api").service(super::document)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/api/openapi.json")
+            .to_request();
+        let spec: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!("3.1.0", spec["openapi"]);
+        for path in [
+            "/api/g/snowflake",
+            "/api/g/snowflake/{id}",
+            "/api/g/stream/{id}/credits",
+            "/api/nodes",
+            "/api/nodes/{id}/heartbeat",
+            "/api/nodes/admin/state",
+            "/api/nodes/admin/reservations/{id}",
+            "/api/nodes/admin/audit/{id}",
+            "/api/datacenters",
+            "/health/ready",
+            "/metrics",
+        ] {
+            assert!(spec["paths"][path].is_object(), "{}", path);
+        }
+        let content = |path: &str, method: &str, status: &str| {
+            spec["paths"][path][method]["responses"][status]["content"].clone()
+        };
+        // 结构化响应可以输出YAML，节点间的接口只有JSON
+        assert!(content("/api/datacenters", "get", "200")["text/plain"].is_object());
+        assert!(content("/api/datacenters", "post", "200")["text/plain"].is_null());
+        assert!(content("/api/g/snowflake", "get", "200")["application/octet-stream"].is_object());
+        // 结构化响应和节点间的接口都按照 `Accept` 协商，不支持时返回406
+        for (path, method) in [
+            ("/api/nodes", "get"),
+            ("/api/nodes/{id}", "delete"),
+            ("/api/nodes/membership", "put"),
+            ("/api/g/snowflake/{id}", "get"),
+        ] {
+            assert!(
+                content(path, method, "406")["text/plain"].is_object(),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(
+            "#/components/schemas/Failure",
+            content("/api/nodes/admin/audit/{id}", "get", "404")["application/json"]["schema"]
+                ["$ref"]
+        );
+        // 所有引用的schema都已经定义
+        let text = spec.to_string();
+        for reference in text.split("#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(spec["components"]["schemas"][name].is_object(), "{}", name);
+        }
+    }
+}
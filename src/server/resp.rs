@@ -0,0 +1,502 @@
+use std::net::SocketAddr;
+
+use actix_web::web;
+use anyhow::{bail, Context};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+use crate::config::Scope;
+use crate::server::auth::Principal;
+use crate::server::generator::{issue_many, DATASET};
+use crate::server::health::{check, HealthState};
+use crate::server::AppState;
+
+/// 一个命令最多的参数数量和单个参数的最大长度
+const MAX_ARGS: usize = 64;
+const MAX_BULK: usize = 64 * 1024;
+
+/// `IDGEN` 一次最多获取的ID数量，和一毫秒内的序列号数量相同
+const MAX_COUNT: usize = 4096;
+
+/// RESP 的响应
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Reply::Error(err) => {
+                // 错误信息不能包含换行
+                let err = err.replace(&['\r', '\n'][..], " ");
+                out.extend_from_slice(format!("-{}\r\n", err).as_bytes())
+            }
+            Reply::Integer(value) => out.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+            Reply::Bulk(value) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<String>> {
+    let mut line = String::new();
+    if (&mut *reader)
+        .take(MAX_BULK as u64)
+        .read_line(&mut line)
+        .await?
+        == 0
+    {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        bail!("line too long");
+    }
+    Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+}
+
+/// 读取一个命令，支持RESP数组和telnet使用的inline命令，连接关闭时返回[None]
+async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix('*') {
+        Some(count) => count.parse::<usize>().context("invalid multibulk length")?,
+        None => {
+            return Ok(Some(
+                line.split_whitespace()
+                    .map(|arg| arg.as_bytes().to_vec())
+                    .collect(),
+            ))
+        }
+    };
+    if count > MAX_ARGS {
+        bail!("too many arguments");
+    }
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader)
+            .await?
+            .context("unexpected end of command")?;
+        let len = line
+            .strip_prefix('$')
+            .context("expected '$'")?
+            .parse::<usize>()
+            .context("invalid bulk length")?;
+        if len > MAX_BULK {
+            bail!("invalid bulk length");
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// 一个客户端连接，配置了认证时需要先执行 `AUTH`
+struct Connection {
+    data: web::Data<AppState>,
+    peer: SocketAddr,
+    principal: Option<Principal>,
+}
+
+impl Connection {
+    fn authenticated(&self) -> bool {
+        self.data.auth.is_none() || self.principal.is_some()
+    }
+
+    /// 审计日志和限流使用的调用方，认证后为调用方名称，否则为来源IP
+    fn caller(&self) -> String {
+        match &self.principal {
+            Some(principal) => principal.id.clone(),
+            None => self.peer.ip().to_string(),
+        }
+    }
+
+    /// 执行命令，返回响应和是否关闭连接
+    fn execute(&mut self, args: &[Vec<u8>]) -> (Reply, bool) {
+        let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let args = &args[1..];
+        if !self.authenticated() && !matches!(command.as_str(), "AUTH" | "QUIT") {
+            return (
+                Reply::Error(String::from("NOAUTH Authentication required.")),
+                false,
+            );
+        }
+        let reply = match command.as_str() {
+            "QUIT" => return (Reply::Status("OK"), true),
+            "AUTH" => self.auth(args),
+            "PING" => self.ping(args),
+            "INFO" => self.info(),
+            "IDGEN" => self.idgen(args),
+            "INCR" => self.incr(args),
+            "INCRBY" => self.incrby(args),
+            // 连接池在连接建立后可能发送的命令
+            "SELECT" | "CLIENT" => Reply::Status("OK"),
+            "COMMAND" => Reply::Array(vec![]),
+            _ => Reply::Error(format!("ERR unknown command '{}'", command)),
+        };
+        (reply, false)
+    }
+
+    /// `AUTH [username] password`，密码为API key或者token
+    fn auth(&mut self, args: &[Vec<u8>]) -> Reply {
+        let auth = match &self.data.auth {
+            Some(auth) => auth,
+            None => {
+                return Reply::Error(String::from(
+                    "ERR AUTH called without any password configured",
+                ))
+            }
+        };
+        let credential = match args {
+            [password] | [_, password] => String::from_utf8_lossy(password),
+            _ => return wrong_arguments("auth"),
+        };
+        match auth.authenticate(&credential) {
+            Some(principal) => {
+                self.principal = Some(principal);
+                Reply::Status("OK")
+            }
+            None => Reply::Error(String::from(
+                "WRONGPASS invalid username-password pair or user is disabled.",
+            )),
+        }
+    }
+
+    /// 就绪检查，节点可以发放ID时返回 `PONG`
+    fn ping(&self, args: &[Vec<u8>]) -> Reply {
+        match check(&self.data) {
+            Ok(health) if health.state == HealthState::Running => match args {
+                [] => Reply::Status("PONG"),
+                [message] => Reply::Bulk(message.clone()),
+                _ => wrong_arguments("ping"),
+            },
+            Ok(health) => Reply::Error(format!("ERR node is {:?}", health.state)),
+            Err(err) => Reply::Error(format!("ERR {}", err)),
+        }
+    }
+
+    /// 节点的健康状态，格式和redis的INFO相同
+    fn info(&self) -> Reply {
+        let health = match check(&self.data) {
+            Ok(health) => health,
+            Err(err) => return Reply::Error(format!("ERR {}", err)),
+        };
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let info = [
+            String::from("# Server"),
+            format!("idgener_version:{}", env!("CARGO_PKG_VERSION")),
+            String::from("redis_mode:standalone"),
+            String::from("# Cluster"),
+            format!("state:{:?}", health.state),
+            format!("node_id:{}", health.id),
+            format!(
+                "leader:{}",
+                optional(health.leader.map(|id| id.to_string()))
+            ),
+            format!(
+                "leader_address:{}",
+                optional(health.leader_address.map(|address| address.to_string()))
+            ),
+            format!("term:{}", health.term),
+            format!("members:{}", health.members),
+            format!(
+                "keep_alive_age:{}",
+                optional(health.keep_alive_age.map(|age| age.to_string()))
+            ),
+            format!(
+                "clock_offset:{}",
+                optional(health.clock_offset.map(|offset| offset.to_string()))
+            ),
+        ];
+        Reply::Bulk(format!("{}\r\n", info.join("\r\n")).into_bytes())
+    }
+
+    /// `IDGEN dataset [count]`，没有count时返回一个整数，否则返回整数数组
+    fn idgen(&self, args: &[Vec<u8>]) -> Reply {
+        let (dataset, count) = match args {
+            [dataset] => (String::from_utf8_lossy(dataset), None),
+            [dataset, count] => match parse_count(count) {
+                Ok(count) => (String::from_utf8_lossy(dataset), Some(count)),
+                Err(reply) => return reply,
+            },
+            _ => return wrong_arguments("idgen"),
+        };
+        if dataset != DATASET {
+            return Reply::Error(format!("ERR unknown dataset {}", dataset));
+        }
+        match (self.generate(&dataset, count.unwrap_or(1)), count) {
+            (Ok(ids), Some(_)) => Reply::Array(ids.into_iter().map(Reply::Integer).collect()),
+            (Ok(ids), None) => Reply::Integer(ids[0]),
+            (Err(reply), _) => reply,
+        }
+    }
+
+    /// `INCR key`，兼容原来用 `INCR` 获取ID的客户端。所有的key共用 `default` 数据集，返回的ID单调递增但不连续
+    fn incr(&self, args: &[Vec<u8>]) -> Reply {
+        match args {
+            [_] => self.last(1),
+            _ => wrong_arguments("incr"),
+        }
+    }
+
+    /// `INCRBY key count`，发放count个ID并返回最后一个。ID不连续，客户端不能把 `[id-count+1, id]` 当作已分配的号段
+    fn incrby(&self, args: &[Vec<u8>]) -> Reply {
+        match args {
+            [_, count] => match parse_count(count) {
+                Ok(count) => self.last(count),
+                Err(reply) => reply,
+            },
+            _ => wrong_arguments("incrby"),
+        }
+    }
+
+    fn last(&self, count: usize) -> Reply {
+        match self.generate(DATASET, count) {
+            Ok(ids) => Reply::Integer(ids[count - 1]),
+            Err(reply) => reply,
+        }
+    }
+
+    /// 检查调用方的权限后发放count个ID，超过限流时一个也不发放
+    fn generate(&self, dataset: &str, count: usize) -> Result<Vec<i64>, Reply> {
+        if let Some(principal) = &self.principal {
+            if !principal.allows(Scope::Generate, Some(dataset)) {
+                return Err(Reply::Error(format!(
+                    "NOPERM {} is not allowed to generate {}",
+                    principal.id, dataset
+                )));
+            }
+        }
+        issue_many(&self.data, &self.caller(), "resp", count)
+            .map(|ids| ids.iter().map(|id| id.value() as i64).collect())
+            .map_err(|err| Reply::Error(format!("ERR {}", err)))
+    }
+}
+
+fn parse_count(count: &[u8]) -> Result<usize, Reply> {
+    match std::str::from_utf8(count).ok().and_then(|c| c.parse().ok()) {
+        Some(count) if (1..=MAX_COUNT).contains(&count) => Ok(count),
+        _ => Err(Reply::Error(format!(
+            "ERR count must be an integer between 1 and {}",
+            MAX_COUNT
+        ))),
+    }
+}
+
+fn wrong_arguments(command: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+async fn stopped(stopper: &mut Option<broadcast::Receiver<u64>>) {
+    match stopper {
+        Some(stopper) => {
+            let _ = stopper.recv().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    peer: SocketAddr,
+    data: web::Data<AppState>,
+) -> anyhow::Result<()> {
+    let mut stopper = data.stopper.as_ref().map(|stopper| stopper.subscribe());
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut connection = Connection {
+        data,
+        peer,
+        principal: None,
+    };
+    loop {
+        let command = tokio::select! {
+            command = read_command(&mut reader) => command,
+            _ = stopped(&mut stopper) => return Ok(()),
+        };
+        let (reply, close) = match command {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => connection.execute(&args),
+            Ok(None) => return Ok(()),
+            Err(err) => (Reply::Error(format!("ERR Protocol error: {}", err)), true),
+        };
+        let mut out = vec![];
+        reply.encode(&mut out);
+        writer.write_all(&out).await?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+/// 兼容redis协议（RESP）的接口，用于把 `INCR` 获取ID的服务切换到idgener。
+/// 只支持明文TCP，`AUTH` 的API key没有加密，所以开启TLS时不能启用
+pub async fn serve(
+    address: SocketAddr,
+    data: web::Data<AppState>,
+    mut stopper: broadcast::Receiver<u64>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("bind resp address {}", address))?;
+    log::info!("resp listen on {}", address);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let data = data.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle(stream, peer, data).await {
+                            log::debug!("resp connection {}: {}", peer, err);
+                        }
+                    });
+                }
+                Err(err) => log::warn!("resp accept: {}", err),
+            },
+            _ = stopper.recv() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use actix_web::web;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::sync::broadcast;
+
+    use crate::cluster::Node;
+    use crate::config::{ApiKey, AuthConfig, Scope};
+    use crate::generator::Snowflake;
+    use crate::server::auth::Auth;
+    use crate::server::resp::{read_command, serve};
+    use crate::server::AppState;
+
+    #[tokio::test]
+    async fn parse() {
+        let mut input = BufReader::new(&b"*2\r\n$4\r\nPING\r\n$2\r\nhi\r\nIDGEN default 2\r\n"[..]);
+        assert_eq!(
+            vec![b"PING".to_vec(), b"hi".to_vec()],
+            read_command(&mut input).await.unwrap().unwrap()
+        );
+        assert_eq!(
+            vec![b"IDGEN".to_vec(), b"default".to_vec(), b"2".to_vec()],
+            read_command(&mut input).await.unwrap().unwrap()
+        );
+        assert!(read_command(&mut input).await.unwrap().is_none());
+        let mut invalid = BufReader::new(&b"*1\r\n+PING\r\n"[..]);
+        assert!(read_command(&mut invalid).await.is_err());
+    }
+
+    async fn roundtrip(stream: &mut TcpStream, command: &str) -> String {
+        stream.write_all(command.as_bytes()).await.unwrap();
+        let mut buffer = vec![0; 4096];
+        let size = stream.read(&mut buffer).await.unwrap();
+        String::from_utf8(buffer[..size].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        let state = AppState {
+            auth: Some(Auth::new(&AuthConfig {
+                keys: vec![
+                    ApiKey {
+                        id: String::from("legacy"),
+                        key: "k1".parse().unwrap(),
+                        scopes: vec![Scope::Generate],
+                        datasets: None,
+                    },
+                    ApiKey {
+                        id: String::from("reader"),
+                        key: "k2".parse().unwrap(),
+                        scopes: vec![Scope::Decode],
+                        datasets: None,
+                    },
+                ],
+                token_secret: None,
+            })),
+            ..AppState::default()
+        };
+        state
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, "127.0.0.1:1024".parse().unwrap()))
+            .set_leader(Some(0))
+            .set_current(0);
+        let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (stopper, _) = broadcast::channel(1);
+        let server = tokio::spawn(serve(address, web::Data::new(state), stopper.subscribe()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        assert!(roundtrip(&mut stream, "PING\r\n")
+            .await
+            .starts_with("-NOAUTH"));
+        assert!(roundtrip(&mut stream, "AUTH wrong\r\n")
+            .await
+            .starts_with("-WRONGPASS"));
+        assert_eq!("+OK\r\n", roundtrip(&mut stream, "AUTH k1\r\n").await);
+        assert_eq!("+PONG\r\n", roundtrip(&mut stream, "PING\r\n").await);
+        assert!(roundtrip(&mut stream, "INFO\r\n")
+            .await
+            .contains("state:Running"));
+
+        let id = roundtrip(&mut stream, "*2\r\n$5\r\nIDGEN\r\n$7\r\ndefault\r\n").await;
+        assert!(id.starts_with(':') && id.ends_with("\r\n"));
+        let ids = roundtrip(&mut stream, "IDGEN default 3\r\n").await;
+        assert!(ids.starts_with("*3\r\n:"));
+        assert!(roundtrip(&mut stream, "IDGEN orders\r\n")
+            .await
+            .starts_with("-ERR unknown dataset"));
+        let id = |reply: String| reply[1..reply.len() - 2].parse::<i64>().unwrap();
+        let first = id(roundtrip(&mut stream, "INCR counter\r\n").await);
+        let last = id(roundtrip(&mut stream, "INCRBY counter 5\r\n").await);
+        assert!(last > first);
+        assert!(id(roundtrip(&mut stream, "INCR other\r\n").await) > last);
+        assert!(roundtrip(&mut stream, "INCRBY counter 0\r\n")
+            .await
+            .starts_with("-ERR count"));
+        assert!(roundtrip(&mut stream, "INCR\r\n")
+            .await
+            .starts_with("-ERR wrong number"));
+        assert_eq!("+OK\r\n", roundtrip(&mut stream, "AUTH k2\r\n").await);
+        assert!(roundtrip(&mut stream, "INCR counter\r\n")
+            .await
+            .starts_with("-NOPERM"));
+        assert_eq!("+OK\r\n", roundtrip(&mut stream, "QUIT\r\n").await);
+
+        stopper.send(0).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -1,10 +1,14 @@
 use actix_web::Scope;
 
+use crate::server::datacenters;
 use crate::server::generator;
 use crate::server::nodes;
+use crate::server::openapi;
 
 pub fn route() -> Scope {
     Scope::new("/api")
         .service(generator::route())
         .service(nodes::route())
+        .service(datacenters::route())
+        .service(openapi::document)
 }
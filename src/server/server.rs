@@ -2,109 +2,602 @@ use std::net::SocketAddr;
 use std::ops::Add;
 
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use actix_server::Server;
 use actix_web::middleware::{Compress, DefaultHeaders, Logger};
 use actix_web::rt::System as ActixSystem;
 use actix_web::{web, App, HttpServer};
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use chrono::Utc;
 
-use futures::executor::block_on;
-use futures::future::try_join_all;
+use async_native_tls::TlsConnector;
+use futures::future::{join_all, try_join_all};
+use futures::FutureExt;
 use http_client::h1::H1Client;
-use http_client::http_types::Mime;
+use http_client::http_types::{Method, Mime, StatusCode, Url};
 use http_client::{Config, HttpClient, Request};
 use lazy_static::lazy_static;
+use opentelemetry::trace::{FutureExt as _, SpanKind, TraceContextExt};
+use opentelemetry::KeyValue;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use tokio::sync::broadcast;
 use tokio::time::Instant;
 
-use crate::cluster::{multicast, Node};
+use crate::cluster::{
+    multicast, Clock, Datacenter, Delta, Federation, Fencing, Identity, Node, Persisted, Signature,
+    Snapshot, Store, Update, MAX_DATACENTER_NODE_ID,
+};
 use crate::config;
-use crate::generator::Snowflake;
-use crate::server::health::health;
-use crate::server::nodes::JoinInfo;
+use crate::config::logger;
+use crate::config::AuthConfig;
+use crate::generator::{AuditLog, Snowflake};
+use crate::server::auth::{self, Auth};
+use crate::server::limit::RateLimiter;
+use crate::server::nodes::{take_over, Handoff, Heartbeat, HeartbeatAck, JoinInfo, Replicate};
 use crate::server::routers::route;
+use crate::server::tls::{self, Certificates};
 use crate::server::AppState;
+use crate::server::{health, metrics, negotiate, resp, trace};
 
-fn bind(address: &SocketAddr, state: web::Data<AppState>) -> anyhow::Result<Server> {
+/// 启动http服务，[certificates]不为[None]时使用https
+fn bind(
+    address: &SocketAddr,
+    state: web::Data<AppState>,
+    certificates: Option<&Arc<Certificates>>,
+) -> anyhow::Result<Server> {
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(auth::Guard)
+            .wrap_fn(negotiate::errors)
             .wrap(DefaultHeaders::new().header("x-idgend-version", "0.2"))
             .wrap(Compress::default())
             .wrap(Logger::new("%a %r %s %b %T"))
+            .wrap_fn(metrics::observe)
+            .wrap_fn(trace::traced)
+            .wrap_fn(trace::request_id)
             .app_data(state.clone())
-            .service(web::resource("/health").to(health))
+            .service(health::route())
+            .service(metrics::scrape)
             .service(route())
     })
-    .workers(num_cpus::get() * 4)
-    .bind(address)
-    .context("bind address error")?
-    .run();
+    .workers(num_cpus::get() * 4);
+    let server = match certificates {
+        Some(certificates) => server.bind_openssl(address, certificates.acceptor()?),
+        None => server.bind(address),
+    };
+    let server = server.context("bind address error")?.run();
     Ok(server)
 }
 
 lazy_static! {
-    static ref CLIENT: H1Client = {
-        let mut client = H1Client::new();
-        client
-            .set_config(Config::default().set_timeout(Some(Duration::from_millis(700))))
-            .unwrap();
-        client
+    /// 节点间请求的客户端和创建时使用的证书，超时由调用方通过[exchange]控制
+    static ref CLIENT: RwLock<(Option<Arc<TlsConnector>>, Arc<H1Client>)> =
+        RwLock::new((None, Arc::new(client(None))));
+}
+
+/// 节点间请求的客户端，[tls::watch]重新加载证书后创建新的客户端
+fn http_client() -> Arc<H1Client> {
+    let connector = tls::connector();
+    let same = |cached: &Option<Arc<TlsConnector>>| match (cached, &connector) {
+        (Some(cached), Some(connector)) => Arc::ptr_eq(cached, connector),
+        (None, None) => true,
+        _ => false,
     };
+    {
+        let cached = CLIENT.read().unwrap();
+        if same(&cached.0) {
+            return cached.1.clone();
+        }
+    }
+    let mut cached = CLIENT.write().unwrap();
+    if !same(&cached.0) {
+        *cached = (connector.clone(), Arc::new(client(connector)));
+    }
+    cached.1.clone()
 }
 
-fn send_register(bind_address: SocketAddr, state: web::Data<AppState>) -> anyhow::Result<()> {
-    let mut nodes = state.nodes.write().expect("cloud get node read lock");
-    if nodes.self_is_leader() {
+/// 节点间请求的客户端，[connector]不为[None]时支持https并出示本机证书
+fn client(connector: Option<Arc<TlsConnector>>) -> H1Client {
+    let mut client = H1Client::new();
+    let config = Config::default().set_timeout(None);
+    client
+        .set_config(match connector {
+            Some(connector) => config.set_tls_config(Some(connector)),
+            None => config,
+        })
+        .unwrap();
+    client
+}
+
+/// 构建发送给其他节点的请求，集群设置了密钥时对请求签名
+fn cluster_request<T: Serialize>(
+    cluster: &Identity,
+    method: Method,
+    url: &str,
+    body: &T,
+) -> anyhow::Result<Request> {
+    signed_request(cluster.secret(), method, url, body)
+}
+
+/// 构建使用[secret]签名的请求，[secret]为[None]时不签名
+fn signed_request<T: Serialize>(
+    secret: Option<&str>,
+    method: Method,
+    url: &str,
+    body: &T,
+) -> anyhow::Result<Request> {
+    let url = Url::parse(url)?;
+    let body = serde_json::to_vec(body)?;
+    let mut req = Request::new(method, url.clone());
+    req.set_content_type(Mime::from_str("application/json").unwrap());
+    req.insert_header("Accept", "application/json");
+    if let Some(secret) = secret {
+        let signature = Signature::sign(secret, method.as_ref(), url.path(), &body);
+        for (name, value) in signature.headers().iter() {
+            req.insert_header(*name, value.as_str());
+        }
+    }
+    req.set_body(body);
+    Ok(req)
+}
+
+/// 节点间请求的响应，响应体已经在超时时间内读取完
+#[derive(Debug)]
+struct Reply {
+    status: StatusCode,
+    body: Vec<u8>,
+    path: String,
+    /// 请求的签名，响应签名需要使用相同的nonce
+    request: Option<Signature>,
+    signature: Result<Signature, String>,
+}
+
+impl Reply {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// 校验响应签名后解析响应体，[secret]为[None]时不校验
+    fn json<T: DeserializeOwned>(&self, secret: Option<&str>) -> anyhow::Result<T> {
+        if let Some(secret) = secret {
+            let request = self.request.as_ref().context("request is not signed")?;
+            self.signature
+                .as_ref()
+                .map_err(|err| err.clone())
+                .and_then(|signature| {
+                    signature.verify_response(
+                        secret,
+                        request,
+                        self.status.into(),
+                        &self.path,
+                        &self.body,
+                    )
+                })
+                .map_err(|err| anyhow!("reject unsigned response: {}", err))?;
+        }
+        serde_json::from_slice(&self.body).context("decode response")
+    }
+
+    /// 响应体文本，只用于日志和错误信息
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// 发送节点间请求并读取响应体，超过[timeout]没有读完时返回错误。通过 `traceparent` 传播当前span
+async fn exchange(mut req: Request, timeout: Duration) -> anyhow::Result<Reply> {
+    let url = req.url().clone();
+    let cx = crate::trace::start(format!("{} {}", req.method(), url.path()), SpanKind::Client);
+    cx.span()
+        .set_attribute(KeyValue::new("http.url", url.to_string()));
+    crate::trace::inject(&cx, &mut req);
+    let request = Signature::from_headers(|name| req.header(name).map(|v| v.last().as_str())).ok();
+    let reply = async {
+        let mut resp = http_client().send(req).await.map_err(|err| anyhow!(err))?;
+        let body = resp.body_bytes().await.map_err(|err| anyhow!(err))?;
+        let signature =
+            Signature::from_headers(|name| resp.header(name).map(|v| v.last().as_str()));
+        Ok(Reply {
+            status: resp.status(),
+            body,
+            path: url.path().to_string(),
+            request,
+            signature,
+        })
+    };
+    // handler 中推送成员变更时运行在actix的运行时上，不能使用tokio的定时器
+    let reply = async_std::future::timeout(timeout, reply)
+        .await
+        .map_err(|_| anyhow!("{} timed out after {:?}", url, timeout))
+        .and_then(|reply| reply);
+    match &reply {
+        Ok(reply) => cx.span().set_attribute(KeyValue::new(
+            "http.status_code",
+            reply.status() as u16 as i64,
+        )),
+        Err(err) => crate::trace::set_error(&cx, err),
+    }
+    reply
+}
+
+/// 发送节点间请求，非2xx响应作为错误返回
+async fn send(req: Request, timeout: Duration) -> anyhow::Result<Reply> {
+    let url = req.url().clone();
+    let reply = exchange(req, timeout).await?;
+    if !reply.status().is_success() {
+        bail!("{} response {}: {}", url, reply.status(), reply.text());
+    }
+    Ok(reply)
+}
+
+/// 退出集群。follower通知leader移除自己，leader先把leader身份移交给继任者，
+/// 这样其他节点不需要等待keep-alive超时再切换leader
+async fn leave(state: web::Data<AppState>) -> anyhow::Result<()> {
+    let (current, leader, successors, term) = {
+        let nodes = state.nodes.read().map_err(|err| anyhow!(err.to_string()))?;
+        let current = match nodes.get_current() {
+            Some(current) => current.clone(),
+            None => return Ok(()),
+        };
+        (
+            current.clone(),
+            nodes.get_leader().cloned(),
+            nodes.successors(current.id),
+            nodes.term(),
+        )
+    };
+
+    let leader = match leader {
+        Some(leader) => leader,
+        None => return Ok(()),
+    };
+    if leader.id != current.id {
+        log::info!("leave cluster, notify leader: {}", leader.address);
+        let req = cluster_request(
+            &state.cluster,
+            Method::Delete,
+            format!(
+                "{}://{}/api/nodes/{}",
+                tls::scheme(),
+                leader.endpoint(),
+                current.id
+            )
+            .as_str(),
+            &JoinInfo::new(&state.cluster, current.address, Some(current.id)),
+        )?;
+        // 响应由leader签名，确认已经被移出集群
+        let node = send(req, state.timeout)
+            .await?
+            .json::<Node>(state.cluster.secret())?;
+        log::info!("left cluster as [{}]:{}", node.id, node.address);
         return Ok(());
     }
-    log::debug!("send keep-alive");
 
-    let leader_address = nodes.get_leader().unwrap().address;
-    let mut req = Request::get(format!("http://{}/api/nodes", leader_address).as_str());
-    req.set_content_type(Mime::from_str("application/json").unwrap());
-    if let Some(current) = nodes.get_current() {
-        req.set_body(JoinInfo {
-            address: bind_address,
-            current_id: Some(current.id),
-            nodes: None,
-        });
-    } else {
-        req.set_body(JoinInfo {
-            address: bind_address,
-            current_id: None,
-            nodes: None,
-        });
+    // 按顺序挑选第一个接受移交的节点作为继任者，然后通知其他节点
+    let mut successor = None;
+    for node in successors.iter() {
+        let handoff = Handoff::new(
+            &state.cluster,
+            current.id,
+            successor.unwrap_or(node.id),
+            term + 1,
+        );
+        let req = cluster_request(
+            &state.cluster,
+            Method::Put,
+            format!("{}://{}/api/nodes/leader", tls::scheme(), node.endpoint()).as_str(),
+            &handoff,
+        )?;
+        let accepted = send(req, state.timeout)
+            .await
+            .and_then(|reply| reply.json::<u64>(state.cluster.secret()));
+        match accepted {
+            Ok(_) if successor.is_none() => {
+                log::info!("hand off leader to [{}]:{}", node.id, node.address);
+                successor = Some(node.id);
+            }
+            Ok(_) => log::debug!("notify [{}]:{} new leader", node.id, node.address),
+            Err(err) => log::warn!("hand off leader to [{}]:{}: {}", node.id, node.address, err),
+        }
+    }
+    if successor.is_none() && !successors.is_empty() {
+        bail!("no node accept leader hand off");
     }
+    Ok(())
+}
+
+/// leader 把成员变更推送给所有follower，follower版本不连续时改为推送完整快照
+pub async fn replicate(state: web::Data<AppState>, deltas: Vec<Delta>) {
+    let (leader, term, snapshot, followers) = {
+        let nodes = match state.nodes.read() {
+            Ok(nodes) => nodes,
+            Err(_) => return,
+        };
+        let leader = match nodes.get_current() {
+            Some(current) if nodes.self_is_leader() => current.id,
+            _ => return,
+        };
+        let followers = nodes
+            .nodes
+            .iter()
+            .filter(|node| node.id != leader)
+            .cloned()
+            .collect::<Vec<_>>();
+        (leader, nodes.term(), nodes.snapshot(), followers)
+    };
+    push_membership(state, leader, term, snapshot, followers, deltas).await
+}
 
-    let mut resp = block_on(CLIENT.send(req)).map_err(|err| anyhow!(err))?;
-    let info = block_on(resp.body_json::<JoinInfo>()).map_err(|err| anyhow!(err))?;
-    log::debug!("self id: {}", info.current_id.unwrap());
-    if nodes.self_is_none() {
-        let _ = init_self(&bind_address, state.clone(), info.current_id);
+/// 以[leader]的身份把成员变更推送给[followers]，当前节点不再是leader时也可以使用
+pub async fn push_membership(
+    state: web::Data<AppState>,
+    leader: u16,
+    term: u64,
+    snapshot: Snapshot,
+    followers: Vec<Node>,
+    deltas: Vec<Delta>,
+) {
+    let pushes = followers.into_iter().map(|node| {
+        let state = state.clone();
+        let deltas = deltas.clone();
+        let snapshot = snapshot.clone();
+        let id = node.id;
+        async move {
+            let url = format!(
+                "{}://{}/api/nodes/membership",
+                tls::scheme(),
+                node.endpoint()
+            );
+            let mut update = Update::Deltas(deltas);
+            for _ in 0..2 {
+                let body = Replicate {
+                    leader,
+                    term,
+                    update,
+                };
+                let req = cluster_request(&state.cluster, Method::Put, &url, &body)?;
+                let resp = exchange(req, state.timeout).await?;
+                if resp.status().is_success() {
+                    let version = resp.json::<u64>(state.cluster.secret())?;
+                    log::debug!("node [{}] membership version: {}", id, version);
+                    return Ok(());
+                }
+                if resp.status() != StatusCode::Conflict {
+                    bail!("{} response {}", url, resp.status());
+                }
+                log::debug!("node [{}]:{} need snapshot", node.id, node.address);
+                update = Update::Snapshot(snapshot.clone());
+            }
+            bail!("{} reject membership snapshot", url)
+        }
+        .map(move |out: anyhow::Result<()>| {
+            if let Err(err) = out {
+                log::warn!("replicate membership to [{}]: {}", id, err);
+            }
+        })
+    });
+    join_all(pushes).await;
+}
+
+/// 发送一次 keep-alive，网络请求期间不持有[AppState]中的锁。
+/// 还没有加入集群或者已经被leader移除时加入集群，否则发送心跳
+async fn send_register(
+    config: &config::KeepAlive,
+    advertise: SocketAddr,
+    state: web::Data<AppState>,
+    preferred: Option<u16>,
+) -> anyhow::Result<()> {
+    let cx = crate::trace::start("send_register", SpanKind::Internal);
+    cx.span()
+        .set_attribute(KeyValue::new("node.address", advertise.to_string()));
+    let result = keep_alive(config, advertise, state, preferred)
+        .with_context(cx.clone())
+        .await;
+    if let Err(err) = &result {
+        crate::trace::set_error(&cx, format!("{:#}", err));
     }
-    for node in info.nodes.unwrap().iter() {
-        nodes.join(node.clone());
+    result
+}
+
+/// leader 确认多数派；follower 发送心跳，还没有加入集群或者被leader移除时重新加入
+async fn keep_alive(
+    config: &config::KeepAlive,
+    advertise: SocketAddr,
+    state: web::Data<AppState>,
+    preferred: Option<u16>,
+) -> anyhow::Result<()> {
+    let (leader, current, version, term) = {
+        let nodes = state.nodes.read().map_err(|err| anyhow!(err.to_string()))?;
+        if nodes.self_is_leader() {
+            if nodes.has_quorum(Utc::now().timestamp_millis(), state.fencing.window()) {
+                state.fencing.confirm();
+            }
+            state.clock.reset();
+            return Ok(());
+        }
+        (
+            nodes.get_leader().context("not found leader")?.clone(),
+            nodes.get_current().cloned(),
+            nodes.version(),
+            nodes.term(),
+        )
+    };
+    let timeout = config.timeout();
+
+    match current {
+        Some(current) => {
+            log::debug!("send heartbeat");
+            let beat = Heartbeat::new(&state.cluster, current.address, version, term);
+            let sent_at = beat.sent_at;
+            let req = cluster_request(
+                &state.cluster,
+                Method::Put,
+                format!(
+                    "{}://{}/api/nodes/{}/heartbeat",
+                    tls::scheme(),
+                    leader.endpoint(),
+                    current.id
+                )
+                .as_str(),
+                &beat,
+            )?;
+            let resp = exchange(req, timeout).await?;
+            if resp.status() == StatusCode::NotFound {
+                log::warn!("leader {} not found self, rejoin", leader.address);
+                send_join(
+                    timeout,
+                    advertise,
+                    state.clone(),
+                    &leader,
+                    Some(current),
+                    None,
+                )
+                .await?;
+            } else if !resp.status().is_success() {
+                bail!(
+                    "heartbeat to {} response {}: {}",
+                    leader.address,
+                    resp.status(),
+                    resp.text()
+                );
+            } else {
+                let ack = resp.json::<HeartbeatAck>(state.cluster.secret())?;
+                let offset = state.clock.sample(
+                    sent_at,
+                    ack.received_at,
+                    ack.sent_at,
+                    Utc::now().timestamp_millis(),
+                );
+                log::debug!(
+                    "clock offset to leader: {} ms, round trip: {:?} ms",
+                    offset,
+                    state.clock.round_trip()
+                );
+                if let Some(update) = &ack.membership {
+                    let mut nodes = state
+                        .nodes
+                        .write()
+                        .map_err(|err| anyhow!(err.to_string()))?;
+                    let before = nodes.leader_id();
+                    if let Err(version) = nodes.apply(update) {
+                        log::warn!("membership version {} is behind leader", version);
+                    }
+                    metrics::leader_changed(before, nodes.leader_id());
+                }
+            }
+        }
+        None => send_join(timeout, advertise, state.clone(), &leader, None, preferred).await?,
     }
+
+    state.fencing.confirm();
+    state
+        .last_keep_alive
+        .store(Utc::now().timestamp_millis(), Ordering::SeqCst);
     Ok(())
 }
 
-fn change_new_leader(state: web::Data<AppState>) -> anyhow::Result<()> {
-    let mut nodes = state.nodes.write().expect("cloud get node read lock");
-    let leader = nodes.get_leader().unwrap();
-    if let Some(&node) = &nodes.next(leader.id) {
-        log::info!("set new leader: {:?}", node);
-        nodes.set_leader(Some(node.id));
+/// 向leader申请加入集群，[current]为[None]时优先使用重启前保存的[preferred]，
+/// 保存的ID已经被占用时由leader分配新的节点ID
+async fn send_join(
+    timeout: Duration,
+    advertise: SocketAddr,
+    state: web::Data<AppState>,
+    leader: &Node,
+    current: Option<Node>,
+    preferred: Option<u16>,
+) -> anyhow::Result<()> {
+    log::debug!("join cluster, leader: {}", leader.address);
+    let address = current
+        .as_ref()
+        .map(|current| current.address)
+        .unwrap_or(advertise);
+    let mut requested = current.as_ref().map(|c| c.id).or(preferred);
+    let info = loop {
+        let mut info = JoinInfo::new(&state.cluster, address, requested);
+        info.host = state.advertise_host.clone();
+        info.version = Some(0);
+        let req = cluster_request(
+            &state.cluster,
+            Method::Post,
+            format!("{}://{}/api/nodes", tls::scheme(), leader.endpoint()).as_str(),
+            &info,
+        )?;
+        let resp = exchange(req, timeout).await?;
+        if resp.status() == StatusCode::Conflict && current.is_none() && requested.is_some() {
+            log::warn!(
+                "saved node id {:?} rejected by leader {}: {}",
+                requested,
+                leader.address,
+                resp.text()
+            );
+            requested = None;
+            continue;
+        }
+        if !resp.status().is_success() {
+            bail!(
+                "join {} response {}: {}",
+                leader.address,
+                resp.status(),
+                resp.text()
+            );
+        }
+        break resp.json::<JoinInfo>(state.cluster.secret())?;
+    };
+    if let Err(err) = info.verify(&state.cluster) {
+        bail!("reject leader {}: {}", leader.address, err);
+    }
+    log::debug!("self id: {:?}", info.current_id);
+    if current.is_none() {
+        init_self(advertise, state.clone(), info.current_id)?;
+    }
+    if let Some(update) = &info.membership {
+        let mut nodes = state
+            .nodes
+            .write()
+            .map_err(|err| anyhow!(err.to_string()))?;
+        let before = nodes.leader_id();
+        if let Err(version) = nodes.apply(update) {
+            log::warn!("membership version {} is behind leader", version);
+        }
+        metrics::leader_changed(before, nodes.leader_id());
     }
     Ok(())
 }
 
+/// leader 连续失联时切换到环形顺序的下一个节点，返回是否切换了leader
+fn change_new_leader(state: web::Data<AppState>) -> anyhow::Result<bool> {
+    let mut nodes = state
+        .nodes
+        .write()
+        .map_err(|err| anyhow!(err.to_string()))?;
+    let leader = match nodes.get_leader() {
+        Some(leader) => leader.id,
+        None => return Ok(false),
+    };
+    let node = match nodes.next(leader).cloned() {
+        Some(node) => node,
+        None => return Ok(false),
+    };
+    log::info!("set new leader: {:?}", node);
+    let term = nodes.term() + 1;
+    nodes.set_leader(Some(node.id)).set_term(term);
+    metrics::leader_changed(Some(leader), Some(node.id));
+    if nodes.self_is_leader() {
+        let deltas = take_over(&mut nodes, leader);
+        tokio::spawn(replicate(state.clone(), deltas));
+    }
+    Ok(true)
+}
+
 async fn register(
     config: config::KeepAlive,
-    bind_address: SocketAddr,
+    advertise: SocketAddr,
     state: web::Data<AppState>,
+    mut preferred: Option<u16>,
     mut stopper: broadcast::Receiver<u64>,
 ) -> anyhow::Result<()> {
     let mut timer_interval = tokio::time::interval_at(
@@ -122,40 +615,179 @@ async fn register(
                 break;
             },
             _ = timer_interval.tick() => {
-                match send_register(bind_address.clone(), state.clone()) {
+                match send_register(&config, advertise, state.clone(), preferred).await {
                     Ok(_) => {
+                        crate::metrics::KEEP_ALIVE.with_label_values(&["success"]).inc();
                         fail_num = config.failure_threshold;
+                        preferred = None;
                     }
                     Err(_) if fail_num == 0 => {
-                        if let Ok(_) = change_new_leader(state.clone()) {
-                            //fail_num = config.failure_threshold;
+                        crate::metrics::KEEP_ALIVE.with_label_values(&["failure"]).inc();
+                        match change_new_leader(state.clone()) {
+                            // 新leader重新计算失败次数，不会在下一次失败时再次切换
+                            Ok(true) => fail_num = config.failure_threshold,
+                            Ok(false) => log::warn!("no node to take over leader"),
+                            Err(err) => log::warn!("change leader: {:#}", err),
                         }
                     }
                     Err(err) => {
+                        crate::metrics::KEEP_ALIVE.with_label_values(&["failure"]).inc();
                         fail_num -= 1;
                         log::warn!("send keep-alive: {}", err);
                     }
                 }
+                persist(&state);
             }
         }
     }
     Ok(())
 }
 
-/// 初始化当前node，如果[self_id]为[None]本机会自动设置为leader
+/// 保存集群状态，重启后使用原来的节点ID加入集群
+fn persist(state: &AppState) {
+    let store = match &state.store {
+        Some(store) => store,
+        None => return,
+    };
+    let persisted = match state.nodes.read() {
+        Ok(nodes) => nodes.get_current().map(|current| Persisted {
+            cluster: state.cluster.name.clone(),
+            id: current.id,
+            address: current.address,
+            snapshot: nodes.snapshot(),
+            saved_at: Utc::now().timestamp_millis(),
+        }),
+        Err(_) => None,
+    };
+    if let Some(persisted) = persisted {
+        match store.save(&persisted) {
+            Ok(true) => log::debug!("save cluster state to {}", store.path().display()),
+            Ok(false) => {}
+            Err(err) => log::warn!("save cluster state: {:#}", err),
+        }
+    }
+}
+
+/// 定期写入超过时间窗口的审计区间，退出时写入所有区间
+async fn flush_audit(
+    state: web::Data<AppState>,
+    mut stopper: broadcast::Receiver<u64>,
+) -> anyhow::Result<()> {
+    let audit = match &state.audit {
+        Some(audit) => audit,
+        None => return Ok(()),
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = stopper.recv() => {
+                return audit.flush(None);
+            },
+            _ = interval.tick() => {
+                if let Err(err) = audit.flush(Some(Utc::now().timestamp_millis())) {
+                    log::error!("flush audit log: {:#}", err);
+                }
+            }
+        }
+    }
+}
+
+/// 重启时向保存的成员查询到的集群状态
+#[derive(Debug)]
+enum Rejoin {
+    /// 其他节点是leader，任期为查询到的任期
+    Follow(Node, u64),
+    /// 其他成员仍然认为本机是leader
+    Resume,
+    /// 保存的成员都无法连接
+    Unreachable,
+}
+
+/// 组播没有发现leader时，依次向保存的leader和其他成员查询当前的leader。
+/// 查询结果只用于选择要加入的leader，加入时仍然校验leader响应的签名
+async fn probe_members(state: &AppState, persisted: &Persisted) -> Rejoin {
+    let snapshot = &persisted.snapshot;
+    let mut members = snapshot
+        .nodes
+        .iter()
+        .filter(|node| node.id != persisted.id && node.address != persisted.address)
+        .cloned()
+        .collect::<Vec<_>>();
+    members.sort_by_key(|node| Some(node.id) != snapshot.leader);
+    for member in members {
+        let url = format!("{}://{}/api/nodes", tls::scheme(), member.endpoint());
+        let current = match cluster_request(&state.cluster, Method::Get, &url, &()) {
+            Ok(req) => send(req, state.timeout)
+                .await
+                .and_then(|reply| reply.json::<Snapshot>(None)),
+            Err(err) => Err(err),
+        };
+        let current = match current {
+            Ok(current) => current,
+            Err(err) => {
+                log::warn!(
+                    "saved member [{}]:{} is unreachable: {:#}",
+                    member.id,
+                    member.endpoint(),
+                    err
+                );
+                continue;
+            }
+        };
+        let leader = current
+            .leader
+            .and_then(|id| current.nodes.iter().find(|node| node.id == id));
+        match leader {
+            Some(leader) if leader.id == persisted.id => return Rejoin::Resume,
+            Some(leader) => return Rejoin::Follow(leader.clone(), current.term),
+            None => continue,
+        }
+    }
+    Rejoin::Unreachable
+}
+
+/// 读取重启前保存的集群状态，其他集群的状态会被忽略
+fn restore(state: &AppState) -> Option<Persisted> {
+    let store = state.store.as_ref()?;
+    match store.load() {
+        Ok(Some(persisted)) if persisted.cluster == state.cluster.name => {
+            log::info!(
+                "restore node id {} of term {} from {}",
+                persisted.id,
+                persisted.snapshot.term,
+                store.path().display()
+            );
+            Some(persisted)
+        }
+        Ok(Some(persisted)) => {
+            log::warn!(
+                "ignore saved state of cluster {} in {}",
+                persisted.cluster,
+                store.path().display()
+            );
+            None
+        }
+        Ok(None) => None,
+        Err(err) => {
+            log::warn!("ignore saved cluster state: {:#}", err);
+            None
+        }
+    }
+}
+
+/// 初始化当前node，[advertise]为本机对外公布的地址，如果[self_id]为[None]本机会自动设置为leader
 fn init_self(
-    bind_address: &SocketAddr,
+    advertise: SocketAddr,
     state: web::Data<AppState>,
     self_id: Option<u16>,
 ) -> anyhow::Result<()> {
-    let ip = multicast::local_ipaddress().context("not found local ip")?;
-    let bind_address = SocketAddr::new(ip, (&bind_address).port());
-    log::info!("self node {} make cluster", &bind_address);
-    let current_id = self_id.or(Some(0)).unwrap();
+    log::info!("self node {} make cluster", &advertise);
+    let current_id = self_id.unwrap_or(0);
+    logger::set_node_id(current_id);
 
     let mut nodes = state.nodes.write().expect("could get nodes write lock");
     nodes
-        .join(Node::new(current_id, bind_address.clone()))
+        .join(Node::new(current_id, advertise).with_host(state.advertise_host.clone()))
         .set_current(current_id);
 
     if self_id.is_none() {
@@ -164,7 +796,74 @@ fn init_self(
 
     let mut snowflake = state.snowflake.write().expect("could get snowflake lock");
     if snowflake.is_none() {
-        let _ = snowflake.insert(Snowflake::new(current_id));
+        let generator = match &state.federation {
+            Some(federation) => {
+                let datacenter = federation
+                    .read()
+                    .map_err(|err| anyhow!(err.to_string()))?
+                    .local()
+                    .id;
+                Snowflake::with_datacenter(datacenter, current_id)?
+            }
+            None => Snowflake::new(current_id),
+        };
+        let _ = snowflake.insert(generator);
+    }
+    Ok(())
+}
+
+/// 定期向其他数据中心注册本数据中心，并记录对方的信息
+async fn federate(
+    period: Duration,
+    state: web::Data<AppState>,
+    mut stopper: broadcast::Receiver<u64>,
+) -> anyhow::Result<()> {
+    let federation = match &state.federation {
+        Some(federation) => federation,
+        None => return Ok(()),
+    };
+    let (local, peers) = {
+        let federation = federation.read().map_err(|err| anyhow!(err.to_string()))?;
+        (federation.local().clone(), federation.peers().to_vec())
+    };
+    let mut timer_interval = tokio::time::interval(period);
+    log::info!("start datacenter federation with {:?}", peers);
+
+    loop {
+        tokio::select! {
+            _ = stopper.recv() => {
+                log::debug!("close datacenter federation");
+                break;
+            },
+            _ = timer_interval.tick() => {
+                let registers = peers.iter().map(|peer| {
+                    let state = state.clone();
+                    let local = &local;
+                    async move {
+                        let url = format!("{}://{}/api/datacenters", tls::scheme(), peer);
+                        let secret = state.federation_secret.as_deref();
+                        let req = signed_request(secret, Method::Post, &url, local)?;
+                        let resp = exchange(req, state.timeout).await?;
+                        if !resp.status().is_success() && resp.status() != StatusCode::Conflict {
+                            bail!("{} response {}", url, resp.status());
+                        }
+                        let remote = resp.json::<Datacenter>(secret)?;
+                        let mut federation = federation
+                            .write()
+                            .map_err(|err| anyhow!(err.to_string()))?;
+                        federation
+                            .register(remote, Utc::now().timestamp_millis())
+                            .map_err(|err| anyhow!(err))
+                    }
+                    .map(move |out: anyhow::Result<()>| {
+                        if let Err(err) = out {
+                            log::warn!("register datacenter to {}: {}", peer, err);
+                        }
+                    })
+                });
+                join_all(registers).await;
+            }
+        }
     }
     Ok(())
 }
@@ -173,16 +872,140 @@ pub async fn embedded(
     config: &config::Options,
     stopper: Arc<broadcast::Sender<u64>>,
 ) -> anyhow::Result<()> {
-    let bind_address = config.http_address.context("can't found http address")?;
+    let bind_address = config.bind_address()?;
+    let advertise = config.advertise_address()?;
+    log::info!("advertise address: {}", advertise);
     let mut sys = ActixSystem::new("idgener");
 
-    let state = web::Data::new(AppState::default());
-    let server = bind(&bind_address, state.clone())?;
+    let mut state = AppState::new(config.identity());
+    state.stopper = Some(stopper.clone());
+    state.admin_token = config.admin_token.as_ref().map(|t| t.expose().to_string());
+    state.store = config.data_dir.as_ref().map(Store::new);
+    if let Some(path) = &config.auth_config {
+        let auth = AuthConfig::load(path)?;
+        log::info!("authentication: {} api keys from {}", auth.keys.len(), path);
+        state.auth = Some(Auth::new(&auth));
+    }
+    if config.audit_log {
+        let dir = config
+            .data_dir
+            .as_ref()
+            .context("audit log requires data_dir")?;
+        state.audit = Some(AuditLog::new(
+            dir,
+            config.audit_rotate_bytes.unwrap_or(64 * 1024 * 1024),
+            config.audit_max_files.unwrap_or(10),
+        ));
+    }
+    config.limits.validate()?;
+    config.keep_alive.validate()?;
+    state.timeout = config.keep_alive.timeout();
+    state.advertise_host = config.advertise_host_name();
+    if config.limits.enabled() {
+        log::info!("rate limits: {:?}", config.limits);
+        state.limiter = Some(RateLimiter::new(&config.limits));
+    }
+    if config.multicast_address.is_some() && config.id.is_none() {
+        state.fencing = Fencing::new(Duration::from_secs(
+            config.keep_alive.isolation_seconds.unwrap_or_default(),
+        ));
+        state.clock = Clock::new(Duration::from_millis(
+            config.keep_alive.max_clock_skew_millis.unwrap_or_default(),
+        ));
+    }
+    if let Some(id) = config.datacenter_id {
+        let local = Datacenter {
+            id,
+            name: config
+                .datacenter_name
+                .clone()
+                .unwrap_or_else(|| state.cluster.name.clone()),
+            address: advertise,
+            last_seen: 0,
+        };
+        log::info!("datacenter: {:?}", local);
+        let period = config.keep_alive.period_seconds * 1000;
+        let expire = (period * (config.keep_alive.failure_threshold + 1)) as i64;
+        let peers = config.federation_peers.clone().unwrap_or_default();
+        state.federation = Some(RwLock::new(Federation::new(local, peers, expire)));
+        state.federation_secret = config
+            .federation_secret
+            .as_ref()
+            .map(|s| s.expose().to_string());
+        if state.federation_secret.is_none() {
+            log::warn!("federation secret is not set, requests between datacenters are not signed");
+        }
+        // worker id 的高位分配给了数据中心ID
+        state
+            .nodes
+            .write()
+            .map_err(|err| anyhow!(err.to_string()))?
+            .set_max_id(MAX_DATACENTER_NODE_ID);
+    }
+    // 开启认证后只有签名有效的节点间请求可以不带凭证，没有密钥时其他节点的请求都会被拒绝
+    if state.auth.is_some() {
+        if state.cluster.secret().is_none() {
+            log::warn!("authentication is enabled without cluster secret, requests from other nodes are rejected");
+        }
+        if state.federation.is_some() && state.federation_secret.is_none() {
+            log::warn!("authentication is enabled without federation secret, requests from other datacenters are rejected");
+        }
+    }
+    config.tls.validate()?;
+    // RESP 只支持明文，开启TLS时启用会让 AUTH 的API key明文传输
+    if config.resp_address.is_some() && config.tls.enabled() {
+        bail!("resp address can not be used with tls, api keys would be sent in cleartext");
+    }
+    let certificates = if config.tls.enabled() {
+        log::info!("tls certificate: {:?}", config.tls.tls_cert);
+        let certificates = Certificates::load(&config.tls)?;
+        tls::init(&certificates);
+        Some(certificates)
+    } else {
+        None
+    };
+    let state = web::Data::new(state);
+    let server = bind(&bind_address, state.clone(), certificates.as_ref())?;
 
     let mut futures = vec![];
+    if let Some(certificates) = certificates {
+        futures.push(tokio::spawn(tls::watch(certificates, stopper.subscribe())));
+    }
+    if let Some(address) = config.resp_address {
+        futures.push(tokio::spawn(resp::serve(
+            address,
+            state.clone(),
+            stopper.subscribe(),
+        )));
+    }
+    if state.audit.is_some() {
+        futures.push(tokio::spawn(flush_audit(
+            state.clone(),
+            stopper.subscribe(),
+        )));
+    }
+    if let Some(exporter) = &config.trace_exporter {
+        log::info!("trace exporter: {}", exporter);
+        let provider = crate::trace::init(exporter, &advertise.to_string())?;
+        let mut stopped = stopper.subscribe();
+        futures.push(tokio::spawn(async move {
+            let _ = stopped.recv().await;
+            // 退出前导出剩余的span，shutdown 会阻塞等待导出完成
+            tokio::task::spawn_blocking(move || provider.shutdown())
+                .await?
+                .map_err(|err| anyhow!(err))
+        }));
+    }
+    if config.federation_peers.is_some() && config.datacenter_id.is_some() {
+        futures.push(tokio::spawn(federate(
+            Duration::from_secs(config.keep_alive.period_seconds),
+            state.clone(),
+            stopper.subscribe(),
+        )));
+    }
     if let Some(id) = &config.id {
         log::info!("make cluster, self id: {}", id);
-        let _ = init_self(&bind_address, state.clone(), Some(*id))?;
+        init_self(advertise, state.clone(), Some(*id))?;
         let mut rc = stopper.subscribe();
         futures.push(tokio::spawn(async move {
             let _ = rc.recv().await;
@@ -190,41 +1013,102 @@ pub async fn embedded(
         }));
     } else if let Some(multicast_address) = &config.multicast_address {
         log::info!("find multicast address: {}", &multicast_address);
-        let timeout = Some(Duration::from_secs(
-            config.keep_alive.period_seconds.clone(),
-        ));
-        match multicast::finder(&multicast_address, timeout)? {
-            Some((leader_address, leader_id)) => {
-                log::info!("find cluster leader: {}", leader_address);
+        let timeout = Some(Duration::from_secs(config.keep_alive.period_seconds));
+        let persisted = restore(&state);
+        let mut preferred = None;
+        match multicast::finder(multicast_address, &state.cluster, timeout)? {
+            Some(leader) => {
+                log::info!("find cluster leader: {:?}", leader);
                 state
                     .nodes
                     .write()
                     .unwrap()
-                    .join(Node::new(leader_id, leader_address))
-                    .set_leader(Some(leader_id));
-            }
-            None => {
-                log::info!("not find cluster leader");
-                init_self(&bind_address, state.clone(), None)?;
+                    .join(Node::new(leader.id, leader.address).with_host(leader.host))
+                    .set_leader(Some(leader.id))
+                    .set_term(leader.term);
+                preferred = persisted.map(|p| p.id);
             }
+            None => match persisted {
+                Some(persisted) => {
+                    if persisted.address != advertise {
+                        log::warn!(
+                            "advertise address changed from {} to {}",
+                            persisted.address,
+                            advertise
+                        );
+                    }
+                    match probe_members(&state, &persisted).await {
+                        Rejoin::Follow(leader, term) => {
+                            log::info!(
+                                "rejoin cluster, leader: [{}]:{}",
+                                leader.id,
+                                leader.endpoint()
+                            );
+                            state
+                                .nodes
+                                .write()
+                                .unwrap()
+                                .set_leader(Some(leader.id))
+                                .join(leader)
+                                .set_term(term);
+                            preferred = Some(persisted.id);
+                        }
+                        Rejoin::Resume => {
+                            log::info!(
+                                "saved members still follow self, resume leader {}",
+                                persisted.id
+                            );
+                            init_self(advertise, state.clone(), Some(persisted.id))?;
+                            let mut nodes = state.nodes.write().unwrap();
+                            let _ = nodes.apply(&Update::Snapshot(persisted.snapshot.clone()));
+                            nodes
+                                .join(
+                                    Node::new(persisted.id, advertise)
+                                        .with_host(state.advertise_host.clone()),
+                                )
+                                .set_leader(Some(persisted.id));
+                        }
+                        Rejoin::Unreachable => {
+                            // 不能确认其他成员的状态时不增加任期，避免和仍然存活的leader竞争
+                            log::info!(
+                                "saved members are unreachable, restart as {} of term {}",
+                                persisted.id,
+                                persisted.snapshot.term
+                            );
+                            init_self(advertise, state.clone(), Some(persisted.id))?;
+                            state
+                                .nodes
+                                .write()
+                                .unwrap()
+                                .set_leader(Some(persisted.id))
+                                .set_term(persisted.snapshot.term);
+                        }
+                    }
+                }
+                None => {
+                    log::info!("not find cluster leader");
+                    init_self(advertise, state.clone(), None)?;
+                }
+            },
         }
 
         // start cluster listener
         futures.push(tokio::spawn(multicast::listener(
-            multicast_address.clone(),
+            *multicast_address,
             state.clone(),
             stopper.subscribe(),
         )));
 
         // start registry
         futures.push(tokio::spawn(register(
-            config.keep_alive.clone(),
-            bind_address.clone(),
+            config.keep_alive,
+            advertise,
             state.clone(),
+            preferred,
             stopper.subscribe(),
         )));
     } else {
-        init_self(&bind_address, state.clone(), None)?;
+        init_self(advertise, state.clone(), None)?;
     }
 
     let mut stopper = stopper.subscribe();
@@ -242,6 +1126,11 @@ pub async fn embedded(
         }
         _ = stopper.recv() => {
             log::info!("user close server");
+            state.draining.store(true, Ordering::SeqCst);
+            persist(&state);
+            if let Err(err) = leave(state.clone()).await {
+                log::warn!("leave cluster: {}", err);
+            }
             sys.block_on(srv.stop(true))
         }
     }
@@ -254,14 +1143,18 @@ mod test {
     use std::thread;
     use std::time::Duration;
 
-    use actix_web::{web};
+    use actix_web::web;
     use anyhow::Context;
     use futures::executor::block_on;
     use http_client::http_types::StatusCode;
     use http_client::{HttpClient, Request};
 
+    use crate::cluster::Persisted;
+    use crate::cluster::{Identity, Node};
+    use crate::config;
     use crate::config::logger;
-    use crate::server::server::{bind, CLIENT};
+    use crate::config::KeepAlive;
+    use crate::server::server::{bind, exchange, probe_members, send_register, Rejoin};
     use crate::server::AppState;
 
     #[actix_rt::test]
@@ -274,7 +1167,7 @@ mod test {
         let address = "127.0.0.1:8080"
             .parse::<SocketAddr>()
             .expect("invalid address");
-        let server = bind(&address, state).expect("Can't run server");
+        let server = bind(&address, state, None).expect("Can't run server");
 
         thread::spawn(move || {
             thread::sleep(Duration::from_secs(1));
@@ -298,9 +1191,243 @@ mod test {
     #[test]
     fn http_client() {
         let req = Request::get("http://ip-api.com/json");
-        let resp = block_on(CLIENT.send(req));
+        let resp = block_on(super::http_client().send(req));
         assert!(resp.is_ok());
         let resp = resp.unwrap();
         assert_eq!(StatusCode::Ok, resp.status());
     }
+
+    #[tokio::test]
+    async fn exchange_timeout() {
+        // 只建立连接不响应的节点
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/nodes", listener.local_addr().unwrap());
+        let req = Request::get(url.as_str());
+        let out = exchange(req, Duration::from_millis(100)).await;
+        assert!(out.unwrap_err().to_string().contains("timed out"));
+        drop(listener);
+    }
+
+    /// actix 的handler中发起的请求同样受超时控制
+    #[actix_rt::test]
+    async fn exchange_on_actix() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/nodes", listener.local_addr().unwrap());
+        let req = Request::get(url.as_str());
+        let out = exchange(req, Duration::from_millis(100)).await;
+        assert!(out.unwrap_err().to_string().contains("timed out"));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn exchange_body_timeout() {
+        // 只返回响应头，响应体一直不发送完的节点
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/nodes", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            use std::io::{Read, Write};
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\n{")
+                .unwrap();
+            thread::sleep(Duration::from_millis(500));
+        });
+        let req = Request::get(url.as_str());
+        let out = exchange(req, Duration::from_millis(100)).await;
+        assert!(out.unwrap_err().to_string().contains("timed out"));
+        server.join().unwrap();
+    }
+
+    /// 没有密钥的节点冒充leader时，响应没有签名，follower拒绝加入
+    #[tokio::test]
+    async fn reject_unsigned_response() {
+        logger::init(true);
+        let mut sys = actix_web::rt::System::new("unsigned");
+        let config = KeepAlive {
+            timeout_millis: Some(2000),
+            ..config::Options::default().keep_alive
+        };
+
+        let leader_address = free_address();
+        let leader = web::Data::new(AppState::default());
+        leader
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, leader_address))
+            .set_leader(Some(0))
+            .set_current(0);
+        let leader_server = bind(&leader_address, leader.clone(), None).unwrap();
+
+        let follower_address = free_address();
+        let follower = web::Data::new(AppState::new(Identity::new("idgener", Some("s3cret"))));
+        follower
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, leader_address))
+            .set_leader(Some(0));
+        let err = send_register(&config, follower_address, follower.clone(), None)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unsigned response"),
+            "{:#}",
+            err
+        );
+        assert!(follower.nodes.read().unwrap().get_current().is_none());
+
+        sys.block_on(leader_server.stop(true));
+    }
+
+    fn free_address() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    /// 启动两个真实节点，follower 先加入集群再发送心跳
+    #[tokio::test]
+    async fn join_and_heartbeat() {
+        logger::init(true);
+        let mut sys = actix_web::rt::System::new("heartbeat");
+        let config = KeepAlive {
+            timeout_millis: Some(2000),
+            ..config::Options::default().keep_alive
+        };
+        // 节点间的请求和响应都需要签名
+        let cluster = Identity::new("idgener", Some("s3cret"));
+
+        let leader_address = free_address();
+        let leader = web::Data::new(AppState::new(cluster.clone()));
+        leader
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, leader_address))
+            .set_leader(Some(0))
+            .set_current(0);
+        let leader_server = bind(&leader_address, leader.clone(), None).unwrap();
+
+        let follower_address = free_address();
+        let follower = web::Data::new(AppState::new(cluster.clone()));
+        follower
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, leader_address))
+            .set_leader(Some(0));
+        let follower_server = bind(&follower_address, follower.clone(), None).unwrap();
+
+        send_register(&config, follower_address, follower.clone(), None)
+            .await
+            .expect("join cluster");
+        let id = follower.nodes.read().unwrap().get_current().unwrap().id;
+        assert_eq!(1, id);
+        assert_eq!(2, leader.nodes.read().unwrap().nodes.len());
+        assert_eq!(None, follower.clock.offset());
+
+        leader
+            .nodes
+            .write()
+            .unwrap()
+            .get_node_by_address(
+                &follower
+                    .nodes
+                    .read()
+                    .unwrap()
+                    .get_current()
+                    .unwrap()
+                    .address,
+            )
+            .unwrap()
+            .last_alive_timestamp = 0;
+        send_register(&config, follower_address, follower.clone(), None)
+            .await
+            .expect("send heartbeat");
+        assert!(follower.clock.offset().is_some());
+        assert!(
+            leader
+                .nodes
+                .read()
+                .unwrap()
+                .get(id)
+                .unwrap()
+                .last_alive_timestamp
+                > 0
+        );
+        assert_eq!(
+            leader.nodes.read().unwrap().version(),
+            follower.nodes.read().unwrap().version()
+        );
+
+        // 重启后使用保存的节点ID加入，ID已被其他节点占用时重新分配
+        let restarted_address = free_address();
+        let restarted = web::Data::new(AppState::new(cluster.clone()));
+        restarted
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, leader_address))
+            .set_leader(Some(0));
+        let restarted_server = bind(&restarted_address, restarted.clone(), None).unwrap();
+        send_register(&config, restarted_address, restarted.clone(), Some(id))
+            .await
+            .expect("join with stale id");
+        assert_eq!(2, restarted.nodes.read().unwrap().get_current().unwrap().id);
+
+        sys.block_on(leader_server.stop(true));
+        sys.block_on(follower_server.stop(true));
+        sys.block_on(restarted_server.stop(true));
+    }
+
+    /// 组播没有发现leader时，重启的节点向保存的成员查询leader
+    #[tokio::test]
+    async fn probe_saved_members() {
+        logger::init(true);
+        let mut sys = actix_web::rt::System::new("probe");
+        let cluster = Identity::new("idgener", Some("s3cret"));
+
+        let leader_address = free_address();
+        let restarted_address = free_address();
+        let leader = web::Data::new(AppState::new(cluster.clone()));
+        leader
+            .nodes
+            .write()
+            .unwrap()
+            .join(Node::new(0, leader_address))
+            .join(Node::new(1, restarted_address))
+            .set_leader(Some(0))
+            .set_current(0)
+            .set_term(3);
+        let leader_server = bind(&leader_address, leader.clone(), None).unwrap();
+
+        let restarted = AppState::new(cluster);
+        let mut persisted = Persisted {
+            cluster: "idgener".to_string(),
+            id: 1,
+            address: restarted_address,
+            snapshot: leader.nodes.read().unwrap().snapshot(),
+            saved_at: 0,
+        };
+        persisted.snapshot.term = 2;
+        match probe_members(&restarted, &persisted).await {
+            Rejoin::Follow(node, term) => assert_eq!((0, 3), (node.id, term)),
+            other => panic!("{:?}", other),
+        }
+
+        // 其他成员仍然认为本机是leader时恢复leader身份
+        leader.nodes.write().unwrap().set_leader(Some(1));
+        assert!(matches!(
+            probe_members(&restarted, &persisted).await,
+            Rejoin::Resume
+        ));
+
+        sys.block_on(leader_server.stop(true));
+        assert!(matches!(
+            probe_members(&restarted, &persisted).await,
+            Rejoin::Unreachable
+        ));
+    }
 }
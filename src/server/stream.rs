@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, CloseCode, CloseReason, Codec, Frame, Message};
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_ENCODING, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, Result};
+use futures::future::ready;
+use futures::stream::{self, StreamExt};
+use serde::Deserialize;
+use tokio::sync::{broadcast, Notify};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::config::logger;
+use crate::server::generator::{caller, issue, DATASET};
+use crate::server::AppState;
+
+const DEFAULT_CHUNK: u64 = 100;
+
+/// 一次最多推送的ID数量，和一毫秒内的序列号数量相同
+const MAX_CHUNK: u64 = 4096;
+
+/// 每个调用方同时打开的SSE和WebSocket流数量
+const MAX_STREAMS: usize = 16;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Params {
+    /// 每次推送的ID数量
+    chunk: Option<u64>,
+    /// 初始额度，默认为一次推送的数量
+    credits: Option<u64>,
+}
+
+/// 客户端授予的额度，`{"credits": 100}`
+#[derive(Debug, Deserialize, ToSchema)]
+struct Grant {
+    credits: u64,
+}
+
+#[derive(Default)]
+struct Flow {
+    available: u64,
+    closed: bool,
+    /// 需要回复的WebSocket ping
+    pings: Vec<Bytes>,
+}
+
+/// 流量控制，客户端授予额度后才推送ID，每推送一个ID消耗一个额度
+#[derive(Default)]
+pub struct Credits {
+    flow: Mutex<Flow>,
+    notify: Notify,
+}
+
+impl Credits {
+    fn update<F: FnOnce(&mut Flow)>(&self, f: F) {
+        f(&mut self.flow.lock().unwrap());
+        self.notify.notify_one();
+    }
+
+    fn grant(&self, credits: u64) {
+        self.update(|flow| flow.available = flow.available.saturating_add(credits));
+    }
+}
+
+/// 正在推送的流和所属的调用方，SSE流通过它接收额度
+#[derive(Default)]
+pub struct Streams(Mutex<HashMap<String, (String, Arc<Credits>)>>);
+
+impl Streams {
+    /// 登记[caller]的流，返回流的ID，调用方的流超过[MAX_STREAMS]时返回429
+    fn register(&self, caller: &str, credits: Arc<Credits>) -> Result<String> {
+        let mut streams = self.0.lock().unwrap();
+        let opened = streams
+            .values()
+            .filter(|(owner, _)| owner == caller)
+            .count();
+        if opened >= MAX_STREAMS {
+            return Err(actix_web::error::ErrorTooManyRequests(format!(
+                "too many streams, at most {}",
+                MAX_STREAMS
+            )));
+        }
+        let id = format!("{:016x}", rand::random::<u64>());
+        streams.insert(id.clone(), (caller.to_string(), credits));
+        Ok(id)
+    }
+}
+
+enum Event {
+    Ids(Vec<u64>),
+    Pong(Bytes),
+    /// 发放ID失败，推送后结束
+    Error(String),
+    /// 客户端关闭
+    Close,
+    /// 服务退出
+    Stopped,
+}
+
+/// 按额度发放ID
+struct Producer {
+    data: web::Data<AppState>,
+    caller: String,
+    chunk: u64,
+    credits: Arc<Credits>,
+    stopper: Option<broadcast::Receiver<u64>>,
+    error: Option<String>,
+    done: bool,
+    /// 超过限流时暂停到该时间，之后继续推送
+    paused: Option<Instant>,
+    /// 流的ID，结束时从[Streams]中移除
+    id: String,
+}
+
+impl Producer {
+    fn new(req: &HttpRequest, data: web::Data<AppState>, params: &Params) -> Result<Self> {
+        let chunk = params.chunk.unwrap_or(DEFAULT_CHUNK).clamp(1, MAX_CHUNK);
+        let credits = Arc::new(Credits::default());
+        credits.grant(params.credits.unwrap_or(chunk));
+        let caller = caller(req);
+        let id = data.streams.register(&caller, credits.clone())?;
+        Ok(Producer {
+            stopper: data.stopper.as_ref().map(|stopper| stopper.subscribe()),
+            data,
+            caller,
+            chunk,
+            credits,
+            error: None,
+            done: false,
+            paused: None,
+            id,
+        })
+    }
+
+    /// 下一个事件，没有额度或者被限流时等待客户端授予额度、限流结束或者服务退出
+    async fn next(&mut self) -> Option<Event> {
+        if self.done {
+            return None;
+        }
+        if let Some(err) = self.error.take() {
+            self.done = true;
+            return Some(Event::Error(err));
+        }
+        loop {
+            let wanted = {
+                let mut flow = self.credits.flow.lock().unwrap();
+                if let Some(payload) = flow.pings.pop() {
+                    return Some(Event::Pong(payload));
+                }
+                if flow.closed {
+                    self.done = true;
+                    return Some(Event::Close);
+                }
+                match self.paused {
+                    Some(until) if until > Instant::now() => 0,
+                    _ => {
+                        let wanted = flow.available.min(self.chunk);
+                        flow.available -= wanted;
+                        wanted
+                    }
+                }
+            };
+            if wanted > 0 {
+                self.paused = None;
+                if let Some(event) = self.produce(wanted) {
+                    return Some(event);
+                }
+                continue;
+            }
+            let resume = self
+                .paused
+                .map(|until| until.saturating_duration_since(Instant::now()));
+            tokio::select! {
+                _ = self.credits.notify.notified() => {}
+                _ = resumed(resume) => {}
+                _ = stopped(&mut self.stopper) => {
+                    self.done = true;
+                    return Some(Event::Stopped);
+                }
+            }
+        }
+    }
+
+    /// 发放失败时先推送已经发放的ID，下一次推送错误。
+    /// 超过限流时退回没有使用的额度，暂停到 `Retry-After` 之后继续，没有发放ID时返回[None]
+    fn produce(&mut self, wanted: u64) -> Option<Event> {
+        let mut ids = Vec::with_capacity(wanted as usize);
+        for _ in 0..wanted {
+            match issue(&self.data, &self.caller, "stream") {
+                Ok(id) => ids.push(id.value()),
+                Err(err) => {
+                    match retry_after(&err) {
+                        Some(wait) => {
+                            log::debug!("stream {} paused for {:?}: {}", self.id, wait, err);
+                            self.paused = Some(Instant::now() + wait);
+                            self.credits.grant(wanted - ids.len() as u64);
+                        }
+                        None => self.error = Some(err.to_string()),
+                    }
+                    break;
+                }
+            }
+        }
+        if !ids.is_empty() {
+            return Some(Event::Ids(ids));
+        }
+        self.error.take().map(|err| {
+            self.done = true;
+            Event::Error(err)
+        })
+    }
+}
+
+/// 限流拒绝时需要等待的时间
+fn retry_after(err: &Error) -> Option<Duration> {
+    let response = err.as_response_error().error_response();
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let seconds = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(1);
+    Some(Duration::from_secs(seconds))
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.data.streams.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// 等待限流结束，没有被限流时一直等待
+async fn resumed(resume: Option<Duration>) {
+    match resume {
+        // 运行在actix的运行时上，不能使用tokio的定时器
+        Some(resume) => async_std::task::sleep(resume).await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn stopped(stopper: &mut Option<broadcast::Receiver<u64>>) {
+    match stopper {
+        Some(stopper) => {
+            let _ = stopper.recv().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+fn sse(event: &str, data: &str) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+/// Server-Sent Events 推送ID，第一个 `open` 事件带有流的ID，
+/// 通过 `POST /stream/{id}/credits` 授予额度。超过限流时暂停推送，`Retry-After` 之后继续
+#[utoipa::path(
+    get,
+    path = "/api/g/stream",
+    tag = "generation",
+    params(Params),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, content_type = "text/event-stream", body = String),
+        (status = 429, description = "too many streams of the client"),
+    )
+)]
+#[get("/stream")]
+pub async fn events(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    params: web::Query<Params>,
+) -> Result<HttpResponse> {
+    logger::set_dataset(DATASET);
+    let producer = Producer::new(&req, data, &params)?;
+    let open = serde_json::json!({ "stream": producer.id, "chunk": producer.chunk });
+    let events = stream::unfold(producer, |mut producer| async move {
+        let event = match producer.next().await? {
+            Event::Ids(ids) => sse("ids", &serde_json::to_string(&ids).unwrap()),
+            Event::Error(err) => sse("error", &err),
+            Event::Close | Event::Stopped => sse("close", ""),
+            Event::Pong(_) => Bytes::from_static(b":\n\n"),
+        };
+        Some((Ok::<_, Error>(event), producer))
+    })
+    .boxed_local();
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        // 压缩会缓存事件
+        .header(CONTENT_ENCODING, "identity")
+        .streaming(stream::once(ready(Ok(sse("open", &open.to_string())))).chain(events)))
+}
+
+/// 授予SSE流额度，只有创建流的调用方可以授予
+#[utoipa::path(
+    post,
+    path = "/api/g/stream/{id}/credits",
+    tag = "generation",
+    params(("id" = String, Path, description = "`open` 事件中的流ID")),
+    request_body = Grant,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 204, description = "granted"),
+        (status = 403, description = "not the owner of the stream"),
+        (status = 404, description = "stream not found"),
+    )
+)]
+#[post("/stream/{id}/credits")]
+pub async fn add_credits(
+    req: HttpRequest,
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+    grant: web::Json<Grant>,
+) -> Result<HttpResponse> {
+    let credits = match data.streams.0.lock().unwrap().get(id.as_str()) {
+        Some((owner, _)) if *owner != caller(&req) => {
+            return Err(actix_web::error::ErrorForbidden(
+                "not the owner of the stream",
+            ))
+        }
+        Some((_, credits)) => credits.clone(),
+        None => return Err(actix_web::error::ErrorNotFound("stream not found")),
+    };
+    credits.grant(grant.credits);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// 读取客户端的额度、ping和关闭消息
+async fn receive(mut payload: web::Payload, credits: Arc<Credits>) {
+    let mut codec = Codec::new();
+    let mut buffer = BytesMut::new();
+    'read: while let Some(Ok(chunk)) = payload.next().await {
+        buffer.extend_from_slice(&chunk);
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(Frame::Text(text))) => match serde_json::from_slice::<Grant>(&text) {
+                    Ok(grant) => credits.grant(grant.credits),
+                    Err(err) => log::debug!("invalid credits message: {}", err),
+                },
+                Ok(Some(Frame::Ping(payload))) => credits.update(|flow| flow.pings.push(payload)),
+                Ok(Some(Frame::Close(_))) | Err(_) => break 'read,
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+            }
+        }
+    }
+    credits.update(|flow| flow.closed = true);
+}
+
+/// WebSocket 推送ID，客户端发送 `{"credits": 100}` 授予额度，超过限流时和SSE一样暂停推送
+#[utoipa::path(
+    get,
+    path = "/api/g/stream/ws",
+    tag = "generation",
+    params(Params),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 101, description = "switching protocols"),
+        (status = 429, description = "too many streams of the client"),
+    )
+)]
+#[get("/stream/ws")]
+pub async fn websocket(
+    req: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<AppState>,
+    params: web::Query<Params>,
+) -> Result<HttpResponse> {
+    logger::set_dataset(DATASET);
+    let mut response = ws::handshake(req.head())?;
+    let producer = Producer::new(&req, data, &params)?;
+    actix_rt::spawn(receive(payload, producer.credits.clone()));
+    let messages = stream::unfold(
+        (producer, Codec::new()),
+        |(mut producer, mut codec)| async move {
+            let message = match producer.next().await? {
+                Event::Ids(ids) => Message::Text(serde_json::to_string(&ids).unwrap()),
+                Event::Pong(payload) => Message::Pong(payload),
+                Event::Error(err) => Message::Close(Some(CloseReason {
+                    code: CloseCode::Again,
+                    description: Some(err),
+                })),
+                Event::Close => Message::Close(Some(CloseCode::Normal.into())),
+                Event::Stopped => Message::Close(Some(CloseCode::Away.into())),
+            };
+            let mut buffer = BytesMut::new();
+            let encoded = codec
+                .encode(message, &mut buffer)
+                .map(|_| buffer.freeze())
+                .map_err(Error::from);
+            Some((encoded, (producer, codec)))
+        },
+    )
+    .boxed_local();
+    Ok(response.streaming(messages))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use actix_http::ws::{CloseCode, Frame, Message};
+    use actix_web::{test, web, App};
+    use futures::{SinkExt, Stream, StreamExt};
+    use tokio::sync::broadcast;
+
+    use crate::config::{logger, Limits};
+    use crate::generator::Snowflake;
+    use crate::server::generator::route;
+    use crate::server::limit::RateLimiter;
+    use crate::server::stream::MAX_STREAMS;
+    use crate::server::AppState;
+
+    fn state() -> (web::Data<AppState>, Arc<broadcast::Sender<u64>>) {
+        let stopper = Arc::new(broadcast::channel(1).0);
+        let state = AppState {
+            stopper: Some(stopper.clone()),
+            ..AppState::default()
+        };
+        let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+        (web::Data::new(state), stopper)
+    }
+
+    /// 读取SSE响应，直到出现[count]个[pattern]
+    async fn read_until<S, E>(body: &mut S, text: &mut String, pattern: &str, count: usize)
+    where
+        S: Stream<Item = Result<web::Bytes, E>> + Unpin,
+        E: std::fmt::Debug,
+    {
+        while text.matches(pattern).count() < count {
+            let chunk = body.next().await.expect("stream closed").unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+
+    fn ids(text: &str) -> usize {
+        text.lines()
+            .filter_map(|line| line.strip_prefix("data: ["))
+            .map(|ids| ids.split(',').count())
+            .sum()
+    }
+
+    #[actix_rt::test]
+    async fn server_sent_events() {
+        logger::init(true);
+        let (data, stopper) = state();
+        let srv = test::start(move || App::new().app_data(data.clone()).service(route()));
+
+        let mut response = srv.get("/g/stream?chunk=2&credits=3").send().await.unwrap();
+        assert_eq!(
+            "text/event-stream",
+            response.headers().get("content-type").unwrap()
+        );
+        let mut text = String::new();
+        read_until(&mut response, &mut text, "event: ids", 2).await;
+        assert_eq!(3, ids(&text));
+        let open = text.lines().nth(1).unwrap().strip_prefix("data: ").unwrap();
+        let open = serde_json::from_str::<serde_json::Value>(open).unwrap();
+        let id = open["stream"].as_str().unwrap().to_string();
+
+        let granted = srv
+            .post(format!("/g/stream/{}/credits", id))
+            .send_json(&serde_json::json!({ "credits": 1 }))
+            .await
+            .unwrap();
+        assert_eq!(204, granted.status().as_u16());
+        read_until(&mut response, &mut text, "event: ids", 3).await;
+        assert_eq!(4, ids(&text));
+        let missing = srv
+            .post("/g/stream/0/credits")
+            .send_json(&serde_json::json!({ "credits": 1 }))
+            .await
+            .unwrap();
+        assert_eq!(404, missing.status().as_u16());
+
+        stopper.send(0).unwrap();
+        read_until(&mut response, &mut text, "event: close", 1).await;
+        assert!(response.next().await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn rate_limited() {
+        logger::init(true);
+        let state = AppState {
+            limiter: Some(RateLimiter::new(&Limits {
+                rate_limit: Some(2.0),
+                ..Limits::default()
+            })),
+            ..AppState::default()
+        };
+        let _ = state.snowflake.write().unwrap().insert(Snowflake::new(0));
+        let data = web::Data::new(state);
+        let srv = test::start(move || App::new().app_data(data.clone()).service(route()));
+
+        // 令牌桶只有两个令牌，超过后暂停到 Retry-After 之后继续推送
+        let mut response = srv.get("/g/stream?chunk=4&credits=4").send().await.unwrap();
+        let mut text = String::new();
+        read_until(&mut response, &mut text, "event: ids", 2).await;
+        assert_eq!(4, ids(&text));
+        assert!(!text.contains("event: error"));
+    }
+
+    #[actix_rt::test]
+    async fn too_many_streams() {
+        logger::init(true);
+        let (data, _stopper) = state();
+        let streams = data.clone();
+        let srv = test::start(move || App::new().app_data(data.clone()).service(route()));
+
+        let mut opened = vec![];
+        for _ in 0..MAX_STREAMS {
+            let response = srv.get("/g/stream?credits=0").send().await.unwrap();
+            assert!(response.status().is_success());
+            opened.push(response);
+        }
+        let response = srv.get("/g/stream?credits=0").send().await.unwrap();
+        assert_eq!(429, response.status().as_u16());
+        assert_eq!(MAX_STREAMS, streams.streams.0.lock().unwrap().len());
+    }
+
+    #[actix_rt::test]
+    async fn websocket() {
+        logger::init(true);
+        let (data, _stopper) = state();
+        let mut srv = test::start(move || App::new().app_data(data.clone()).service(route()));
+
+        let mut framed = srv.ws_at("/g/stream/ws?chunk=2&credits=0").await.unwrap();
+        framed
+            .send(Message::Text(String::from(r#"{"credits": 3}"#)))
+            .await
+            .unwrap();
+        let mut received = vec![];
+        while received.len() < 3 {
+            match framed.next().await.unwrap().unwrap() {
+                Frame::Text(text) => {
+                    received.extend(serde_json::from_slice::<Vec<u64>>(&text).unwrap())
+                }
+                other => panic!("unexpected frame {:?}", other),
+            }
+        }
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+        framed
+            .send(Message::Ping(web::Bytes::from_static(b"p")))
+            .await
+            .unwrap();
+        assert_eq!(
+            Frame::Pong(web::Bytes::from_static(b"p")),
+            framed.next().await.unwrap().unwrap()
+        );
+        framed
+            .send(Message::Close(Some(CloseCode::Normal.into())))
+            .await
+            .unwrap();
+        assert_eq!(
+            Frame::Close(Some(CloseCode::Normal.into())),
+            framed.next().await.unwrap().unwrap()
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use async_native_tls::{Certificate, Identity, TlsConnector};
+use lazy_static::lazy_static;
+use openssl::ssl::{
+    select_next_proto, AlpnError, ClientHelloResponse, SslAcceptor, SslAcceptorBuilder, SslContext,
+    SslFiletype, SslMethod, SslVerifyMode,
+};
+use tokio::sync::broadcast;
+
+use crate::config::Tls;
+
+/// 检查证书文件是否修改的间隔
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// 节点间请求使用的TLS配置，[None]时使用http
+    static ref CONNECTOR: RwLock<Option<Arc<TlsConnector>>> = RwLock::new(None);
+}
+
+/// 节点间请求的协议
+pub fn scheme() -> &'static str {
+    if CONNECTOR.read().unwrap().is_some() {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+/// 节点间请求使用的TLS配置
+pub fn connector() -> Option<Arc<TlsConnector>> {
+    CONNECTOR.read().unwrap().clone()
+}
+
+/// 开启TLS时节点间请求使用https，信任[Tls::tls_ca]并且出示本机证书
+pub fn init(certificates: &Certificates) {
+    *CONNECTOR.write().unwrap() = certificates.connector();
+}
+
+/// 节点间请求的TLS配置，没有开启TLS时返回[None]
+fn client(tls: &Tls) -> anyhow::Result<Option<TlsConnector>> {
+    let (cert, key) = match (&tls.tls_cert, &tls.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+        .with_context(|| format!("load tls certificate {}", cert))?;
+    let mut connector = TlsConnector::new().identity(identity);
+    if let Some(ca) = &tls.tls_ca {
+        for cert in openssl::x509::X509::stack_from_pem(&read(ca)?)
+            .with_context(|| format!("parse tls ca {}", ca))?
+        {
+            let cert = Certificate::from_der(&cert.to_der()?)?;
+            connector = connector.add_root_certificate(cert);
+        }
+    }
+    Ok(Some(connector))
+}
+
+fn read(path: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("read {}", path))
+}
+
+/// 监听端口的TLS配置，校验客户端证书时要求客户端出示[Tls::tls_ca]签发的证书
+fn builder(tls: &Tls) -> anyhow::Result<SslAcceptorBuilder> {
+    let cert = tls.tls_cert.as_deref().context("tls_cert is required")?;
+    let key = tls.tls_key.as_deref().context("tls_key is required")?;
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder
+        .set_certificate_chain_file(cert)
+        .with_context(|| format!("load tls certificate {}", cert))?;
+    builder
+        .set_private_key_file(key, SslFiletype::PEM)
+        .with_context(|| format!("load tls key {}", key))?;
+    builder
+        .check_private_key()
+        .with_context(|| format!("{} doesn't match {}", key, cert))?;
+    if let Some(ca) = &tls.tls_ca {
+        builder
+            .set_ca_file(ca)
+            .with_context(|| format!("load tls ca {}", ca))?;
+    }
+    if tls.tls_verify_client {
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    builder.set_alpn_select_callback(|_, protocols| {
+        select_next_proto(b"\x02h2\x08http/1.1", protocols).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder)
+}
+
+/// 证书文件最后的修改时间
+fn modified(tls: &Tls) -> Option<SystemTime> {
+    [&tls.tls_cert, &tls.tls_key, &tls.tls_ca]
+        .iter()
+        .filter_map(|path| path.as_ref())
+        .filter_map(|path| Path::new(path).metadata().ok()?.modified().ok())
+        .max()
+}
+
+/// 监听端口和节点间请求的证书，文件修改后重新加载，新的握手和新的连接使用新证书
+pub struct Certificates {
+    tls: Tls,
+    modified: RwLock<Option<SystemTime>>,
+    context: RwLock<SslContext>,
+    connector: RwLock<Option<Arc<TlsConnector>>>,
+}
+
+impl Certificates {
+    pub fn load(tls: &Tls) -> anyhow::Result<Arc<Self>> {
+        let modified = modified(tls);
+        let context = builder(tls)?.build().into_context();
+        let connector = client(tls)?.map(Arc::new);
+        Ok(Arc::new(Certificates {
+            tls: tls.clone(),
+            modified: RwLock::new(modified),
+            context: RwLock::new(context),
+            connector: RwLock::new(connector),
+        }))
+    }
+
+    /// 节点间请求出示的证书
+    pub fn connector(&self) -> Option<Arc<TlsConnector>> {
+        self.connector.read().unwrap().clone()
+    }
+
+    /// 用于 `HttpServer::bind_openssl`，每次握手时切换到最新加载的证书
+    pub fn acceptor(self: &Arc<Self>) -> anyhow::Result<SslAcceptorBuilder> {
+        let mut builder = builder(&self.tls)?;
+        let certificates = self.clone();
+        builder.set_client_hello_callback(move |ssl, _| {
+            ssl.set_ssl_context(&certificates.context.read().unwrap())?;
+            Ok(ClientHelloResponse::SUCCESS)
+        });
+        Ok(builder)
+    }
+
+    /// 文件修改后重新加载，返回是否加载了新证书。加载失败时继续使用原来的证书
+    pub fn reload(&self) -> anyhow::Result<bool> {
+        let modified = modified(&self.tls);
+        if modified == *self.modified.read().unwrap() {
+            return Ok(false);
+        }
+        let context = builder(&self.tls)?.build().into_context();
+        let connector = client(&self.tls)?.map(Arc::new);
+        *self.context.write().unwrap() = context;
+        *self.connector.write().unwrap() = connector;
+        *self.modified.write().unwrap() = modified;
+        Ok(true)
+    }
+}
+
+/// 定时检查证书文件，重新加载后节点间请求也切换到新证书
+pub async fn watch(
+    certificates: Arc<Certificates>,
+    mut stopper: broadcast::Receiver<u64>,
+) -> anyhow::Result<()> {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(RELOAD_INTERVAL) => {
+                match certificates.reload() {
+                    Ok(true) => {
+                        log::info!("reload tls certificate {:?}", certificates.tls.tls_cert);
+                        init(&certificates);
+                    }
+                    Ok(false) => {}
+                    Err(err) => log::warn!("reload tls certificate: {:#}", err),
+                }
+            }
+            _ = stopper.recv() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use actix_web::{get, App, HttpServer};
+    use futures::executor::block_on;
+    use http_client::h1::H1Client;
+    use http_client::{Config, HttpClient, Request};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509NameBuilder, X509};
+
+    use crate::config::logger;
+    use crate::config::Tls;
+    use crate::server::tls::Certificates;
+
+    /// 生成证书，[ca]为[None]时生成自签名的CA证书
+    fn issue(name: &str, ca: Option<&(X509, PKey<Private>)>) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match ca {
+            None => {
+                builder.set_issuer_name(&subject).unwrap();
+                let constraints = BasicConstraints::new().critical().ca().build().unwrap();
+                builder.append_extension(constraints).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+            Some((ca_cert, ca_key)) => {
+                builder.set_issuer_name(ca_cert.subject_name()).unwrap();
+                let san = SubjectAlternativeName::new()
+                    .ip("127.0.0.1")
+                    .dns("localhost")
+                    .build(&builder.x509v3_context(Some(ca_cert), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    fn write(dir: &Path, name: &str, (cert, key): &(X509, PKey<Private>)) {
+        std::fs::write(dir.join(format!("{}.pem", name)), cert.to_pem().unwrap()).unwrap();
+        let key = key.private_key_to_pem_pkcs8().unwrap();
+        std::fs::write(dir.join(format!("{}.key", name)), key).unwrap();
+    }
+
+    /// 使用openssl直接握手，返回服务端证书的序列号和响应
+    fn handshake(
+        address: SocketAddr,
+        dir: &Path,
+        with_cert: bool,
+    ) -> anyhow::Result<(String, String)> {
+        let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+        connector.set_ca_file(dir.join("ca.pem"))?;
+        if with_cert {
+            connector.set_certificate_file(dir.join("client.pem"), SslFiletype::PEM)?;
+            connector.set_private_key_file(dir.join("client.key"), SslFiletype::PEM)?;
+        }
+        let stream = TcpStream::connect(address)?;
+        let mut stream = connector.build().connect("localhost", stream)?;
+        stream.write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let serial = stream
+            .ssl()
+            .peer_certificate()
+            .unwrap()
+            .serial_number()
+            .to_bn()?
+            .to_hex_str()?
+            .to_string();
+        Ok((serial, response))
+    }
+
+    #[get("/hello")]
+    async fn hello() -> &'static str {
+        "hello"
+    }
+
+    #[actix_rt::test]
+    async fn mutual_tls() {
+        logger::init(true);
+        let dir = std::env::temp_dir().join(format!("idgener-tls-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = issue("idgener-ca", None);
+        std::fs::write(dir.join("ca.pem"), ca.0.to_pem().unwrap()).unwrap();
+        let node = issue("node", Some(&ca));
+        write(&dir, "node", &node);
+        write(&dir, "client", &issue("client", Some(&ca)));
+
+        let path = |name: &str| Some(dir.join(name).display().to_string());
+        let tls = Tls {
+            tls_cert: path("node.pem"),
+            tls_key: path("node.key"),
+            tls_ca: path("ca.pem"),
+            tls_verify_client: true,
+        };
+        tls.validate().unwrap();
+        let certificates = Certificates::load(&tls).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let server = HttpServer::new(|| App::new().service(hello))
+            .workers(1)
+            .bind_openssl(address, certificates.acceptor().unwrap())
+            .unwrap()
+            .run();
+
+        let serial = |cert: &X509| {
+            cert.serial_number()
+                .to_bn()
+                .unwrap()
+                .to_hex_str()
+                .unwrap()
+                .to_string()
+        };
+        let (presented, response) = handshake(address, &dir, true).unwrap();
+        assert_eq!(serial(&node.0), presented);
+        assert!(response.ends_with("hello"));
+        assert!(handshake(address, &dir, false).is_err());
+
+        // 节点间请求信任集群CA，使用IP地址访问并出示本机证书
+        let get = |connector| {
+            let mut h1 = H1Client::new();
+            h1.set_config(Config::default().set_tls_config(connector))
+                .unwrap();
+            let url = format!("https://{}/hello", address);
+            let mut resp = block_on(h1.send(Request::get(url.as_str()))).unwrap();
+            block_on(resp.body_string()).unwrap()
+        };
+        let connector = certificates.connector();
+        assert_eq!("hello", get(connector.clone()));
+
+        // 替换证书文件后新的握手使用新证书
+        assert!(!certificates.reload().unwrap());
+        let renewed = issue("node", Some(&ca));
+        write(&dir, "node", &renewed);
+        assert!(certificates.reload().unwrap());
+        let (presented, _) = handshake(address, &dir, true).unwrap();
+        assert_eq!(serial(&renewed.0), presented);
+        // 节点间请求也换成新证书
+        let reloaded = certificates.connector();
+        assert!(!Arc::ptr_eq(
+            connector.as_ref().unwrap(),
+            reloaded.as_ref().unwrap()
+        ));
+        assert_eq!("hello", get(reloaded));
+
+        server.stop(true).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
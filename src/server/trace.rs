@@ -0,0 +1,170 @@
+use std::future::Future;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::FutureExt;
+use opentelemetry::trace::{FutureExt as _, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, KeyValue};
+
+use crate::config::logger::{self, RequestContext};
+use crate::trace;
+
+const REQUEST_ID: &str = "x-request-id";
+
+/// 为每个请求创建server span，请求头中有 `traceparent` 时作为父span，用于 `App::wrap_fn`
+pub fn traced<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    traced_with(&global::tracer(trace::NAME), req, srv)
+}
+
+fn traced_with<T, S, B>(
+    tracer: &T,
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    T: Tracer,
+    T::Span: Send + Sync + 'static,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let parent = trace::extract(|name| req.headers().get(name).and_then(|v| v.to_str().ok()));
+    let path = req
+        .match_pattern()
+        .unwrap_or_else(|| req.path().to_string());
+    let mut attributes = vec![
+        KeyValue::new("http.method", req.method().to_string()),
+        KeyValue::new("http.target", req.path().to_string()),
+    ];
+    if let Some(peer) = req.peer_addr() {
+        attributes.push(KeyValue::new("net.peer.ip", peer.ip().to_string()));
+    }
+    let span = tracer
+        .span_builder(format!("{} {}", req.method(), path))
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(tracer, &parent);
+    let cx = parent.with_span(span);
+    srv.call(req).with_context(cx.clone()).map(move |res| {
+        let span = cx.span();
+        match &res {
+            Ok(res) => {
+                span.set_attribute(KeyValue::new(
+                    "http.status_code",
+                    res.status().as_u16() as i64,
+                ));
+                if res.status().is_server_error() {
+                    span.set_status(Status::error(res.status().to_string()));
+                }
+            }
+            Err(err) => span.set_status(Status::error(err.to_string())),
+        }
+        span.end();
+        res
+    })
+}
+
+/// 请求ID，客户端没有传 `x-request-id` 时生成，在响应头中返回，JSON日志中带有该字段
+pub fn request_id<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let header = HeaderValue::from_str(&id);
+    logger::scope(RequestContext::new(id), srv.call(req)).map(move |res| {
+        res.map(|mut res| {
+            if let Ok(header) = header {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID), header);
+            }
+            res
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::web::Buf;
+    use actix_web::{get, test, App, HttpResponse};
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use opentelemetry::Context;
+    use opentelemetry_sdk::trace::TracerProvider;
+
+    use crate::config::logger;
+    use crate::server::trace::traced_with;
+    use crate::trace::JsonLines;
+
+    /// 收集导出的span，不影响全局的 tracer provider
+    #[derive(Debug, Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[get("/current")]
+    async fn current() -> HttpResponse {
+        let cx = Context::current();
+        let span = cx.span();
+        HttpResponse::Ok().body(span.span_context().trace_id().to_string())
+    }
+
+    #[actix_rt::test]
+    async fn propagate() {
+        logger::init(true);
+        let buffer = Buffer::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(JsonLines::new(buffer.clone()))
+            .build();
+        let tracer = provider.tracer("test");
+        let srv = test::start(move || {
+            let tracer = tracer.clone();
+            App::new()
+                .wrap_fn(move |req, srv| traced_with(&tracer, req, srv))
+                .service(current)
+        });
+        let mut response = srv
+            .get("/current")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .send()
+            .await
+            .unwrap();
+        let body = response.body().await.unwrap();
+        assert_eq!(
+            "0af7651916cd43dd8448eb211c80319c",
+            std::str::from_utf8(body.bytes()).unwrap()
+        );
+
+        let lines = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let span: serde_json::Value = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!("GET /current", span["name"]);
+        assert_eq!("server", span["kind"]);
+        assert_eq!("b7ad6b7169203331", span["parentSpanId"]);
+    }
+}
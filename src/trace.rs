@@ -0,0 +1,242 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::{ready, BoxFuture};
+use http_client::http_types::Url;
+use http_client::Request;
+use opentelemetry::propagation::{Injector, TextMapPropagator};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{Protocol, WithExportConfig};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// instrumentation scope 和 `service.name`
+pub const NAME: &str = "idgener";
+
+/// span 的导出方式，`stdout` 每行输出一个JSON，或者 OTLP/HTTP 收集器的地址（如 `http://127.0.0.1:4318`）
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Exporter {
+    Stdout,
+    Otlp(Url),
+}
+
+impl FromStr for Exporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "stdout" => Ok(Exporter::Stdout),
+            endpoint => Url::parse(endpoint)
+                .map(Exporter::Otlp)
+                .map_err(|err| format!("invalid trace exporter {}: {}", endpoint, err)),
+        }
+    }
+}
+
+impl TryFrom<String> for Exporter {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Exporter> for String {
+    fn from(val: Exporter) -> Self {
+        val.to_string()
+    }
+}
+
+impl Display for Exporter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exporter::Stdout => f.write_str("stdout"),
+            Exporter::Otlp(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// 开启tracing，设置为全局的 tracer provider。服务退出时调用 [TracerProvider::shutdown] 导出剩余的span
+pub fn init(exporter: &Exporter, instance: &str) -> anyhow::Result<TracerProvider> {
+    let builder = TracerProvider::builder().with_resource(Resource::new(vec![
+        KeyValue::new("service.name", NAME),
+        KeyValue::new("service.instance.id", instance.to_string()),
+    ]));
+    let provider = match exporter {
+        Exporter::Stdout => {
+            builder.with_batch_exporter(JsonLines::new(std::io::stdout()), runtime::Tokio)
+        }
+        Exporter::Otlp(endpoint) => {
+            let endpoint = match endpoint.path().ends_with("/v1/traces") {
+                true => endpoint.clone(),
+                false => endpoint.join("/v1/traces")?,
+            };
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpJson)
+                .with_endpoint(endpoint.as_str())
+                .build()?;
+            builder.with_batch_exporter(exporter, runtime::Tokio)
+        }
+    }
+    .build();
+    global::set_tracer_provider(provider.clone());
+    Ok(provider)
+}
+
+/// 以当前context为父span开始一个span。没有开启tracing时只传递父span的 trace context
+pub fn start(name: impl Into<Cow<'static, str>>, kind: SpanKind) -> Context {
+    let tracer = global::tracer(NAME);
+    let span = tracer.span_builder(name).with_kind(kind).start(&tracer);
+    Context::current_with_span(span)
+}
+
+/// 把错误记录到span的状态中
+pub fn set_error<E: Display>(cx: &Context, err: E) {
+    cx.span().set_status(Status::error(err.to_string()));
+}
+
+/// 从请求头中读取上游的 W3C trace context
+pub fn extract<'a, F>(header: F) -> Context
+where
+    F: Fn(&str) -> Option<&'a str>,
+{
+    let propagator = TraceContextPropagator::new();
+    let headers = propagator
+        .fields()
+        .filter_map(|name| header(name).map(|value| (name.to_string(), value.to_string())))
+        .collect::<HashMap<_, _>>();
+    propagator.extract(&headers)
+}
+
+/// 把[cx]的 W3C trace context 写入节点间请求的请求头，没有采样的context也会传播
+pub fn inject(cx: &Context, req: &mut Request) {
+    struct Headers<'a>(&'a mut Request);
+
+    impl Injector for Headers<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            self.0.insert_header(key, value);
+        }
+    }
+
+    TraceContextPropagator::new().inject_context(cx, &mut Headers(req));
+}
+
+/// 每行输出一个JSON的span导出器
+#[derive(Debug)]
+pub struct JsonLines<W> {
+    writer: W,
+}
+
+impl<W> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLines { writer }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn to_json(span: &SpanData) -> serde_json::Value {
+    let parent = match span.parent_span_id {
+        id if id == opentelemetry::trace::SpanId::INVALID => None,
+        id => Some(id.to_string()),
+    };
+    let error = match &span.status {
+        Status::Error { description } => Some(description.to_string()),
+        _ => None,
+    };
+    json!({
+        "traceId": span.span_context.trace_id().to_string(),
+        "spanId": span.span_context.span_id().to_string(),
+        "parentSpanId": parent,
+        "name": span.name,
+        "kind": format!("{:?}", span.span_kind).to_lowercase(),
+        "startTimeUnixNano": unix_nanos(span.start_time).to_string(),
+        "endTimeUnixNano": unix_nanos(span.end_time).to_string(),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|kv| (kv.key.to_string(), kv.value.to_string()))
+            .collect::<Vec<_>>(),
+        "error": error,
+    })
+}
+
+impl<W: Write + Send + Sync + Debug> SpanExporter for JsonLines<W> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let result = batch
+            .iter()
+            .try_for_each(|span| writeln!(self.writer, "{}", to_json(span)))
+            .and_then(|_| self.writer.flush())
+            .map_err(|err| err.to_string().into());
+        Box::pin(ready(result))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use http_client::http_types::{Method, Url};
+    use http_client::Request;
+    use opentelemetry::trace::{SpanKind, TraceContextExt};
+    use opentelemetry::Context;
+
+    use crate::trace::{extract, inject, start, Exporter};
+
+    #[test]
+    fn traceparent() {
+        assert!("stdout".parse::<Exporter>().is_ok());
+        assert!("not a url".parse::<Exporter>().is_err());
+
+        let headers = HashMap::from([(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        )]);
+        let cx = extract(|name| headers.get(name).copied());
+        let span = cx.span().span_context().clone();
+        assert_eq!(
+            "0af7651916cd43dd8448eb211c80319c",
+            span.trace_id().to_string()
+        );
+        assert!(span.is_sampled());
+        let invalid = extract(|_| Some("00-00000000000000000000000000000000-b7ad6b7169203331-01"));
+        assert!(!invalid.span().span_context().is_valid());
+    }
+
+    /// 没有采样的context也要传播给下游节点，保证同一个trace的采样决定一致
+    #[test]
+    fn propagate_unsampled() {
+        let parent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00";
+        let cx = extract(|name| match name {
+            "traceparent" => Some(parent),
+            _ => None,
+        });
+        let _guard = cx.attach();
+        let child = start("child", SpanKind::Client);
+        let mut req = Request::new(Method::Get, Url::parse("http://127.0.0.1/").unwrap());
+        inject(&child, &mut req);
+        let header = req.header("traceparent").unwrap().last().as_str();
+        assert!(header.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert!(header.ends_with("-00"));
+
+        let mut req = Request::new(Method::Get, Url::parse("http://127.0.0.1/").unwrap());
+        inject(&Context::new(), &mut req);
+        assert!(req.header("traceparent").is_none());
+    }
+}